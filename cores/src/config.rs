@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+
+use crate::secrets::{Secret, SecretsProvider, load_secrets_provider};
+
+// Looks configuration values up in
+// 1. the environment
+// 2. the KEY=VALUE file at YOSHINO_CONFIG_FILE
+// secrets are looked up in the secrets provider first, when one is configured.
+// Problems are collected so that a deployment reports all of them at once on startup.
+// Logging is set up before the configuration is loaded, its settings are read from the environment only.
+pub struct ConfigLoader {
+    file: HashMap<String, String>,
+    secrets_provider: Option<Arc<dyn SecretsProvider>>,
+    errors: Vec<String>,
+}
+
+impl ConfigLoader {
+    pub async fn from_env() -> Result<Self> {
+        let file = match env::var("YOSHINO_CONFIG_FILE") {
+            Ok(path) => {
+                let text = fs::read_to_string(&path)
+                    .with_context(|| format!("failed to read YOSHINO_CONFIG_FILE {}", path))?;
+                parse_config_file(&text)
+                    .with_context(|| format!("broken YOSHINO_CONFIG_FILE {}", path))?
+            },
+            Err(_) => HashMap::new(),
+        };
+        let mut this = Self {
+            file,
+            secrets_provider: None,
+            errors: vec![],
+        };
+        this.secrets_provider = load_secrets_provider(&mut this);
+        Ok(this)
+    }
+
+    pub fn secrets_provider(&self) -> Option<&Arc<dyn SecretsProvider>> {
+        self.secrets_provider.as_ref()
+    }
+
+    pub fn optional(&self, name: &str) -> Option<String> {
+        env::var(name).ok()
+            .or_else(|| self.file.get(name).cloned())
+            .filter(|v| !v.is_empty())
+    }
+
+    pub fn required(&mut self, name: &str) -> String {
+        match self.optional(name) {
+            Some(value) => value,
+            None => {
+                self.errors.push(format!("{} is not set", name));
+                String::new()
+            }
+        }
+    }
+
+    pub async fn optional_secret(&mut self, name: &str) -> Option<String> {
+        if let Some(ref secrets_provider) = self.secrets_provider {
+            match secrets_provider.get(name).await {
+                Ok(Some(value)) => return Some(value),
+                Ok(None) => {},
+                Err(error) => {
+                    self.errors.push(format!("{} could not be read from the secrets provider: {:#}", name, error));
+                    return None
+                },
+            }
+        }
+        self.optional(name)
+    }
+
+    pub async fn required_secret(&mut self, name: &str) -> String {
+        let reported = self.errors.len();
+        match self.optional_secret(name).await {
+            Some(value) => value,
+            None => {
+                // a failed lookup is already reported
+                if self.errors.len() == reported {
+                    self.errors.push(format!("{} is not set", name));
+                }
+                String::new()
+            }
+        }
+    }
+
+    // for secrets that are used after startup, see Secret
+    pub async fn required_refreshed_secret(&mut self, name: &str) -> Secret {
+        let value = self.required_secret(name).await;
+        Secret::new(name, value, self.secrets_provider.as_ref())
+    }
+
+    pub async fn optional_refreshed_secret(&mut self, name: &str) -> Option<Secret> {
+        let value = self.optional_secret(name).await?;
+        Some(Secret::new(name, value, self.secrets_provider.as_ref()))
+    }
+
+    pub fn parse<T>(&mut self, name: &str, default: T) -> T where T: FromStr, T::Err: Display {
+        let Some(value) = self.optional(name) else { return default };
+        match value.parse() {
+            Ok(value) => value,
+            Err(error) => {
+                self.errors.push(format!("{} has an invalid value {:?}: {}", name, value, error));
+                default
+            }
+        }
+    }
+
+    // for settings without a default, e.g. a limit that is not enforced when unset
+    pub fn parse_optional<T>(&mut self, name: &str) -> Option<T> where T: FromStr, T::Err: Display {
+        let value = self.optional(name)?;
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(error) => {
+                self.errors.push(format!("{} has an invalid value {:?}: {}", name, value, error));
+                None
+            }
+        }
+    }
+
+    // `1` or `true` turn a feature on
+    pub fn flag(&mut self, name: &str) -> bool {
+        match self.optional(name).as_deref() {
+            None | Some("0") | Some("false") => false,
+            Some("1") | Some("true") => true,
+            Some(value) => {
+                self.errors.push(format!("{} has an invalid value {:?}: expected 1, true, 0 or false", name, value));
+                false
+            },
+        }
+    }
+
+    pub fn invalid(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if self.errors.is_empty() {
+            return Ok(())
+        }
+        bail!("invalid configuration\n  * {}", self.errors.join("\n  * "))
+    }
+}
+
+// where a store keeps its data, tables are shared by every Lambda instance,
+// files only by the invocations of one and are meant for running locally
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StoreKind {
+    DynamoDb,
+    File,
+}
+
+impl FromStr for StoreKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dynamodb" => Ok(StoreKind::DynamoDb),
+            "file" => Ok(StoreKind::File),
+            _ => bail!("expected dynamodb or file"),
+        }
+    }
+}
+
+// the format of .env files, blank lines and `#` comments are skipped
+fn parse_config_file(text: &str) -> Result<HashMap<String, String>> {
+    let mut values = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line.split_once('=')
+            .with_context(|| format!("line {} is not NAME=VALUE", i + 1))?;
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        values.insert(name.trim().to_string(), value.to_string());
+    }
+    Ok(values)
+}
@@ -0,0 +1,11 @@
+use aws_config::BehaviorVersion;
+
+// shared by the table stores, `endpoint` points at DynamoDB Local
+pub async fn client(endpoint: Option<&str>) -> aws_sdk_dynamodb::Client {
+    let mut loader = aws_config::defaults(BehaviorVersion::latest());
+    if let Some(endpoint) = endpoint {
+        loader = loader.endpoint_url(endpoint);
+    }
+    let config = loader.load().await;
+    aws_sdk_dynamodb::Client::new(&config)
+}
@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+
+// The parts of an Events API callback web and worker act on, parsed once by web and handed to the worker typed.
+// Slack's other fields are dropped.
+// https://api.slack.com/apis/connections/events-api#callback-field
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EventCallback {
+    pub team_id: Option<String>,
+    pub event_id: Option<String>,
+    pub event: Event,
+}
+
+impl EventCallback {
+    // event_id is unique per event, client_msg_id per message the user sent
+    pub fn dedupe_key(&self) -> Option<String> {
+        let event_id = self.event_id.as_ref()
+            .map(|v| format!("event_id:{}", v));
+        let client_msg_id = self.event.client_msg_id.as_ref()
+            .map(|v| format!("client_msg_id:{}", v));
+        event_id.or(client_msg_id)
+    }
+}
+
+// https://api.slack.com/events/message
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Event {
+    pub r#type: String,
+    pub subtype: Option<String>,
+    pub channel: Option<String>,
+    pub user: Option<String>,
+    pub bot_id: Option<String>,
+    #[serde(default)]
+    pub text: String,
+    pub ts: Option<String>,
+    pub thread_ts: Option<String>,
+    pub client_msg_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<File>,
+    // the new state of the message on message_changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<Box<Message>>,
+    // the old state on message_changed, the deleted message on message_deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_message: Option<Box<Message>>,
+    // the users whose tokens were revoked on tokens_revoked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<Box<RevokedTokens>>,
+}
+
+// https://api.slack.com/events/tokens_revoked
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RevokedTokens {
+    // users whose user tokens were revoked
+    #[serde(default)]
+    pub oauth: Vec<String>,
+    // bot users whose bot tokens were revoked
+    #[serde(default)]
+    pub bot: Vec<String>,
+}
+
+// https://api.slack.com/events/message/message_changed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Message {
+    pub subtype: Option<String>,
+    pub user: Option<String>,
+    pub bot_id: Option<String>,
+    #[serde(default)]
+    pub text: String,
+    pub ts: String,
+    pub thread_ts: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<File>,
+}
+
+// https://api.slack.com/types/file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct File {
+    pub id: String,
+    #[serde(default)]
+    pub mimetype: String,
+    pub url_private_download: Option<String>,
+    #[serde(default)]
+    pub size: u64,
+}
@@ -0,0 +1,161 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Result, Context, bail};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::{ConfigLoader, StoreKind};
+
+// what the OAuth flow granted for a workspace
+// https://api.slack.com/authentication/oauth-v2#exchanging
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Installation {
+    pub team_id: String,
+    pub team_name: Option<String>,
+    pub bot_user_id: Option<String>,
+    pub bot_token: String,
+    // granted when user scopes such as search:read were requested
+    pub user_token: Option<String>,
+    // who granted user_token
+    #[serde(default)]
+    pub user_id: Option<String>,
+    pub installed_at: DateTime<Utc>,
+}
+
+// written by web when a workspace installs the app, read by worker to act in that workspace
+#[async_trait]
+pub trait InstallationStore: Send + Sync {
+    // replaces the installation of the same team
+    async fn save(&self, installation: &Installation) -> Result<()>;
+    async fn find(&self, team_id: &str) -> Result<Option<Installation>>;
+    // forgets the tokens once the app is uninstalled or they are revoked
+    async fn delete(&self, team_id: &str) -> Result<()>;
+}
+
+// YOSHINO_INSTALLATION_STORE selects the implementation
+// * `dynamodb` (default) the table YOSHINO_INSTALLATION_TABLE, YOSHINO_INSTALLATION_DYNAMODB_ENDPOINT points at DynamoDB Local
+// * `file` one JSON file per team in YOSHINO_INSTALLATION_DIR
+pub async fn load_installation_store(loader: &mut ConfigLoader) -> Arc<dyn InstallationStore> {
+    match loader.parse("YOSHINO_INSTALLATION_STORE", StoreKind::DynamoDb) {
+        StoreKind::DynamoDb => {
+            let table = loader.optional("YOSHINO_INSTALLATION_TABLE")
+                .unwrap_or_else(|| "yoshino-radio-installations".into());
+            let endpoint = loader.optional("YOSHINO_INSTALLATION_DYNAMODB_ENDPOINT");
+            DynamoDbInstallationStore::new(&table, endpoint.as_deref()).await
+        },
+        StoreKind::File => FileInstallationStore::new(PathBuf::from(loader.required("YOSHINO_INSTALLATION_DIR"))),
+    }
+}
+
+pub struct FileInstallationStore {
+    dir: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileInstallationStore {
+    pub fn new(dir: PathBuf) -> Arc<Self> {
+        let this = Self {
+            dir,
+            lock: Mutex::new(()),
+        };
+        Arc::new(this)
+    }
+
+    fn path(&self, team_id: &str) -> PathBuf {
+        // team IDs are alphanumeric, anything else is dropped
+        let key: String = team_id.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect();
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+#[async_trait]
+impl InstallationStore for FileInstallationStore {
+    async fn save(&self, installation: &Installation) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(&installation.team_id), serde_json::to_string(installation)?)?;
+        Ok(())
+    }
+
+    async fn find(&self, team_id: &str) -> Result<Option<Installation>> {
+        let _guard = self.lock.lock().await;
+        let path = self.path(team_id);
+        if !path.exists() {
+            return Ok(None)
+        }
+        let text = fs::read_to_string(&path)?;
+        let installation = serde_json::from_str(&text)
+            .with_context(|| format!("broken installation file {:?}", path))?;
+        Ok(Some(installation))
+    }
+
+    async fn delete(&self, team_id: &str) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let path = self.path(team_id);
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
+
+// expects a table with the string partition key `team_id`,
+// the installation is kept as JSON in `installation`
+pub struct DynamoDbInstallationStore {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+}
+
+impl DynamoDbInstallationStore {
+    pub async fn new(table: &str, endpoint: Option<&str>) -> Arc<Self> {
+        let client = crate::dynamodb::client(endpoint).await;
+        let this = Self {
+            client,
+            table: table.into(),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl InstallationStore for DynamoDbInstallationStore {
+    async fn save(&self, installation: &Installation) -> Result<()> {
+        self.client.put_item()
+            .table_name(&self.table)
+            .item("team_id", AttributeValue::S(installation.team_id.clone()))
+            .item("installation", AttributeValue::S(serde_json::to_string(installation)?))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn find(&self, team_id: &str) -> Result<Option<Installation>> {
+        let output = self.client.get_item()
+            .table_name(&self.table)
+            .key("team_id", AttributeValue::S(team_id.into()))
+            .consistent_read(true)
+            .send()
+            .await?;
+        let Some(item) = output.item else { return Ok(None) };
+        let Some(AttributeValue::S(text)) = item.get("installation") else {
+            bail!("installation of {} without the installation attribute", team_id);
+        };
+        Ok(Some(serde_json::from_str(text)?))
+    }
+
+    async fn delete(&self, team_id: &str) -> Result<()> {
+        self.client.delete_item()
+            .table_name(&self.table)
+            .key("team_id", AttributeValue::S(team_id.into()))
+            .send()
+            .await?;
+        Ok(())
+    }
+}
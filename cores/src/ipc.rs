@@ -1,12 +1,384 @@
 
 
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::{Result, bail, Context};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::task::TaskTracker;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::events::EventCallback;
+use crate::tasks::FlushTracker;
+use crate::telemetry::current_traceparent;
 
 pub const IPC_EXTENSION_ENDPOINT: &str = "0.0.0.0:4000";
+pub const IPC_CLIENT_ENDPOINT: &str = "127.0.0.1:4000";
 pub const IPC_ACCEPT_ACK_TOKEN: &[u8; 3] = b"ACK";
 
-#[derive(Serialize, Deserialize)]
+// same as the Lambda invoke payload limit
+const IPC_MAX_FRAME_SIZE: usize = 6 * 1024 * 1024;
+
+// Web and worker deploy separately, the envelope carries the schema version it was written with.
+// A reader accepts any minor version of the major version it knows, newer fields are ignored
+// and payloads a minor version added are read as InvokePayload::Unknown for the reader to skip.
+// 1.1 adds InvokePayload::SlashCommand
+// 2.0 carries the event callback typed instead of as the raw request body
+// 2.1 adds traceparent
+pub const INVOKE_SCHEMA_VERSION: SchemaVersion = SchemaVersion { major: 2, minor: 1 };
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SchemaVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl fmt::Display for SchemaVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(try_from = "Value")]
 pub struct InvokeMessage {
-    pub event_type: String,
-    pub body: String,
+    pub schema_version: SchemaVersion,
+    // correlates the logs of a message across web and worker
+    pub trace_id: String,
+    pub received_at: DateTime<Utc>,
+    pub team_id: Option<String>,
+    // W3C trace context of web's span, the worker's spans join web's trace when exported over OTLP
+    // https://www.w3.org/TR/trace-context/#traceparent-header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+    pub payload: InvokePayload,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InvokePayload {
+    // https://api.slack.com/apis/connections/events-api#callback-field
+    EventCallback(EventCallback),
+    // https://api.slack.com/interactivity/slash-commands#app_command_handling
+    SlashCommand {
+        command: String,
+        text: String,
+        user_id: String,
+        channel_id: String,
+        response_url: String,
+    },
+    // added by a newer minor version than this build reads
+    #[serde(other)]
+    Unknown,
+}
+
+// mirrors InvokeMessage without the compatibility check
+#[derive(Deserialize)]
+struct UncheckedInvokeMessage {
+    schema_version: SchemaVersion,
+    trace_id: String,
+    received_at: DateTime<Utc>,
+    team_id: Option<String>,
+    #[serde(default)]
+    traceparent: Option<String>,
+    payload: InvokePayload,
+}
+
+// generated once per request web receives, every log line of the request in web and worker carries it
+pub fn new_trace_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+impl InvokeMessage {
+    pub fn new(trace_id: String, team_id: Option<String>, payload: InvokePayload) -> Self {
+        Self {
+            schema_version: INVOKE_SCHEMA_VERSION,
+            trace_id,
+            received_at: Utc::now(),
+            team_id,
+            traceparent: current_traceparent(),
+            payload,
+        }
+    }
+
+    pub fn decode(text: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(text)?;
+        Self::try_from(value)
+    }
+}
+
+impl TryFrom<Value> for InvokeMessage {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        let schema_version = value.get("schema_version")
+            .context("invoke message without schema_version")?;
+        let schema_version: SchemaVersion = serde_json::from_value(schema_version.clone())
+            .context("malformed schema_version")?;
+        if schema_version.major != INVOKE_SCHEMA_VERSION.major {
+            bail!(
+                "unsupported invoke message schema version {}, this build reads {}.x",
+                schema_version, INVOKE_SCHEMA_VERSION.major);
+        }
+        let message: UncheckedInvokeMessage = serde_json::from_value(value)
+            .with_context(|| format!("malformed invoke message of schema version {}", schema_version))?;
+        let message = Self {
+            schema_version: message.schema_version,
+            trace_id: message.trace_id,
+            received_at: message.received_at,
+            team_id: message.team_id,
+            traceparent: message.traceparent,
+            payload: message.payload,
+        };
+        Ok(message)
+    }
+}
+
+// frames sent from the web runtime to the co-located extension
+#[derive(Serialize, Deserialize)]
+pub enum IpcRequest {
+    // acknowledged as soon as the message is accepted
+    Invoke(Box<InvokeMessage>),
+    // acknowledged once every message accepted before it has been handled
+    Flush,
+}
+
+// a frame is a big-endian u32 length followed by that many bytes of JSON
+pub async fn write_frame<W, T>(writer: &mut W, value: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let payload = serde_json::to_vec(value)?;
+    if payload.len() > IPC_MAX_FRAME_SIZE {
+        bail!("frame too large {}", payload.len());
+    }
+    writer.write_u32(payload.len() as u32).await?;
+    writer.write_all(&payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_frame<R, T>(reader: &mut R) -> Result<T>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let length = reader.read_u32().await? as usize;
+    if length > IPC_MAX_FRAME_SIZE {
+        bail!("frame too large {}", length);
+    }
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload).await?;
+    let value = serde_json::from_slice(&payload)?;
+    Ok(value)
+}
+
+pub struct IpcClient {
+    endpoint: String,
+}
+
+impl IpcClient {
+    pub fn new(endpoint: &str) -> Arc<Self> {
+        let client = Self {
+            endpoint: endpoint.into(),
+        };
+        Arc::new(client)
+    }
+
+    // hands the message off and waits for the ACK
+    pub async fn invoke(&self, message: InvokeMessage) -> Result<()> {
+        self.request(&IpcRequest::Invoke(Box::new(message))).await
+    }
+
+    // waits until every message handed off so far has been handled
+    pub async fn flush(&self) -> Result<()> {
+        self.request(&IpcRequest::Flush).await
+    }
+
+    async fn request(&self, request: &IpcRequest) -> Result<()> {
+        let mut stream = TcpStream::connect(&self.endpoint).await
+            .with_context(|| format!("failed to connect {}", self.endpoint))?;
+        write_frame(&mut stream, request).await?;
+        let mut ack = [0u8; 3];
+        stream.read_exact(&mut ack).await?;
+        if &ack != IPC_ACCEPT_ACK_TOKEN {
+            bail!("unexpected ack {:?}", ack);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+pub trait IpcHandler: Send + Sync + 'static {
+    async fn handle(&self, message: InvokeMessage) -> Result<()>;
+}
+
+pub struct IpcServer {
+    listener: TcpListener,
+    task_tracker: FlushTracker,
+}
+
+impl IpcServer {
+    pub async fn bind(endpoint: &str) -> Result<Self> {
+        let listener = TcpListener::bind(endpoint).await?;
+        let server = Self {
+            listener,
+            task_tracker: FlushTracker::new(),
+        };
+        Ok(server)
+    }
+
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // tracks the handlers so that the owner can drain them on shutdown
+    pub fn task_tracker(&self) -> TaskTracker {
+        self.task_tracker.task_tracker()
+    }
+
+    pub async fn serve(self, handler: Arc<dyn IpcHandler>) -> Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let handler = Arc::clone(&handler);
+            let task_tracker = self.task_tracker.clone();
+            tokio::spawn(async move {
+                if let Err(error) = Self::process_connection(stream, handler, task_tracker).await {
+                    info!("ipc connection failed {:?}", error);
+                }
+            });
+        }
+    }
+
+    async fn process_connection(mut stream: TcpStream, handler: Arc<dyn IpcHandler>, task_tracker: FlushTracker) -> Result<()> {
+        let request: IpcRequest = read_frame(&mut stream).await?;
+        match request {
+            IpcRequest::Invoke(message) => {
+                task_tracker.spawn(async move {
+                    if let Err(error) = handler.handle(*message).await {
+                        info!("ipc handler failed {:?}", error);
+                    }
+                });
+            },
+            IpcRequest::Flush => task_tracker.flush().await,
+        }
+        stream.write_all(IPC_ACCEPT_ACK_TOKEN).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    // takes a while to handle each message, so that a flush has something to wait for
+    struct RecordingHandler {
+        handled: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl IpcHandler for RecordingHandler {
+        async fn handle(&self, message: InvokeMessage) -> Result<()> {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            self.handled.lock().await.push(message.trace_id);
+            Ok(())
+        }
+    }
+
+    fn message(trace_id: &str) -> InvokeMessage {
+        let payload = InvokePayload::SlashCommand {
+            command: "/yoshino-usage".into(),
+            text: String::new(),
+            user_id: "U0123".into(),
+            channel_id: "D0123".into(),
+            response_url: "https://hooks.slack.com/commands/T0123/1/abc".into(),
+        };
+        InvokeMessage::new(trace_id.into(), None, payload)
+    }
+
+    async fn serve(handler: &Arc<RecordingHandler>) -> Arc<IpcClient> {
+        let server = IpcServer::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = server.local_addr().unwrap().to_string();
+        let handler: Arc<dyn IpcHandler> = handler.clone();
+        tokio::spawn(server.serve(handler));
+        IpcClient::new(&endpoint)
+    }
+
+    #[tokio::test]
+    async fn flush_waits_for_the_handoffs() {
+        let handler = Arc::new(RecordingHandler { handled: Mutex::new(vec![]) });
+        let client = serve(&handler).await;
+        client.invoke(message("first")).await.unwrap();
+        client.invoke(message("second")).await.unwrap();
+        // acknowledged on acceptance, before the handler is done
+        assert!(handler.handled.lock().await.is_empty());
+        client.flush().await.unwrap();
+        let mut handled = handler.handled.lock().await.clone();
+        handled.sort();
+        assert_eq!(handled, vec!["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_flushes_are_both_acknowledged() {
+        let handler = Arc::new(RecordingHandler { handled: Mutex::new(vec![]) });
+        let client = serve(&handler).await;
+        client.invoke(message("first")).await.unwrap();
+        let later = async {
+            // begins while the first flush is waiting, with a handoff of its own
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.invoke(message("second")).await.unwrap();
+            client.flush().await.unwrap();
+            handler.handled.lock().await.len()
+        };
+        let flushes = async { tokio::join!(client.flush(), later) };
+        let (first, handled) = tokio::time::timeout(Duration::from_secs(5), flushes).await
+            .expect("a flush was never acknowledged");
+        first.unwrap();
+        assert_eq!(handled, 2);
+    }
+
+    #[tokio::test]
+    async fn flush_without_handoffs() {
+        let handler = Arc::new(RecordingHandler { handled: Mutex::new(vec![]) });
+        let client = serve(&handler).await;
+        client.flush().await.unwrap();
+        assert!(handler.handled.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn invoke_fails_without_a_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        assert!(IpcClient::new(&endpoint).invoke(message("lost")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let (mut writer, mut reader) = tokio::io::duplex(1024);
+        write_frame(&mut writer, &IpcRequest::Invoke(Box::new(message("framed")))).await.unwrap();
+        let request: IpcRequest = read_frame(&mut reader).await.unwrap();
+        let IpcRequest::Invoke(message) = request else { panic!("expected an invoke") };
+        assert_eq!(message.trace_id, "framed");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_frames() {
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+        writer.write_u32(IPC_MAX_FRAME_SIZE as u32 + 1).await.unwrap();
+        assert!(read_frame::<_, IpcRequest>(&mut reader).await.is_err());
+    }
 }
@@ -1,2 +1,13 @@
 
+pub mod config;
+pub mod dynamodb;
+pub mod events;
+pub mod installations;
 pub mod ipc;
+pub mod metrics;
+pub mod queue;
+pub mod redaction;
+pub mod secrets;
+pub mod slack;
+pub mod tasks;
+pub mod telemetry;
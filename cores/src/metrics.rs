@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write as _;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::info;
+
+// upper bounds of the latency histogram buckets in milliseconds
+const BUCKETS: [f64; 11] = [50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0, 30000.0, 60000.0, 120000.0];
+
+// EMF allows up to 100 values per metric in a document
+// https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html
+const EMF_MAX_VALUES: usize = 100;
+
+const EMF_NAMESPACE: &str = "YoshinoRadio";
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct Histogram {
+    // cumulative counts per bucket, the last one is +Inf
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; BUCKETS.len() + 1];
+        }
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            if value <= *bound {
+                self.buckets[i] += 1;
+            }
+        }
+        self.buckets[BUCKETS.len()] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    // cumulative, for /metrics
+    counters: BTreeMap<(&'static str, Labels), u64>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>,
+    // since the last flush, for EMF
+    pending: BTreeMap<(&'static str, Labels), Vec<f64>>,
+}
+
+struct Metrics {
+    service: String,
+    // on Lambda the metrics are flushed as EMF lines at the end of each invocation
+    emf: bool,
+    registry: Mutex<Registry>,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+// metrics recorded before init are dropped
+pub fn init_metrics(service_name: &str) {
+    let metrics = Metrics {
+        service: service_name.into(),
+        emf: env::var("AWS_LAMBDA_RUNTIME_API").is_ok(),
+        registry: Mutex::new(Registry::default()),
+    };
+    let _ = METRICS.set(metrics);
+}
+
+fn to_labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels.iter()
+        .map(|(k, v)| (*k, v.to_string()))
+        .collect()
+}
+
+pub fn increment(name: &'static str, labels: &[(&'static str, &str)]) {
+    add(name, labels, 1);
+}
+
+pub fn add(name: &'static str, labels: &[(&'static str, &str)], value: u64) {
+    let Some(metrics) = METRICS.get() else { return };
+    let mut registry = metrics.registry.lock().unwrap();
+    let key = (name, to_labels(labels));
+    *registry.counters.entry(key.clone()).or_default() += value;
+    if metrics.emf {
+        registry.pending.entry(key).or_default().push(value as f64);
+    }
+}
+
+pub fn observe(name: &'static str, labels: &[(&'static str, &str)], duration: Duration) {
+    let Some(metrics) = METRICS.get() else { return };
+    let value = duration.as_secs_f64() * 1000.0;
+    let mut registry = metrics.registry.lock().unwrap();
+    let key = (name, to_labels(labels));
+    registry.histograms.entry(key.clone()).or_default().observe(value);
+    if metrics.emf {
+        registry.pending.entry(key).or_default().push(value);
+    }
+}
+
+// prints what was recorded since the last flush as EMF lines, CloudWatch extracts the metrics from the logs
+pub fn flush_metrics() {
+    let Some(metrics) = METRICS.get() else { return };
+    if !metrics.emf {
+        return
+    }
+    let pending = std::mem::take(&mut metrics.registry.lock().unwrap().pending);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis() as u64)
+        .unwrap_or_default();
+    for ((name, labels), values) in pending {
+        let unit = if name.ends_with("_ms") { "Milliseconds" } else { "Count" };
+        let mut dimensions = vec!["service".to_string()];
+        let mut document = Map::new();
+        document.insert("service".into(), metrics.service.clone().into());
+        for (k, v) in labels {
+            dimensions.push(k.into());
+            document.insert(k.into(), v.into());
+        }
+        for chunk in values.chunks(EMF_MAX_VALUES) {
+            let mut document = document.clone();
+            document.insert("_aws".into(), json!({
+                "Timestamp": timestamp,
+                "CloudWatchMetrics": [{
+                    "Namespace": EMF_NAMESPACE,
+                    "Dimensions": [dimensions],
+                    "Metrics": [{ "Name": name, "Unit": unit }],
+                }],
+            }));
+            document.insert(name.into(), json!(chunk));
+            // bypasses tracing, the line has to be the JSON document alone
+            println!("{}", Value::Object(document));
+        }
+    }
+}
+
+fn render_labels(service: &str, labels: &Labels, extra: Option<(&str, String)>) -> String {
+    let labels = [("service", service.to_string())].into_iter()
+        .chain(labels.iter().map(|(k, v)| (*k, v.clone())))
+        .chain(extra)
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(","))
+}
+
+// https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+pub fn render_prometheus() -> String {
+    let Some(metrics) = METRICS.get() else { return String::new() };
+    let registry = metrics.registry.lock().unwrap();
+    let mut text = String::new();
+    let mut last_name = "";
+    for ((name, labels), value) in &registry.counters {
+        if *name != last_name {
+            let _ = writeln!(text, "# TYPE yoshino_{}_total counter", name);
+            last_name = name;
+        }
+        let _ = writeln!(text, "yoshino_{}_total{} {}", name, render_labels(&metrics.service, labels, None), value);
+    }
+    for ((name, labels), histogram) in &registry.histograms {
+        if *name != last_name {
+            let _ = writeln!(text, "# TYPE yoshino_{} histogram", name);
+            last_name = name;
+        }
+        let bounds = BUCKETS.iter().map(|v| v.to_string()).chain(["+Inf".to_string()]);
+        for (bound, count) in bounds.zip(&histogram.buckets) {
+            let labels = render_labels(&metrics.service, labels, Some(("le", bound)));
+            let _ = writeln!(text, "yoshino_{}_bucket{} {}", name, labels, count);
+        }
+        let labels = render_labels(&metrics.service, labels, None);
+        let _ = writeln!(text, "yoshino_{}_sum{} {}", name, labels, histogram.sum);
+        let _ = writeln!(text, "yoshino_{}_count{} {}", name, labels, histogram.count);
+    }
+    text
+}
+
+// a minimal HTTP server answering GET /metrics for long-running processes
+pub async fn serve_prometheus(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("serving metrics on {}", addr);
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let Ok(len) = stream.read(&mut buf).await else { return };
+            let request = String::from_utf8_lossy(&buf[..len]);
+            let response = if request.starts_with("GET /metrics ") {
+                let body = render_prometheus();
+                format!("HTTP/1.1 200 OK\r\ncontent-type: text/plain; version=0.0.4\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}", body.len(), body)
+            } else {
+                "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string()
+            };
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
@@ -0,0 +1,257 @@
+
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, Context};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+// a received message stays invisible to other consumers for the visibility timeout,
+// it is delivered again unless acknowledged in time
+pub struct QueueMessage {
+    pub id: String,
+    pub receipt: String,
+    pub body: String,
+    // 1 on the first delivery
+    pub attempts: u32,
+}
+
+#[async_trait]
+pub trait MessageQueue: Send + Sync {
+    async fn send(&self, body: String) -> Result<()>;
+    async fn receive(&self, max_messages: usize, visibility_timeout: Duration) -> Result<Vec<QueueMessage>>;
+    async fn ack(&self, receipt: &str) -> Result<()>;
+}
+
+// where messages go after their final attempt
+#[async_trait]
+pub trait DeadLetterSink: Send + Sync {
+    async fn dead_letter(&self, message: &QueueMessage, reason: &str) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueuePolicy {
+    pub visibility_timeout: Duration,
+    pub max_attempts: u32,
+}
+
+impl QueuePolicy {
+    pub fn is_final_attempt(&self, message: &QueueMessage) -> bool {
+        message.attempts >= self.max_attempts
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct StoredMessage {
+    id: String,
+    body: String,
+    attempts: u32,
+    // unix seconds
+    visible_at: u64,
+    // changes on every delivery so that a stale receipt cannot ack a redelivered message
+    receipt: Option<String>,
+}
+
+impl StoredMessage {
+    fn new(body: String) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            body,
+            attempts: 0,
+            visible_at: 0,
+            receipt: None,
+        }
+    }
+
+    fn deliver(&mut self, now: u64, visibility_timeout: Duration) -> QueueMessage {
+        let receipt = Uuid::new_v4().to_string();
+        self.attempts += 1;
+        self.visible_at = now + visibility_timeout.as_secs();
+        self.receipt = Some(receipt.clone());
+        QueueMessage {
+            id: self.id.clone(),
+            receipt,
+            body: self.body.clone(),
+            attempts: self.attempts,
+        }
+    }
+}
+
+// for a consumer in the same process
+pub struct MemoryQueue {
+    messages: Mutex<VecDeque<StoredMessage>>,
+}
+
+impl MemoryQueue {
+    pub fn new() -> Arc<Self> {
+        let this = Self {
+            messages: Mutex::new(VecDeque::new()),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl MessageQueue for MemoryQueue {
+    async fn send(&self, body: String) -> Result<()> {
+        self.messages.lock().unwrap().push_back(StoredMessage::new(body));
+        Ok(())
+    }
+
+    async fn receive(&self, max_messages: usize, visibility_timeout: Duration) -> Result<Vec<QueueMessage>> {
+        let now = unix_now();
+        let mut messages = self.messages.lock().unwrap();
+        let received = messages.iter_mut()
+            .filter(|v| v.visible_at <= now)
+            .take(max_messages)
+            .map(|v| v.deliver(now, visibility_timeout))
+            .collect();
+        Ok(received)
+    }
+
+    async fn ack(&self, receipt: &str) -> Result<()> {
+        self.messages.lock().unwrap()
+            .retain(|v| v.receipt.as_deref() != Some(receipt));
+        Ok(())
+    }
+}
+
+// one JSON file per message, shared by processes on the same machine
+pub struct FileQueue {
+    dir: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileQueue {
+    pub fn new(dir: PathBuf) -> Result<Arc<Self>> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create queue directory {:?}", dir))?;
+        let this = Self {
+            dir,
+            lock: Mutex::new(()),
+        };
+        Ok(Arc::new(this))
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn write(&self, message: &StoredMessage) -> Result<()> {
+        // rename makes the update atomic for readers in other processes
+        let temporary = self.dir.join(format!(".{}.tmp", message.id));
+        fs::write(&temporary, serde_json::to_vec(message)?)?;
+        fs::rename(&temporary, self.path(&message.id))?;
+        Ok(())
+    }
+
+    fn read_all(&self) -> Result<Vec<StoredMessage>> {
+        let mut messages = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|v| v.to_str()) != Some("json") {
+                continue;
+            }
+            let Ok(bytes) = fs::read(&path) else { continue };
+            let Ok(message) = serde_json::from_slice::<StoredMessage>(&bytes) else { continue };
+            messages.push(message);
+        }
+        messages.sort_by_key(|v| v.visible_at);
+        Ok(messages)
+    }
+}
+
+#[async_trait]
+impl MessageQueue for FileQueue {
+    async fn send(&self, body: String) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        self.write(&StoredMessage::new(body))
+    }
+
+    async fn receive(&self, max_messages: usize, visibility_timeout: Duration) -> Result<Vec<QueueMessage>> {
+        let _guard = self.lock.lock().unwrap();
+        let now = unix_now();
+        let mut received = vec![];
+        for mut message in self.read_all()? {
+            if received.len() >= max_messages {
+                break;
+            }
+            if message.visible_at > now {
+                continue;
+            }
+            received.push(message.deliver(now, visibility_timeout));
+            self.write(&message)?;
+        }
+        Ok(received)
+    }
+
+    async fn ack(&self, receipt: &str) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        for message in self.read_all()? {
+            if message.receipt.as_deref() == Some(receipt) {
+                fs::remove_file(self.path(&message.id))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct DeadLetterRecord<'a> {
+    id: &'a str,
+    body: &'a str,
+    attempts: u32,
+    reason: &'a str,
+    dead_lettered_at: DateTime<Utc>,
+}
+
+// appends dead letters as JSON lines for inspection and manual replay
+pub struct FileDeadLetterSink {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileDeadLetterSink {
+    pub fn new(path: PathBuf) -> Arc<Self> {
+        let this = Self {
+            path,
+            lock: Mutex::new(()),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl DeadLetterSink for FileDeadLetterSink {
+    async fn dead_letter(&self, message: &QueueMessage, reason: &str) -> Result<()> {
+        let record = DeadLetterRecord {
+            id: &message.id,
+            body: &message.body,
+            attempts: message.attempts,
+            reason,
+            dead_lettered_at: Utc::now(),
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
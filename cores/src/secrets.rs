@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::config::ConfigLoader;
+
+// where secrets such as signing secrets and API keys are kept apart from the deployment
+#[async_trait]
+pub trait SecretsProvider: Send + Sync {
+    // None when the provider does not know the secret
+    async fn get(&self, name: &str) -> Result<Option<String>>;
+}
+
+// YOSHINO_SECRETS_PROVIDER selects the implementation, secrets are read from the environment without one
+// * `env` the environment
+// * `file` a JSON object of names to values at YOSHINO_SECRETS_FILE, e.g. a mounted secret or a local stand-in
+// * `secretsmanager` the JSON object in the secret YOSHINO_SECRETS_ID
+// * `ssm` one SecureString parameter per secret under YOSHINO_SSM_PREFIX
+// values are cached for YOSHINO_SECRETS_TTL seconds, so that rotated secrets are picked up without a restart
+pub fn load_secrets_provider(loader: &mut ConfigLoader) -> Option<Arc<dyn SecretsProvider>> {
+    let kind = loader.optional("YOSHINO_SECRETS_PROVIDER")?;
+    let provider: Arc<dyn SecretsProvider> = match kind.as_str() {
+        "env" => Arc::new(EnvSecretsProvider {}),
+        "file" => FileSecretsProvider::new(PathBuf::from(loader.required("YOSHINO_SECRETS_FILE"))),
+        "secretsmanager" => SecretsManagerProvider::new(&loader.required("YOSHINO_SECRETS_ID")),
+        "ssm" => {
+            let prefix = loader.optional("YOSHINO_SSM_PREFIX")
+                .unwrap_or_else(|| "/yoshino-radio/".into());
+            SsmParameterProvider::new(&prefix)
+        },
+        _ => {
+            loader.invalid(format!("YOSHINO_SECRETS_PROVIDER has an invalid value {:?}: expected env, file, secretsmanager or ssm", kind));
+            return None
+        },
+    };
+    let ttl = loader.parse("YOSHINO_SECRETS_TTL", 300);
+    Some(CachingSecretsProvider::new(provider, Duration::from_secs(ttl)))
+}
+
+// A secret that is re-read through the secrets provider whenever it is used. The provider's cache expires,
+// so that a rotation is picked up without a restart. The value loaded on startup is used when the provider fails.
+#[derive(Clone)]
+pub struct Secret {
+    name: String,
+    value: String,
+    secrets_provider: Option<Arc<dyn SecretsProvider>>,
+}
+
+impl Secret {
+    pub fn new(name: &str, value: String, secrets_provider: Option<&Arc<dyn SecretsProvider>>) -> Self {
+        Self {
+            name: name.into(),
+            value,
+            secrets_provider: secrets_provider.cloned(),
+        }
+    }
+
+    // not backed by a provider, e.g. a token kept in the installation store
+    pub fn fixed(value: String) -> Self {
+        Self {
+            name: String::new(),
+            value,
+            secrets_provider: None,
+        }
+    }
+
+    pub async fn get(&self) -> String {
+        let Some(ref secrets_provider) = self.secrets_provider else { return self.value.clone() };
+        match secrets_provider.get(&self.name).await {
+            Ok(Some(value)) => value,
+            Ok(None) => self.value.clone(),
+            Err(error) => {
+                info!(error = ?error, name = self.name, "failed to refresh a secret");
+                self.value.clone()
+            },
+        }
+    }
+}
+
+pub struct EnvSecretsProvider {}
+
+#[async_trait]
+impl SecretsProvider for EnvSecretsProvider {
+    async fn get(&self, name: &str) -> Result<Option<String>> {
+        Ok(env::var(name).ok())
+    }
+}
+
+pub struct FileSecretsProvider {
+    path: PathBuf,
+}
+
+impl FileSecretsProvider {
+    pub fn new(path: PathBuf) -> Arc<Self> {
+        let this = Self {
+            path,
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl SecretsProvider for FileSecretsProvider {
+    async fn get(&self, name: &str) -> Result<Option<String>> {
+        let text = fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read secrets file {:?}", self.path))?;
+        let mut secrets: HashMap<String, String> = serde_json::from_str(&text)
+            .with_context(|| format!("broken secrets file {:?}", self.path))?;
+        Ok(secrets.remove(name))
+    }
+}
+
+// keeps values, including unknown ones, for the ttl
+pub struct CachingSecretsProvider {
+    inner: Arc<dyn SecretsProvider>,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, Option<String>)>>,
+}
+
+impl CachingSecretsProvider {
+    pub fn new(inner: Arc<dyn SecretsProvider>, ttl: Duration) -> Arc<Self> {
+        let this = Self {
+            inner,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl SecretsProvider for CachingSecretsProvider {
+    async fn get(&self, name: &str) -> Result<Option<String>> {
+        if let Some((fetched_at, value)) = self.entries.lock().unwrap().get(name) {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(value.clone())
+            }
+        }
+        let value = self.inner.get(name).await?;
+        self.entries.lock().unwrap().insert(name.into(), (Instant::now(), value.clone()));
+        Ok(value)
+    }
+}
+
+// Secrets Manager and Parameter Store are read through the AWS Parameters and Secrets Lambda Extension,
+// which has to be added to the function as a layer
+// https://docs.aws.amazon.com/secretsmanager/latest/userguide/retrieving-secrets_lambda.html
+struct ParametersSecretsExtension {
+    client: reqwest::Client,
+    endpoint: String,
+}
+
+impl ParametersSecretsExtension {
+    fn new() -> Self {
+        let port = env::var("PARAMETERS_SECRETS_EXTENSION_HTTP_PORT")
+            .unwrap_or_else(|_| "2773".into());
+        Self {
+            client: reqwest::Client::new(),
+            endpoint: format!("http://localhost:{}", port),
+        }
+    }
+
+    // None when the secret or parameter does not exist
+    async fn get<T>(&self, path: &str, query: &[(&str, &str)]) -> Result<Option<T>> where T: for<'de> Deserialize<'de> {
+        let session_token = env::var("AWS_SESSION_TOKEN").unwrap_or_default();
+        let response = self.client.get(format!("{}{}", self.endpoint, path))
+            .query(query)
+            .header("X-Aws-Parameters-Secrets-Token", session_token)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let text = response.text().await?;
+            // ResourceNotFoundException, ParameterNotFound
+            if text.contains("NotFound") {
+                return Ok(None)
+            }
+            bail!("parameters and secrets extension failure. {}", text);
+        }
+        Ok(Some(response.json().await?))
+    }
+}
+
+// https://docs.aws.amazon.com/secretsmanager/latest/apireference/API_GetSecretValue.html
+#[derive(Deserialize)]
+struct GetSecretValueResponse {
+    #[serde(rename = "SecretString")]
+    secret_string: Option<String>,
+}
+
+// the key/value pairs of a single secret, as entered in the Secrets Manager console
+pub struct SecretsManagerProvider {
+    extension: ParametersSecretsExtension,
+    secret_id: String,
+}
+
+impl SecretsManagerProvider {
+    pub fn new(secret_id: &str) -> Arc<Self> {
+        let this = Self {
+            extension: ParametersSecretsExtension::new(),
+            secret_id: secret_id.into(),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl SecretsProvider for SecretsManagerProvider {
+    async fn get(&self, name: &str) -> Result<Option<String>> {
+        let response: Option<GetSecretValueResponse> = self.extension
+            .get("/secretsmanager/get", &[("secretId", self.secret_id.as_str())])
+            .await?;
+        let Some(secret_string) = response.and_then(|v| v.secret_string) else { return Ok(None) };
+        let mut secrets: HashMap<String, String> = serde_json::from_str(&secret_string)
+            .with_context(|| format!("secret {} is not a JSON object of strings", self.secret_id))?;
+        Ok(secrets.remove(name))
+    }
+}
+
+// https://docs.aws.amazon.com/systems-manager/latest/APIReference/API_GetParameter.html
+#[derive(Deserialize)]
+struct GetParameterResponse {
+    #[serde(rename = "Parameter")]
+    parameter: Parameter,
+}
+
+#[derive(Deserialize)]
+struct Parameter {
+    #[serde(rename = "Value")]
+    value: String,
+}
+
+// SLACK_SIGNING_SECRET is read from `<prefix>SLACK_SIGNING_SECRET`
+pub struct SsmParameterProvider {
+    extension: ParametersSecretsExtension,
+    prefix: String,
+}
+
+impl SsmParameterProvider {
+    pub fn new(prefix: &str) -> Arc<Self> {
+        let this = Self {
+            extension: ParametersSecretsExtension::new(),
+            prefix: prefix.into(),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl SecretsProvider for SsmParameterProvider {
+    async fn get(&self, name: &str) -> Result<Option<String>> {
+        let parameter_name = format!("{}{}", self.prefix, name);
+        let response: Option<GetParameterResponse> = self.extension
+            .get("/systemsmanager/parameters/get", &[("name", parameter_name.as_str()), ("withDecryption", "true")])
+            .await?;
+        Ok(response.map(|v| v.parameter.value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct SecretsFile(PathBuf);
+
+    impl SecretsFile {
+        fn new() -> Self {
+            let path = env::temp_dir().join(format!("yoshino-radio-secrets-{}.json", uuid::Uuid::new_v4()));
+            Self(path)
+        }
+
+        fn write(&self, text: &str) {
+            fs::write(&self.0, text).unwrap();
+        }
+    }
+
+    impl Drop for SecretsFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn secret(file: &SecretsFile, ttl: Duration) -> Secret {
+        let provider: Arc<dyn SecretsProvider> = FileSecretsProvider::new(file.0.clone());
+        let provider: Arc<dyn SecretsProvider> = CachingSecretsProvider::new(provider, ttl);
+        Secret::new("OPENAI_API_KEY", "startup".into(), Some(&provider))
+    }
+
+    #[tokio::test]
+    async fn picks_up_a_rotated_secret_once_the_cache_expires() {
+        let file = SecretsFile::new();
+        file.write(r#"{"OPENAI_API_KEY": "first"}"#);
+        let secret = secret(&file, Duration::ZERO);
+        assert_eq!(secret.get().await, "first");
+        file.write(r#"{"OPENAI_API_KEY": "second"}"#);
+        assert_eq!(secret.get().await, "second");
+    }
+
+    #[tokio::test]
+    async fn keeps_the_cached_secret_within_the_ttl() {
+        let file = SecretsFile::new();
+        file.write(r#"{"OPENAI_API_KEY": "first"}"#);
+        let secret = secret(&file, Duration::from_secs(300));
+        assert_eq!(secret.get().await, "first");
+        file.write(r#"{"OPENAI_API_KEY": "second"}"#);
+        assert_eq!(secret.get().await, "first");
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_startup_value() {
+        let file = SecretsFile::new();
+        file.write(r#"{"SLACK_BOT_TOKEN": "xoxb"}"#);
+        let secret = secret(&file, Duration::ZERO);
+        // unknown to the provider
+        assert_eq!(secret.get().await, "startup");
+        // the provider fails
+        file.write("not json");
+        assert_eq!(secret.get().await, "startup");
+    }
+
+    #[tokio::test]
+    async fn fixed_secret() {
+        assert_eq!(Secret::fixed("xoxb".into()).get().await, "xoxb");
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::Instant;
+
+use anyhow::{Result, bail};
+use reqwest::{RequestBuilder, Response};
+use serde::Deserialize;
+
+use crate::config::ConfigLoader;
+use crate::metrics;
+
+#[derive(Deserialize)]
+struct ApiResponseStatus {
+    ok: bool,
+    error: Option<String>,
+}
+
+// times every call for the slack_api_latency_ms histogram of its method
+pub async fn send(method: &'static str, request: RequestBuilder) -> Result<Response> {
+    let started = Instant::now();
+    let result = request.send().await;
+    metrics::observe("slack_api_latency_ms", &[("method", method)], started.elapsed());
+    if result.is_err() {
+        metrics::increment("errors", &[("type", "slack_api")]);
+    }
+    Ok(result?)
+}
+
+// Web API methods answer 200 with `ok: false` on failure
+pub fn check_status(text: &str) -> Result<()> {
+    let status: ApiResponseStatus = serde_json::from_str(text)?;
+    if !status.ok {
+        bail!("slack api error {}", status.error.unwrap_or_default());
+    }
+    Ok(())
+}
+
+// https://api.slack.com/methods/users.info
+#[derive(Deserialize)]
+pub struct UserInfoResponseBody {
+    pub user: UserInfo,
+}
+
+// https://api.slack.com/types/user
+#[derive(Deserialize, Debug)]
+pub struct UserInfo {
+    pub id: String,
+    pub name: String,
+    pub real_name: Option<String>,
+    pub team_id: Option<String>,
+    pub tz: Option<String>,
+    // e.g. ja-JP, only with include_locale
+    pub locale: Option<String>,
+    pub is_bot: Option<bool>,
+    // multi-channel guests
+    pub is_restricted: Option<bool>,
+    // single-channel guests
+    pub is_ultra_restricted: Option<bool>,
+    pub profile: Option<UserInfoProfile>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserInfoProfile {
+    pub display_name: Option<String>,
+    pub title: Option<String>,
+}
+
+// YOSHINO_ADMIN_USERS is a comma-separated list of Slack user IDs,
+// the same list on both runtimes lets admins change the access policy and read usage and audit records
+#[derive(Clone, Debug, Default)]
+pub struct AdminUsers(Vec<String>);
+
+impl AdminUsers {
+    pub fn load(loader: &mut ConfigLoader) -> Self {
+        let users = loader.optional("YOSHINO_ADMIN_USERS")
+            .map(|v| v.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect())
+            .unwrap_or_default();
+        Self(users)
+    }
+
+    pub fn contains(&self, user: &str) -> bool {
+        self.0.iter().any(|v| v == user)
+    }
+}
@@ -0,0 +1,74 @@
+use std::future::Future;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use tokio::task::JoinHandle;
+use tokio_util::task::TaskTracker;
+
+// Tracks background tasks so that a flush waits for the tasks spawned before it began.
+// Each flush closes the generation of tasks spawned since the previous one and waits for it,
+// tasks spawned meanwhile go to the next generation, so concurrent flushes never wait on a reopened tracker.
+#[derive(Clone)]
+pub struct FlushTracker {
+    // every task, drained on shutdown
+    all: TaskTracker,
+    generation: Arc<Mutex<TaskTracker>>,
+}
+
+impl Default for FlushTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlushTracker {
+    pub fn new() -> Self {
+        Self {
+            all: TaskTracker::new(),
+            generation: Arc::new(Mutex::new(TaskTracker::new())),
+        }
+    }
+
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        // tracked under the lock, a flush swapping the generation cannot miss it
+        let task = self.generation.lock().unwrap().track_future(task);
+        self.all.spawn(task)
+    }
+
+    // waits for the tasks spawned before the flush began, including those an earlier flush is still waiting for
+    pub async fn flush(&self) {
+        let next = TaskTracker::new();
+        let previous = mem::replace(&mut *self.generation.lock().unwrap(), next.clone());
+        previous.close();
+        // a later flush waits for this generation through the next one
+        let waiting = previous.clone();
+        tokio::spawn(next.track_future(async move { waiting.wait().await }));
+        previous.wait().await;
+    }
+
+    // stops the tracker from becoming empty before it is closed, see TaskTracker::close
+    pub fn close(&self) {
+        self.all.close();
+    }
+
+    pub async fn wait(&self) {
+        self.all.wait().await
+    }
+
+    pub fn len(&self) -> usize {
+        self.all.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.all.is_empty()
+    }
+
+    // the tracker of every task
+    pub fn task_tracker(&self) -> TaskTracker {
+        self.all.clone()
+    }
+}
@@ -0,0 +1,173 @@
+use std::env;
+
+use anyhow::{Result, Context};
+use tracing::Span;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+
+use crate::redaction::{Redactor, RedactingMakeWriter};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+// YOSHINO_LOG_FORMAT selects the log format
+// * `json` (default) one object per line with the fields of the event and of the spans it happened in
+// * `text` the plain format, easier to read locally
+// YOSHINO_LOG_LEVEL is `info` by default, message bodies and API payloads are only logged at `debug`
+// OTEL_EXPORTER_OTLP_ENDPOINT additionally exports the spans over OTLP when built with the `otlp` feature
+pub fn init_tracing(service_name: &str) -> Result<()> {
+    let level: LevelFilter = match env::var("YOSHINO_LOG_LEVEL") {
+        Ok(v) => v.parse().with_context(|| format!("invalid log level {}", v))?,
+        Err(_) => LevelFilter::INFO,
+    };
+    let redactor = Redactor::from_env()?;
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(RedactingMakeWriter::new(&redactor))
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time();
+    let fmt: BoxedLayer = match env::var("YOSHINO_LOG_FORMAT").as_deref() {
+        Ok("text") => fmt.boxed(),
+        _ => fmt.json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+    };
+    #[cfg(feature = "otlp")]
+    let otlp = otlp::layer(service_name)?;
+    #[cfg(not(feature = "otlp"))]
+    let otlp: Option<BoxedLayer> = {
+        let _ = service_name;
+        None
+    };
+    let layers: Vec<BoxedLayer> = Some(fmt).into_iter()
+        .chain(otlp)
+        .collect();
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(level)
+        .try_init()?;
+    Ok(())
+}
+
+// exports the spans ended so far, call before the execution environment may be frozen
+pub async fn flush_tracing() {
+    #[cfg(feature = "otlp")]
+    otlp::flush().await;
+}
+
+// the trace context of the current span as a traceparent, none unless its spans are exported
+pub fn current_traceparent() -> Option<String> {
+    #[cfg(feature = "otlp")]
+    return otlp::current_traceparent();
+    #[cfg(not(feature = "otlp"))]
+    None
+}
+
+// makes the span a child of the remote span the traceparent came from
+pub fn set_remote_parent(span: &Span, traceparent: Option<&str>) {
+    #[cfg(feature = "otlp")]
+    if let Some(traceparent) = traceparent {
+        otlp::set_remote_parent(span, traceparent);
+    }
+    #[cfg(not(feature = "otlp"))]
+    let _ = (span, traceparent);
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use std::collections::HashMap;
+    use std::env;
+    use std::sync::OnceLock;
+
+    use anyhow::Result;
+    use opentelemetry::KeyValue;
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::{runtime, trace, Resource};
+    use tracing::{info, Span};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::Layer;
+    use tracing_subscriber::filter::filter_fn;
+
+    use super::BoxedLayer;
+
+    static PROVIDER: OnceLock<trace::TracerProvider> = OnceLock::new();
+
+    // https://opentelemetry.io/docs/specs/otel/protocol/exporter/
+    pub fn layer(service_name: &str) -> Result<Option<BoxedLayer>> {
+        if env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_err() {
+            return Ok(None)
+        }
+        // the exporter reads the endpoint and headers from the standard OTEL_EXPORTER_OTLP_* variables
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+            .with_trace_config(trace::config()
+                .with_resource(Resource::new(vec![KeyValue::new("service.name", service_name.to_string())])))
+            .install_batch(runtime::Tokio)?;
+        if let Some(provider) = tracer.provider() {
+            let _ = PROVIDER.set(provider);
+        }
+        // only spans are exported, their fields are IDs while events may carry what the redactor scrubs from the logs
+        let layer = tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(filter_fn(|metadata| metadata.is_span()));
+        Ok(Some(layer.boxed()))
+    }
+
+    // https://www.w3.org/TR/trace-context/
+    pub fn current_traceparent() -> Option<String> {
+        let mut carrier = HashMap::new();
+        TraceContextPropagator::new().inject_context(&Span::current().context(), &mut carrier);
+        carrier.remove("traceparent")
+    }
+
+    pub fn set_remote_parent(span: &Span, traceparent: &str) {
+        let carrier = HashMap::from([("traceparent".to_string(), traceparent.to_string())]);
+        span.set_parent(TraceContextPropagator::new().extract(&carrier));
+    }
+
+    pub async fn flush() {
+        let Some(provider) = PROVIDER.get() else { return };
+        // force_flush blocks until the batch is exported by the task on the runtime
+        let result = tokio::task::spawn_blocking(|| provider.force_flush()).await;
+        if let Ok(results) = result {
+            for error in results.into_iter().filter_map(|v| v.err()) {
+                info!("span export failed {:?}", error);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "otlp"))]
+mod tests {
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use tracing::info_span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[test]
+    fn joins_the_trace_of_the_traceparent() {
+        // spans are only sampled while the provider is alive
+        let provider = TracerProvider::builder().build();
+        let tracer = provider.tracer("test");
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer));
+        tracing::subscriber::with_default(subscriber, || {
+            let web = info_span!("request");
+            let traceparent = web.in_scope(current_traceparent).unwrap();
+            let worker = info_span!("message");
+            set_remote_parent(&worker, Some(&traceparent));
+            let web_trace_id = web.context().span().span_context().trace_id();
+            assert_eq!(worker.context().span().span_context().trace_id(), web_trace_id);
+            assert!(traceparent.contains(&web_trace_id.to_string()));
+        });
+    }
+}
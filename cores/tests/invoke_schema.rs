@@ -0,0 +1,93 @@
+// The invoke message is the contract between web and worker, which deploy separately.
+// The golden files pin its JSON, a change to them is a change to the schema version.
+
+use chrono::{TimeZone, Utc};
+use cores::events::{EventCallback, File};
+use cores::ipc::{InvokeMessage, InvokePayload, INVOKE_SCHEMA_VERSION};
+use serde_json::Value;
+
+fn golden(name: &str) -> String {
+    let path = format!("{}/tests/golden/{}", env!("CARGO_MANIFEST_DIR"), name);
+    std::fs::read_to_string(&path).unwrap_or_else(|error| panic!("{} {}", path, error))
+}
+
+fn event_callback() -> EventCallback {
+    serde_json::from_str(&golden("slack_message.json")).unwrap()
+}
+
+#[test]
+fn parses_the_slack_callback() {
+    let callback = event_callback();
+    assert_eq!(callback.team_id.as_deref(), Some("T0123ABCD"));
+    assert_eq!(callback.event.subtype.as_deref(), Some("file_share"));
+    assert_eq!(callback.event.files, vec![File {
+        id: "F0123ABCD".into(),
+        mimetype: "image/png".into(),
+        url_private_download: Some("https://files.slack.com/files-pri/T0123ABCD-F0123ABCD/download/photo.png".into()),
+        size: 52431,
+    }]);
+    assert_eq!(callback.dedupe_key().as_deref(), Some("event_id:Ev0123ABCD"));
+}
+
+#[test]
+fn parses_the_slack_message_changed_callback() {
+    let callback: EventCallback = serde_json::from_str(&golden("slack_message_changed.json")).unwrap();
+    let message = callback.event.message.unwrap();
+    let previous_message = callback.event.previous_message.unwrap();
+    assert_eq!(message.text, "明日の天気は？");
+    assert_eq!(message.thread_ts.as_deref(), Some("1712000000.000100"));
+    assert_eq!(previous_message.text, "今日の天気は？");
+    assert_eq!(callback.event.text, "");
+}
+
+#[test]
+fn encodes_the_event_callback() {
+    let message = InvokeMessage {
+        schema_version: INVOKE_SCHEMA_VERSION,
+        trace_id: "0b9c6a54-6f1e-4f43-9d7e-6d2a0c1e8f11".into(),
+        received_at: Utc.with_ymd_and_hms(2024, 4, 1, 19, 33, 20).unwrap(),
+        team_id: Some("T0123ABCD".into()),
+        traceparent: Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".into()),
+        payload: InvokePayload::EventCallback(event_callback()),
+    };
+    let expected: Value = serde_json::from_str(&golden("invoke_event_callback.json")).unwrap();
+    assert_eq!(serde_json::to_value(&message).unwrap(), expected);
+}
+
+#[test]
+fn decodes_the_event_callback() {
+    let message = InvokeMessage::decode(&golden("invoke_event_callback.json")).unwrap();
+    assert_eq!(message.traceparent.as_deref(), Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+    let InvokePayload::EventCallback(callback) = message.payload else { panic!("expected an event callback") };
+    assert_eq!(callback, event_callback());
+}
+
+#[test]
+fn round_trips_the_slash_command() {
+    let text = golden("invoke_slash_command.json");
+    let message = InvokeMessage::decode(&text).unwrap();
+    let InvokePayload::SlashCommand { ref command, ref text, .. } = message.payload else { panic!("expected a slash command") };
+    assert_eq!((command.as_str(), text.as_str()), ("/yoshino-usage", "2024-04"));
+    let expected: Value = serde_json::from_str(&golden("invoke_slash_command.json")).unwrap();
+    assert_eq!(serde_json::to_value(&message).unwrap(), expected);
+}
+
+#[test]
+fn accepts_a_newer_minor_version() {
+    let message = InvokeMessage::decode(&golden("invoke_newer_minor.json")).unwrap();
+    assert_eq!((message.schema_version.major, message.schema_version.minor), (2, 7));
+    assert!(matches!(message.payload, InvokePayload::SlashCommand { .. }));
+}
+
+#[test]
+fn rejects_another_major_version() {
+    let error = InvokeMessage::decode(&golden("invoke_v1_event_callback.json")).unwrap_err();
+    assert!(error.to_string().contains("unsupported invoke message schema version 1.1"), "{}", error);
+}
+
+#[test]
+fn skips_a_payload_of_a_newer_minor_version() {
+    let message = InvokeMessage::decode(&golden("invoke_newer_payload.json")).unwrap();
+    assert_eq!(message.trace_id, "0b9c6a54-6f1e-4f43-9d7e-6d2a0c1e8f15");
+    assert!(matches!(message.payload, InvokePayload::Unknown));
+}
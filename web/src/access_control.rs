@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use cores::metrics;
+use cores::slack::{AdminUsers, UserInfo};
+use tracing::info;
+
+use crate::access_policy::{AccessPolicy, AccessPolicyStore, Denial, is_direct_message, policy_key};
+use crate::config::Config;
+use crate::runtime_context::RuntimeContext;
+use crate::slack_client::SlackClient;
+
+// the Slack API methods an access check calls, in the workspace of the team
+#[async_trait]
+pub trait WorkspaceSlack: Send + Sync {
+    async fn usergroup_users(&self, team_id: Option<&str>, usergroup: &str) -> Result<Vec<String>>;
+    async fn user_info(&self, team_id: Option<&str>, user: &str) -> Result<UserInfo>;
+    async fn post_ephemeral(&self, team_id: Option<&str>, channel: &str, user: &str, text: &str) -> Result<()>;
+}
+
+#[async_trait]
+impl WorkspaceSlack for Config {
+    async fn usergroup_users(&self, team_id: Option<&str>, usergroup: &str) -> Result<Vec<String>> {
+        SlackClient::for_team(self, team_id).await?.usergroup_users(usergroup).await
+    }
+
+    async fn user_info(&self, team_id: Option<&str>, user: &str) -> Result<UserInfo> {
+        SlackClient::for_team(self, team_id).await?.user_info(user).await
+    }
+
+    async fn post_ephemeral(&self, team_id: Option<&str>, channel: &str, user: &str, text: &str) -> Result<()> {
+        SlackClient::for_team(self, team_id).await?.post_ephemeral(channel, user, text).await
+    }
+}
+
+// values with the time they were fetched
+type Entries<K, V> = Mutex<HashMap<K, (Instant, V)>>;
+
+// keeps policies and user group members for YOSHINO_ACCESS_CACHE_TTL seconds,
+// every message would otherwise read the table and call usergroups.users.list, which has a low rate limit
+pub struct AccessCache {
+    ttl: Duration,
+    policies: Entries<String, AccessPolicy>,
+    // by team and user group
+    usergroups: Entries<(String, String), Vec<String>>,
+}
+
+impl AccessCache {
+    pub fn new(ttl: Duration) -> Arc<Self> {
+        let this = Self {
+            ttl,
+            policies: Mutex::new(HashMap::new()),
+            usergroups: Mutex::new(HashMap::new()),
+        };
+        Arc::new(this)
+    }
+
+    fn fresh<K: Eq + Hash, V: Clone>(&self, entries: &Entries<K, V>, key: &K) -> Option<V> {
+        let entries = entries.lock().unwrap();
+        let (fetched_at, value) = entries.get(key)?;
+        (fetched_at.elapsed() < self.ttl).then(|| value.clone())
+    }
+
+    // a policy changed in this execution environment applies right away, in others once the entry expires
+    pub fn forget_policy(&self, key: &str) {
+        self.policies.lock().unwrap().remove(key);
+    }
+}
+
+// enforces the workspace's access policy before anything is dispatched to the worker
+pub struct AccessControl {
+    admin_users: AdminUsers,
+    store: Arc<dyn AccessPolicyStore>,
+    slack: Arc<dyn WorkspaceSlack>,
+    cache: Arc<AccessCache>,
+}
+
+impl AccessControl {
+    pub fn new(runtime_context: &Arc<RuntimeContext>) -> Arc<Self> {
+        let config = runtime_context.config();
+        Self::with_parts(
+            config.admin_users.clone(),
+            Arc::clone(runtime_context.access_policy_store()),
+            Arc::clone(config) as Arc<dyn WorkspaceSlack>,
+            Arc::clone(runtime_context.access_cache()),
+        )
+    }
+
+    pub fn with_parts(admin_users: AdminUsers, store: Arc<dyn AccessPolicyStore>, slack: Arc<dyn WorkspaceSlack>, cache: Arc<AccessCache>) -> Arc<Self> {
+        let this = Self {
+            admin_users,
+            store,
+            slack,
+            cache,
+        };
+        Arc::new(this)
+    }
+
+    async fn policy(&self, team_id: Option<&str>) -> Result<AccessPolicy> {
+        let key = policy_key(team_id).to_string();
+        if let Some(policy) = self.cache.fresh(&self.cache.policies, &key) {
+            return Ok(policy)
+        }
+        let policy = self.store.find(&key).await?.unwrap_or_default();
+        self.cache.policies.lock().unwrap().insert(key, (Instant::now(), policy.clone()));
+        Ok(policy)
+    }
+
+    async fn usergroup_users(&self, team_id: Option<&str>, usergroup: &str) -> Result<Vec<String>> {
+        let key = (policy_key(team_id).to_string(), usergroup.to_string());
+        if let Some(users) = self.cache.fresh(&self.cache.usergroups, &key) {
+            return Ok(users)
+        }
+        let users = self.slack.usergroup_users(team_id, usergroup).await?;
+        self.cache.usergroups.lock().unwrap().insert(key, (Instant::now(), users.clone()));
+        Ok(users)
+    }
+
+    // None when the user may talk to Yoshino in the channel,
+    // admins are always allowed so that they cannot lock themselves out.
+    // Fails closed: a policy or Slack lookup that fails turns the user away with Denial::Unavailable.
+    pub async fn check(&self, team_id: Option<&str>, user: &str, channel: &str) -> Option<Denial> {
+        match self.evaluate(team_id, user, channel).await {
+            Ok(denial) => denial,
+            Err(error) => {
+                info!(error = ?error, user, channel, "access check failed");
+                metrics::increment("errors", &[("type", "access")]);
+                Some(Denial::Unavailable)
+            },
+        }
+    }
+
+    async fn evaluate(&self, team_id: Option<&str>, user: &str, channel: &str) -> Result<Option<Denial>> {
+        if self.admin_users.contains(user) {
+            return Ok(None)
+        }
+        let policy = self.policy(team_id).await?;
+        if policy.blocked_users.contains(user) {
+            return Ok(Some(Denial::BlockedUser))
+        }
+        if policy.blocked_channels.contains(channel) {
+            return Ok(Some(Denial::Channel))
+        }
+        if !policy.allowed_channels.is_empty() && !is_direct_message(channel) && !policy.allowed_channels.contains(channel) {
+            return Ok(Some(Denial::Channel))
+        }
+        let restricts_users = !policy.allowed_users.is_empty() || !policy.allowed_usergroups.is_empty();
+        // only asks Slack when the policy depends on the answer
+        if restricts_users && !policy.allowed_users.contains(user) {
+            let mut member = false;
+            for usergroup in &policy.allowed_usergroups {
+                if self.usergroup_users(team_id, usergroup).await?.iter().any(|v| v == user) {
+                    member = true;
+                    break;
+                }
+            }
+            if !member {
+                return Ok(Some(Denial::NotAllowedUser))
+            }
+        }
+        if policy.deny_guests || policy.deny_external {
+            let user_info = self.slack.user_info(team_id, user).await?;
+            let guest = user_info.is_restricted.unwrap_or(false) || user_info.is_ultra_restricted.unwrap_or(false);
+            if policy.deny_guests && guest {
+                return Ok(Some(Denial::Guest))
+            }
+            // users of the workspace itself carry its team ID
+            let external = matches!((team_id, &user_info.team_id), (Some(team_id), Some(user_team_id)) if team_id != user_team_id);
+            if policy.deny_external && external {
+                return Ok(Some(Denial::External))
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn record_denied(&self, user: &str, channel: &str, denial: Denial) {
+        info!(user, channel, denial = denial.name(), "access denied");
+        metrics::increment("access_denied", &[("reason", denial.name())]);
+    }
+
+    // tells the user privately why nothing is going to happen
+    pub async fn notify_denied(&self, team_id: Option<&str>, user: &str, channel: &str, denial: Denial) -> Result<()> {
+        self.record_denied(user, channel, denial);
+        self.slack.post_ephemeral(team_id, channel, user, denial.text()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use anyhow::bail;
+    use tokio::sync::Mutex as AsyncMutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeStore {
+        policy: Option<AccessPolicy>,
+        failing: bool,
+        finds: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl AccessPolicyStore for FakeStore {
+        async fn find(&self, _key: &str) -> Result<Option<AccessPolicy>> {
+            self.finds.fetch_add(1, Ordering::SeqCst);
+            if self.failing {
+                bail!("table is unavailable")
+            }
+            Ok(self.policy.clone())
+        }
+
+        async fn save(&self, _key: &str, _policy: &AccessPolicy) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeSlack {
+        members: Vec<String>,
+        guest: bool,
+        failing: bool,
+        usergroup_calls: AtomicUsize,
+        ephemerals: AsyncMutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl WorkspaceSlack for FakeSlack {
+        async fn usergroup_users(&self, _team_id: Option<&str>, _usergroup: &str) -> Result<Vec<String>> {
+            self.usergroup_calls.fetch_add(1, Ordering::SeqCst);
+            if self.failing {
+                bail!("ratelimited")
+            }
+            Ok(self.members.clone())
+        }
+
+        async fn user_info(&self, _team_id: Option<&str>, user: &str) -> Result<UserInfo> {
+            if self.failing {
+                bail!("user_not_found")
+            }
+            let user_info = serde_json::json!({"id": user, "name": "hiroshi", "team_id": "T0123ABCD", "is_restricted": self.guest});
+            Ok(serde_json::from_value(user_info)?)
+        }
+
+        async fn post_ephemeral(&self, _team_id: Option<&str>, _channel: &str, _user: &str, text: &str) -> Result<()> {
+            self.ephemerals.lock().await.push(text.into());
+            Ok(())
+        }
+    }
+
+    fn new_access_control(store: Arc<FakeStore>, slack: Arc<FakeSlack>) -> Arc<AccessControl> {
+        AccessControl::with_parts(AdminUsers::default(), store, slack, AccessCache::new(Duration::from_secs(60)))
+    }
+
+    fn policy(text: &str) -> AccessPolicy {
+        let mut policy = AccessPolicy::default();
+        policy.apply_command(text, "U0ADMIN").unwrap();
+        policy
+    }
+
+    #[tokio::test]
+    async fn allows_everyone_without_a_policy() {
+        let access_control = new_access_control(Arc::default(), Arc::default());
+        assert_eq!(access_control.check(Some("T0123ABCD"), "U0123ABCD", "C0123ABCD").await, None);
+    }
+
+    #[tokio::test]
+    async fn denies_blocked_users_and_channels_outside_the_allowed_ones() {
+        let store = Arc::new(FakeStore { policy: Some(policy("block U0BLOCKED")), ..Default::default() });
+        let access_control = new_access_control(store, Arc::default());
+        assert_eq!(access_control.check(Some("T0123ABCD"), "U0BLOCKED", "D0123ABCD").await, Some(Denial::BlockedUser));
+        let store = Arc::new(FakeStore { policy: Some(policy("allow <#C0ALLOWED|general>")), ..Default::default() });
+        let access_control = new_access_control(store, Arc::default());
+        assert_eq!(access_control.check(Some("T0123ABCD"), "U0123ABCD", "C0OTHER").await, Some(Denial::Channel));
+        assert_eq!(access_control.check(Some("T0123ABCD"), "U0123ABCD", "C0ALLOWED").await, None);
+        // DMs are not restricted by the allowed channels
+        assert_eq!(access_control.check(Some("T0123ABCD"), "U0123ABCD", "D0123ABCD").await, None);
+    }
+
+    #[tokio::test]
+    async fn allows_members_of_allowed_groups_and_caches_the_members() {
+        let store = Arc::new(FakeStore { policy: Some(policy("allow <!subteam^S0GROUP|@radio>")), ..Default::default() });
+        let slack = Arc::new(FakeSlack { members: vec!["U0MEMBER".into()], ..Default::default() });
+        let access_control = new_access_control(Arc::clone(&store), Arc::clone(&slack));
+        assert_eq!(access_control.check(Some("T0123ABCD"), "U0MEMBER", "D0123ABCD").await, None);
+        assert_eq!(access_control.check(Some("T0123ABCD"), "U0OTHER", "D0123ABCD").await, Some(Denial::NotAllowedUser));
+        assert_eq!(slack.usergroup_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(store.finds.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn denies_guests() {
+        let store = Arc::new(FakeStore { policy: Some(policy("guests deny")), ..Default::default() });
+        let slack = Arc::new(FakeSlack { guest: true, ..Default::default() });
+        let access_control = new_access_control(store, slack);
+        assert_eq!(access_control.check(Some("T0123ABCD"), "U0GUEST", "D0123ABCD").await, Some(Denial::Guest));
+    }
+
+    #[tokio::test]
+    async fn fails_closed_when_a_lookup_fails() {
+        let store = Arc::new(FakeStore { failing: true, ..Default::default() });
+        let slack = Arc::new(FakeSlack::default());
+        let access_control = new_access_control(store, Arc::clone(&slack));
+        let denial = access_control.check(Some("T0123ABCD"), "U0123ABCD", "D0123ABCD").await;
+        assert_eq!(denial, Some(Denial::Unavailable));
+        access_control.notify_denied(Some("T0123ABCD"), "U0123ABCD", "D0123ABCD", Denial::Unavailable).await.unwrap();
+        assert_eq!(*slack.ephemerals.lock().await, vec![Denial::Unavailable.text().to_string()]);
+        let store = Arc::new(FakeStore { policy: Some(policy("external deny")), ..Default::default() });
+        let slack = Arc::new(FakeSlack { failing: true, ..Default::default() });
+        let access_control = new_access_control(store, slack);
+        assert_eq!(access_control.check(Some("T0123ABCD"), "U0123ABCD", "D0123ABCD").await, Some(Denial::Unavailable));
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Result, Context, bail};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use cores::config::{ConfigLoader, StoreKind};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+// who may talk to Yoshino in a workspace, the default allows everyone
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct AccessPolicy {
+    // when either is non-empty, only the listed users and the members of the listed user groups are allowed
+    pub allowed_users: BTreeSet<String>,
+    pub allowed_usergroups: BTreeSet<String>,
+    pub blocked_users: BTreeSet<String>,
+    // when non-empty, channels other than DMs have to be listed
+    pub allowed_channels: BTreeSet<String>,
+    pub blocked_channels: BTreeSet<String>,
+    // multi-channel and single-channel guests
+    pub deny_guests: bool,
+    // users of other organizations in Slack Connect channels
+    pub deny_external: bool,
+    pub updated_by: Option<String>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+// why a user was turned away, each with the message shown to them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Denial {
+    BlockedUser,
+    NotAllowedUser,
+    Channel,
+    Guest,
+    External,
+    // the policy or Slack could not be asked, access control fails closed
+    Unavailable,
+}
+
+impl Denial {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::BlockedUser => "blocked_user",
+            Self::NotAllowedUser => "not_allowed_user",
+            Self::Channel => "channel",
+            Self::Guest => "guest",
+            Self::External => "external",
+            Self::Unavailable => "unavailable",
+        }
+    }
+
+    pub fn text(&self) -> &'static str {
+        match self {
+            Self::BlockedUser | Self::NotAllowedUser => "申し訳ありませぬー。わたくしとのお話は、管理のお役目の方が許された方のみとなっておりましてー。お心当たりがなければ、管理の方にお尋ねくださいー",
+            Self::Channel => "申し訳ありませぬー。こちらのチャンネルではお話をお受けできないのでしてー。DM か、許されたチャンネルでお声がけくださいー",
+            Self::Guest => "申し訳ありませぬー。ゲストの方とのお話は、お受けできないことになっておりましてー",
+            Self::External => "申し訳ありませぬー。他の組織の方とのお話は、お受けできないことになっておりましてー",
+            Self::Unavailable => "申し訳ありませぬー。ただいまお話をお受けできるか確かめられずにおりましてー。少し時間をおいて、もう一度お声がけくださいー",
+        }
+    }
+}
+
+// DM channel IDs start with D
+pub fn is_direct_message(channel: &str) -> bool {
+    channel.starts_with('D')
+}
+
+// a single policy serves a deployment without team IDs
+pub fn policy_key(team_id: Option<&str>) -> &str {
+    team_id.unwrap_or("default")
+}
+
+// what a mention or ID in the command text refers to
+#[derive(Debug, PartialEq)]
+enum Target {
+    User(String),
+    Channel(String),
+    Usergroup(String),
+}
+
+// `<@U123|name>`, `<#C123|name>`, `<!subteam^S123|@name>` or a bare ID
+// https://api.slack.com/reference/surfaces/formatting#advanced
+fn parse_target(word: &str) -> Option<Target> {
+    let id = |v: &str| v.split('|').next().unwrap_or_default().to_string();
+    if let Some(rest) = word.strip_prefix("<@").and_then(|v| v.strip_suffix('>')) {
+        return Some(Target::User(id(rest)))
+    }
+    if let Some(rest) = word.strip_prefix("<#").and_then(|v| v.strip_suffix('>')) {
+        return Some(Target::Channel(id(rest)))
+    }
+    if let Some(rest) = word.strip_prefix("<!subteam^").and_then(|v| v.strip_suffix('>')) {
+        return Some(Target::Usergroup(id(rest)))
+    }
+    if !word.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None
+    }
+    match word.chars().next()? {
+        'U' | 'W' => Some(Target::User(word.into())),
+        'C' | 'G' => Some(Target::Channel(word.into())),
+        'S' => Some(Target::Usergroup(word.into())),
+        _ => None,
+    }
+}
+
+pub const ACCESS_COMMAND_USAGE: &str = "`/yoshino-access [show]`\n`/yoshino-access allow|disallow|block|unblock <@user|#channel|@group> ...`\n`/yoshino-access guests|external allow|deny`\n`/yoshino-access reset`";
+
+impl AccessPolicy {
+    // applies `/yoshino-access` to the policy, false when the command only shows it
+    pub fn apply_command(&mut self, text: &str, user: &str) -> Result<bool> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words.as_slice() {
+            [] | ["show"] => return Ok(false),
+            ["reset"] => *self = Self::default(),
+            ["guests", setting] => self.deny_guests = parse_setting(setting)?,
+            ["external", setting] => self.deny_external = parse_setting(setting)?,
+            [action @ ("allow" | "disallow" | "block" | "unblock"), targets @ ..] if !targets.is_empty() => {
+                for word in targets {
+                    let target = parse_target(word)
+                        .with_context(|| format!("{} is not a user, channel or user group", word))?;
+                    self.apply_target(action, target)?;
+                }
+            },
+            _ => bail!("unknown command"),
+        }
+        self.updated_by = Some(user.into());
+        self.updated_at = Some(Utc::now());
+        Ok(true)
+    }
+
+    fn apply_target(&mut self, action: &str, target: Target) -> Result<()> {
+        match (action, target) {
+            ("allow", Target::User(id)) => { self.allowed_users.insert(id); },
+            ("allow", Target::Usergroup(id)) => { self.allowed_usergroups.insert(id); },
+            ("allow", Target::Channel(id)) => { self.allowed_channels.insert(id); },
+            ("disallow", Target::User(id)) => { self.allowed_users.remove(&id); },
+            ("disallow", Target::Usergroup(id)) => { self.allowed_usergroups.remove(&id); },
+            ("disallow", Target::Channel(id)) => { self.allowed_channels.remove(&id); },
+            ("block", Target::User(id)) => { self.blocked_users.insert(id); },
+            ("block", Target::Channel(id)) => { self.blocked_channels.insert(id); },
+            ("unblock", Target::User(id)) => { self.blocked_users.remove(&id); },
+            ("unblock", Target::Channel(id)) => { self.blocked_channels.remove(&id); },
+            (action, target) => bail!("cannot {} {:?}", action, target),
+        }
+        Ok(())
+    }
+
+    pub fn describe(&self) -> String {
+        let list = |prefix: &str, ids: &BTreeSet<String>, suffix: &str| -> String {
+            if ids.is_empty() {
+                return "-".into()
+            }
+            ids.iter().map(|v| format!("{}{}{}", prefix, v, suffix)).collect::<Vec<_>>().join(" ")
+        };
+        let setting = |deny: bool| if deny { "deny" } else { "allow" };
+        let mut lines = vec![
+            format!("allowed users: {}", list("<@", &self.allowed_users, ">")),
+            format!("allowed groups: {}", list("<!subteam^", &self.allowed_usergroups, ">")),
+            format!("blocked users: {}", list("<@", &self.blocked_users, ">")),
+            format!("allowed channels: {}", list("<#", &self.allowed_channels, ">")),
+            format!("blocked channels: {}", list("<#", &self.blocked_channels, ">")),
+            format!("guests: {}", setting(self.deny_guests)),
+            format!("external: {}", setting(self.deny_external)),
+        ];
+        if let (Some(updated_by), Some(updated_at)) = (&self.updated_by, &self.updated_at) {
+            lines.push(format!("updated by <@{}> at {}", updated_by, updated_at.format("%Y-%m-%d %H:%M UTC")));
+        }
+        lines.join("\n")
+    }
+}
+
+fn parse_setting(setting: &str) -> Result<bool> {
+    match setting {
+        "allow" => Ok(false),
+        "deny" => Ok(true),
+        _ => bail!("expected allow or deny"),
+    }
+}
+
+// written by `/yoshino-access`, read before every dispatch
+#[async_trait]
+pub trait AccessPolicyStore: Send + Sync {
+    async fn find(&self, key: &str) -> Result<Option<AccessPolicy>>;
+    async fn save(&self, key: &str, policy: &AccessPolicy) -> Result<()>;
+}
+
+// YOSHINO_ACCESS_POLICY_STORE selects the implementation
+// * `dynamodb` (default) the table YOSHINO_ACCESS_POLICY_TABLE, YOSHINO_ACCESS_POLICY_DYNAMODB_ENDPOINT points at DynamoDB Local
+// * `file` one JSON file per team in YOSHINO_ACCESS_POLICY_DIR, only seen by one execution environment
+pub async fn load_access_policy_store(loader: &mut ConfigLoader) -> Arc<dyn AccessPolicyStore> {
+    match loader.parse("YOSHINO_ACCESS_POLICY_STORE", StoreKind::DynamoDb) {
+        StoreKind::DynamoDb => {
+            let table = loader.optional("YOSHINO_ACCESS_POLICY_TABLE")
+                .unwrap_or_else(|| "yoshino-radio-access-policies".into());
+            let endpoint = loader.optional("YOSHINO_ACCESS_POLICY_DYNAMODB_ENDPOINT");
+            DynamoDbAccessPolicyStore::new(&table, endpoint.as_deref()).await
+        },
+        StoreKind::File => FileAccessPolicyStore::new(PathBuf::from(loader.required("YOSHINO_ACCESS_POLICY_DIR"))),
+    }
+}
+
+pub struct FileAccessPolicyStore {
+    dir: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileAccessPolicyStore {
+    pub fn new(dir: PathBuf) -> Arc<Self> {
+        let this = Self {
+            dir,
+            lock: Mutex::new(()),
+        };
+        Arc::new(this)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        // team IDs are alphanumeric, anything else is dropped
+        let key: String = key.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect();
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+#[async_trait]
+impl AccessPolicyStore for FileAccessPolicyStore {
+    async fn find(&self, key: &str) -> Result<Option<AccessPolicy>> {
+        let _guard = self.lock.lock().await;
+        let path = self.path(key);
+        if !path.exists() {
+            return Ok(None)
+        }
+        let text = fs::read_to_string(&path)?;
+        let policy = serde_json::from_str(&text)
+            .with_context(|| format!("broken access policy file {:?}", path))?;
+        Ok(Some(policy))
+    }
+
+    async fn save(&self, key: &str, policy: &AccessPolicy) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(key), serde_json::to_string_pretty(policy)?)?;
+        Ok(())
+    }
+}
+
+// expects a table with the string partition key `team_id`,
+// the policy is kept as JSON in `policy`
+pub struct DynamoDbAccessPolicyStore {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+}
+
+impl DynamoDbAccessPolicyStore {
+    pub async fn new(table: &str, endpoint: Option<&str>) -> Arc<Self> {
+        let client = cores::dynamodb::client(endpoint).await;
+        let this = Self {
+            client,
+            table: table.into(),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl AccessPolicyStore for DynamoDbAccessPolicyStore {
+    async fn find(&self, key: &str) -> Result<Option<AccessPolicy>> {
+        let output = self.client.get_item()
+            .table_name(&self.table)
+            .key("team_id", AttributeValue::S(key.into()))
+            .consistent_read(true)
+            .send()
+            .await?;
+        let Some(item) = output.item else { return Ok(None) };
+        let Some(AttributeValue::S(text)) = item.get("policy") else {
+            bail!("access policy of {} without the policy attribute", key);
+        };
+        Ok(Some(serde_json::from_str(text)?))
+    }
+
+    async fn save(&self, key: &str, policy: &AccessPolicy) -> Result<()> {
+        self.client.put_item()
+            .table_name(&self.table)
+            .item("team_id", AttributeValue::S(key.into()))
+            .item("policy", AttributeValue::S(serde_json::to_string(policy)?))
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mentions_and_bare_ids() {
+        assert_eq!(parse_target("<@U0123ABCD|hiroshi>"), Some(Target::User("U0123ABCD".into())));
+        assert_eq!(parse_target("<#C0123ABCD|general>"), Some(Target::Channel("C0123ABCD".into())));
+        assert_eq!(parse_target("<!subteam^S0123ABCD|@radio>"), Some(Target::Usergroup("S0123ABCD".into())));
+        assert_eq!(parse_target("W0123ABCD"), Some(Target::User("W0123ABCD".into())));
+        assert_eq!(parse_target("G0123ABCD"), Some(Target::Channel("G0123ABCD".into())));
+        assert_eq!(parse_target("@hiroshi"), None);
+        assert_eq!(parse_target("X0123ABCD"), None);
+    }
+
+    #[test]
+    fn applies_commands() {
+        let mut policy = AccessPolicy::default();
+        assert!(policy.apply_command("allow <@U0123ABCD|hiroshi> <!subteam^S0123ABCD|@radio> C0123ABCD", "U0ADMIN").unwrap());
+        assert!(policy.allowed_users.contains("U0123ABCD"));
+        assert!(policy.allowed_usergroups.contains("S0123ABCD"));
+        assert!(policy.allowed_channels.contains("C0123ABCD"));
+        assert_eq!(policy.updated_by.as_deref(), Some("U0ADMIN"));
+        policy.apply_command("disallow U0123ABCD", "U0ADMIN").unwrap();
+        assert!(policy.allowed_users.is_empty());
+        policy.apply_command("block U0BLOCKED", "U0ADMIN").unwrap();
+        policy.apply_command("guests deny", "U0ADMIN").unwrap();
+        policy.apply_command("external deny", "U0ADMIN").unwrap();
+        assert!(policy.blocked_users.contains("U0BLOCKED") && policy.deny_guests && policy.deny_external);
+        assert!(!policy.apply_command("show", "U0ADMIN").unwrap());
+        assert!(!policy.apply_command("", "U0ADMIN").unwrap());
+        policy.apply_command("reset", "U0ADMIN").unwrap();
+        assert!(policy.allowed_usergroups.is_empty() && policy.blocked_users.is_empty() && !policy.deny_guests);
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        let mut policy = AccessPolicy::default();
+        assert!(policy.apply_command("allow", "U0ADMIN").is_err());
+        assert!(policy.apply_command("allow @hiroshi", "U0ADMIN").is_err());
+        // user groups can be allowed, not blocked
+        assert!(policy.apply_command("block S0123ABCD", "U0ADMIN").is_err());
+        assert!(policy.apply_command("guests maybe", "U0ADMIN").is_err());
+        assert!(policy.apply_command("promote U0123ABCD", "U0ADMIN").is_err());
+        assert!(policy.updated_by.is_none());
+    }
+}
@@ -1,27 +1,186 @@
 
+
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
-use anyhow::bail;
+use anyhow::{Context, Result};
 use aws_config::BehaviorVersion;
 use aws_sdk_lambda::primitives::Blob;
 use aws_sdk_lambda::types::InvocationType;
-use cores::ipc::InvokeMessage;
-use tracing::info;
+use cores::config::ConfigLoader;
+use cores::ipc::{InvokeMessage, IpcClient, IPC_CLIENT_ENDPOINT};
+use cores::metrics;
+use cores::queue::{FileQueue, MessageQueue};
+use tracing::{info, instrument};
 
 use aws_sdk_lambda::Client;
 
-pub struct ChannelClient {}
+use crate::sqs_queue::SqsQueue;
+
+// YOSHINO_DISPATCH selects how messages reach the worker
+// * `lambda` (default) invokes the yoshino-radio-worker function
+// * `ipc` hands off to the worker running as an extension in this execution environment
+// * `queue` enqueues for the worker, see load_queue()
+enum DispatchMode {
+    Lambda,
+    Ipc(Arc<IpcClient>),
+    Queue(Arc<dyn MessageQueue>),
+}
+
+impl DispatchMode {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Lambda => "lambda",
+            Self::Ipc(_) => "ipc",
+            Self::Queue(_) => "queue",
+        }
+    }
+}
+
+// a dispatch is attempted this many times before the message is set aside in the dead-letter queue if any,
+// waiting DISPATCH_RETRY_DELAY times the attempt in between
+const DISPATCH_ATTEMPTS: u32 = 3;
+const DISPATCH_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+pub struct ChannelClient {
+    mode: DispatchMode,
+    dead_letter_queue: Option<Arc<dyn MessageQueue>>,
+}
+
+// YOSHINO_QUEUE selects the queue
+// * `sqs` (default) the queue at YOSHINO_QUEUE_URL, consumed through the worker's event source mapping
+// * `file` a directory at YOSHINO_QUEUE_DIR, consumed by a worker running with YOSHINO_WORKER_MODE=queue
+async fn load_queue(loader: &mut ConfigLoader) -> Option<Arc<dyn MessageQueue>> {
+    let kind = loader.optional("YOSHINO_QUEUE").unwrap_or_else(|| "sqs".into());
+    match kind.as_str() {
+        "sqs" => Some(SqsQueue::new(&loader.required("YOSHINO_QUEUE_URL")).await),
+        "file" => {
+            let dir = loader.optional("YOSHINO_QUEUE_DIR")
+                .unwrap_or_else(|| "/tmp/yoshino-radio-queue".into());
+            file_queue(loader, "YOSHINO_QUEUE_DIR", dir)
+        },
+        _ => {
+            loader.invalid(format!("YOSHINO_QUEUE has an invalid value {:?}: expected sqs or file", kind));
+            None
+        },
+    }
+}
+
+fn file_queue(loader: &mut ConfigLoader, name: &str, dir: String) -> Option<Arc<dyn MessageQueue>> {
+    match FileQueue::new(PathBuf::from(&dir)) {
+        Ok(queue) => Some(queue),
+        Err(error) => {
+            loader.invalid(format!("{} {} could not be opened: {:#}", name, dir, error));
+            None
+        },
+    }
+}
+
+// Messages that could not be dispatched are kept as InvokeMessage JSON, the format of the dispatch queue,
+// so that the worker can consume them once the cause is fixed.
+// YOSHINO_DISPATCH_DEAD_LETTER selects the queue
+// * `sqs` the queue at YOSHINO_DISPATCH_DEAD_LETTER_QUEUE_URL, the default when the URL is set
+// * `file` a directory at YOSHINO_DISPATCH_DEAD_LETTER_DIR, consumed by a worker running with YOSHINO_WORKER_MODE=queue
+// Without either, undispatched messages are only logged and counted.
+async fn load_dead_letter_queue(loader: &mut ConfigLoader) -> Option<Arc<dyn MessageQueue>> {
+    let kind = match loader.optional("YOSHINO_DISPATCH_DEAD_LETTER") {
+        Some(kind) => kind,
+        None if loader.optional("YOSHINO_DISPATCH_DEAD_LETTER_QUEUE_URL").is_some() => "sqs".into(),
+        None => return None,
+    };
+    match kind.as_str() {
+        "sqs" => Some(SqsQueue::new(&loader.required("YOSHINO_DISPATCH_DEAD_LETTER_QUEUE_URL")).await),
+        "file" => {
+            let dir = loader.required("YOSHINO_DISPATCH_DEAD_LETTER_DIR");
+            file_queue(loader, "YOSHINO_DISPATCH_DEAD_LETTER_DIR", dir)
+        },
+        _ => {
+            loader.invalid(format!("YOSHINO_DISPATCH_DEAD_LETTER has an invalid value {:?}: expected sqs or file", kind));
+            None
+        },
+    }
+}
 
 impl ChannelClient {
-    pub fn new() -> Arc<Self> {
+    // None when the configuration is invalid, the loader reports why
+    pub async fn load(loader: &mut ConfigLoader) -> Option<Arc<Self>> {
+        let kind = loader.optional("YOSHINO_DISPATCH").unwrap_or_else(|| "lambda".into());
+        let mode = match kind.as_str() {
+            "lambda" => DispatchMode::Lambda,
+            "ipc" => DispatchMode::Ipc(IpcClient::new(IPC_CLIENT_ENDPOINT)),
+            "queue" => DispatchMode::Queue(load_queue(loader).await?),
+            _ => {
+                loader.invalid(format!("YOSHINO_DISPATCH has an invalid value {:?}: expected lambda, ipc or queue", kind));
+                return None
+            },
+        };
+        let dead_letter_queue = load_dead_letter_queue(loader).await;
         let client = Self {
+            mode,
+            dead_letter_queue,
+        };
+        Some(Arc::new(client))
+    }
+
+    // Slack has been acknowledged by now and does not retry, so a message that cannot be dispatched
+    // is retried here and then dead-lettered. Fails only when the dead-letter queue fails as well.
+    #[instrument(name = "dispatch", skip_all, fields(mode = self.mode.name()))]
+    pub async fn dispatch(self: &Arc<Self>, message: InvokeMessage) -> Result<()> {
+        let mut attempt = 1;
+        let error = loop {
+            let error = match self.invoke(message.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+            if attempt == DISPATCH_ATTEMPTS {
+                break error
+            }
+            info!(error = ?error, attempt, "dispatch failed, retrying");
+            metrics::increment("retries", &[("source", "dispatch")]);
+            tokio::time::sleep(DISPATCH_RETRY_DELAY * attempt).await;
+            attempt += 1;
+        };
+        metrics::increment("dead_letters", &[]);
+        let payload = serde_json::to_string(&message)?;
+        let Some(ref dead_letter_queue) = self.dead_letter_queue else {
+            // the log is the only copy left, redacted like any other line
+            info!(error = ?error, message = payload, "dispatch failed, no dead-letter queue to keep the message");
+            return Ok(())
         };
-        Arc::new(client)
+        info!(error = ?error, "dispatch failed, dead-lettering");
+        dead_letter_queue.send(payload).await
+            .context("failed to dead-letter an undispatched message")
+    }
+
+    async fn invoke(self: &Arc<Self>, message: InvokeMessage) -> Result<()> {
+        match self.mode {
+            DispatchMode::Lambda => self.invoke_lambda(message).await,
+            DispatchMode::Ipc(ref client) => {
+                info!("ipc handoff in progress");
+                client.invoke(message).await?;
+                info!("ipc handoff complete");
+                Ok(())
+            },
+            DispatchMode::Queue(ref queue) => {
+                info!("enqueue in progress");
+                queue.send(serde_json::to_string(&message)?).await?;
+                info!("enqueue complete");
+                Ok(())
+            },
+        }
+    }
+
+    // waits until the dispatched messages are handled when they are handled in this execution environment
+    pub async fn flush(self: &Arc<Self>) -> Result<()> {
+        match self.mode {
+            DispatchMode::Lambda | DispatchMode::Queue(_) => Ok(()),
+            DispatchMode::Ipc(ref client) => client.flush().await,
+        }
     }
 
-    pub async fn invoke(self: &Arc<Self>, message: InvokeMessage) -> Result<()> {
-        let config = aws_config::load_defaults(BehaviorVersion::v2023_11_09()).await;
+    async fn invoke_lambda(self: &Arc<Self>, message: InvokeMessage) -> Result<()> {
+        let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
         info!("invoke in progress");
         let payload = serde_json::to_string(&message)?;
         let client = Client::new(&config);
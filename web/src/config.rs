@@ -0,0 +1,112 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use cores::config::ConfigLoader;
+use cores::installations::{InstallationStore, load_installation_store};
+use cores::secrets::{Secret, SecretsProvider};
+use cores::slack::AdminUsers;
+use tracing::info;
+
+use crate::access_policy::{AccessPolicyStore, load_access_policy_store};
+use crate::channel_client::ChannelClient;
+use crate::dedupe::{DedupeStore, load_dedupe_store};
+
+// the scopes listed in the Slack Setup of the README
+const DEFAULT_BOT_SCOPES: &str = "chat:write,im:history,files:read,files:write,im:write,channels:history,groups:history,channels:read,groups:read,users:read,commands";
+
+// loaded once on startup, see ConfigLoader for where the values come from
+pub struct Config {
+    signing_secret: String,
+    // accepted alongside the current one while a rotation is rolled out
+    signing_secret_previous: Option<String>,
+    secrets_provider: Option<Arc<dyn SecretsProvider>>,
+    // acts in a workspace that was not installed through OAuth
+    pub slack_bot_token: Option<Secret>,
+    // may change the access policy with /yoshino-access
+    pub admin_users: AdminUsers,
+    // present when SLACK_CLIENT_ID is set
+    pub oauth: Option<OAuthConfig>,
+    pub installation_store: Arc<dyn InstallationStore>,
+    pub channel_client: Arc<ChannelClient>,
+    pub dedupe_store: Arc<dyn DedupeStore>,
+    pub access_policy_store: Arc<dyn AccessPolicyStore>,
+    // how long access policies and user group members are cached
+    pub access_cache_ttl: Duration,
+}
+
+// https://api.slack.com/authentication/oauth-v2
+pub struct OAuthConfig {
+    pub client_id: String,
+    pub client_secret: Secret,
+    // required when the app has several redirect URLs
+    pub redirect_uri: Option<String>,
+    pub scopes: String,
+    pub user_scopes: String,
+}
+
+impl Config {
+    pub async fn load() -> Result<Arc<Self>> {
+        let mut loader = ConfigLoader::from_env().await?;
+        let signing_secret = loader.required_secret("SLACK_SIGNING_SECRET").await;
+        let signing_secret_previous = loader.optional_secret("SLACK_SIGNING_SECRET_PREVIOUS").await;
+        let slack_bot_token = loader.optional_refreshed_secret("SLACK_BOT_TOKEN").await;
+        let admin_users = AdminUsers::load(&mut loader);
+        let oauth = match loader.optional("SLACK_CLIENT_ID") {
+            Some(client_id) => Some(OAuthConfig {
+                client_id,
+                client_secret: loader.required_refreshed_secret("SLACK_CLIENT_SECRET").await,
+                redirect_uri: loader.optional("SLACK_REDIRECT_URI"),
+                scopes: loader.optional("YOSHINO_SLACK_SCOPES").unwrap_or_else(|| DEFAULT_BOT_SCOPES.into()),
+                user_scopes: loader.optional("YOSHINO_SLACK_USER_SCOPES").unwrap_or_default(),
+            }),
+            None => None,
+        };
+        let installation_store = load_installation_store(&mut loader).await;
+        let channel_client = ChannelClient::load(&mut loader).await;
+        let dedupe_store = load_dedupe_store(&mut loader).await;
+        let access_policy_store = load_access_policy_store(&mut loader).await;
+        let access_cache_ttl = Duration::from_secs(loader.parse("YOSHINO_ACCESS_CACHE_TTL", 60));
+        let secrets_provider = loader.secrets_provider().cloned();
+        loader.finish()?;
+        // only missing when the loader has reported why
+        let channel_client = channel_client.context("no channel client")?;
+        let this = Self {
+            signing_secret,
+            signing_secret_previous,
+            secrets_provider,
+            slack_bot_token,
+            admin_users,
+            oauth,
+            installation_store,
+            channel_client,
+            dedupe_store,
+            access_policy_store,
+            access_cache_ttl,
+        };
+        Ok(Arc::new(this))
+    }
+
+    // re-read through the secrets provider, its cache expires so that a rotation is picked up without a restart,
+    // the secrets loaded on startup are used when the provider fails
+    pub async fn signing_secrets(&self) -> Vec<String> {
+        let mut current = self.signing_secret.clone();
+        let mut previous = self.signing_secret_previous.clone();
+        if let Some(ref secrets_provider) = self.secrets_provider {
+            let result = async {
+                let current = secrets_provider.get("SLACK_SIGNING_SECRET").await?;
+                let previous = secrets_provider.get("SLACK_SIGNING_SECRET_PREVIOUS").await?;
+                anyhow::Ok((current, previous))
+            };
+            match result.await {
+                Ok((Some(provided), provided_previous)) => {
+                    current = provided;
+                    previous = provided_previous;
+                },
+                Ok((None, _)) => {},
+                Err(error) => info!(error = ?error, "failed to refresh the signing secrets"),
+            }
+        }
+        [Some(current), previous].into_iter().flatten().collect()
+    }
+}
@@ -0,0 +1,184 @@
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use aws_sdk_dynamodb::types::AttributeValue;
+use cores::config::ConfigLoader;
+use rusqlite::{params, Connection};
+
+// Slack retries for up to about an hour
+// https://api.slack.com/apis/connections/events-api#retries
+pub const DEDUPE_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+
+// idempotency of event deliveries
+#[async_trait]
+pub trait DedupeStore: Send + Sync {
+    // true when the key is seen for the first time within the ttl
+    async fn claim(&self, key: &str, ttl: Duration) -> Result<bool>;
+    // gives the key back so that a retry of a failed delivery is processed
+    async fn release(&self, key: &str) -> Result<()>;
+}
+
+// YOSHINO_DEDUPE_STORE selects the implementation
+// * `dynamodb` (default) the table YOSHINO_DEDUPE_TABLE, YOSHINO_DEDUPE_DYNAMODB_ENDPOINT points at DynamoDB Local
+// * `sqlite` a database file at YOSHINO_DEDUPE_SQLITE_PATH
+// * `memory` only survives within a warm execution environment, a retry reaching another one is handled twice
+pub async fn load_dedupe_store(loader: &mut ConfigLoader) -> Arc<dyn DedupeStore> {
+    let kind = loader.optional("YOSHINO_DEDUPE_STORE").unwrap_or_else(|| "dynamodb".into());
+    match kind.as_str() {
+        "memory" => MemoryDedupeStore::new(),
+        "sqlite" => {
+            let path = loader.optional("YOSHINO_DEDUPE_SQLITE_PATH")
+                .unwrap_or_else(|| "/tmp/yoshino-radio-dedupe.sqlite3".into());
+            match SqliteDedupeStore::open(&path) {
+                Ok(store) => store,
+                Err(error) => {
+                    loader.invalid(format!("YOSHINO_DEDUPE_SQLITE_PATH {} could not be opened: {:#}", path, error));
+                    MemoryDedupeStore::new()
+                },
+            }
+        },
+        "dynamodb" => {
+            let table = loader.optional("YOSHINO_DEDUPE_TABLE")
+                .unwrap_or_else(|| "yoshino-radio-dedupe".into());
+            let endpoint = loader.optional("YOSHINO_DEDUPE_DYNAMODB_ENDPOINT");
+            DynamoDbDedupeStore::new(&table, endpoint.as_deref()).await
+        },
+        _ => {
+            loader.invalid(format!("YOSHINO_DEDUPE_STORE has an invalid value {:?}: expected dynamodb, sqlite or memory", kind));
+            MemoryDedupeStore::new()
+        },
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or_default()
+}
+
+pub struct MemoryDedupeStore {
+    keys: Mutex<HashMap<String, Instant>>,
+}
+
+impl MemoryDedupeStore {
+    pub fn new() -> Arc<Self> {
+        let this = Self {
+            keys: Mutex::new(HashMap::new()),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl DedupeStore for MemoryDedupeStore {
+    async fn claim(&self, key: &str, ttl: Duration) -> Result<bool> {
+        let now = Instant::now();
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|_, expires_at| *expires_at > now);
+        if keys.contains_key(key) {
+            return Ok(false)
+        }
+        keys.insert(key.into(), now + ttl);
+        Ok(true)
+    }
+
+    async fn release(&self, key: &str) -> Result<()> {
+        self.keys.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+pub struct SqliteDedupeStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteDedupeStore {
+    pub fn open(path: &str) -> Result<Arc<Self>> {
+        let connection = Connection::open(path)?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS dedupe (key TEXT PRIMARY KEY, expires_at INTEGER NOT NULL)",
+            [])?;
+        let this = Self {
+            connection: Mutex::new(connection),
+        };
+        Ok(Arc::new(this))
+    }
+}
+
+#[async_trait]
+impl DedupeStore for SqliteDedupeStore {
+    async fn claim(&self, key: &str, ttl: Duration) -> Result<bool> {
+        let now = unix_now();
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM dedupe WHERE expires_at <= ?1", params![now])?;
+        let inserted = connection.execute(
+            "INSERT OR IGNORE INTO dedupe (key, expires_at) VALUES (?1, ?2)",
+            params![key, now + ttl.as_secs()])?;
+        Ok(inserted == 1)
+    }
+
+    async fn release(&self, key: &str) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM dedupe WHERE key = ?1", params![key])?;
+        Ok(())
+    }
+}
+
+// expects a table with the string partition key `dedupe_key`,
+// enable TTL on `expires_at` to let DynamoDB sweep old keys
+pub struct DynamoDbDedupeStore {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+}
+
+impl DynamoDbDedupeStore {
+    pub async fn new(table: &str, endpoint: Option<&str>) -> Arc<Self> {
+        let client = cores::dynamodb::client(endpoint).await;
+        let this = Self {
+            client,
+            table: table.into(),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl DedupeStore for DynamoDbDedupeStore {
+    async fn claim(&self, key: &str, ttl: Duration) -> Result<bool> {
+        let now = unix_now();
+        let result = self.client.put_item()
+            .table_name(&self.table)
+            .item("dedupe_key", AttributeValue::S(key.into()))
+            .item("expires_at", AttributeValue::N((now + ttl.as_secs()).to_string()))
+            // expired items may linger until the TTL sweep
+            .condition_expression("attribute_not_exists(dedupe_key) OR expires_at <= :now")
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(error) => {
+                let error = error.into_service_error();
+                if error.is_conditional_check_failed_exception() {
+                    Ok(false)
+                } else {
+                    Err(error.into())
+                }
+            }
+        }
+    }
+
+    async fn release(&self, key: &str) -> Result<()> {
+        self.client.delete_item()
+            .table_name(&self.table)
+            .key("dedupe_key", AttributeValue::S(key.into()))
+            .send()
+            .await?;
+        Ok(())
+    }
+}
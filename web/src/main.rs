@@ -1,25 +1,67 @@
 use std::sync::Arc;
 
+use cores::ipc::new_trace_id;
+use cores::metrics::init_metrics;
+use cores::telemetry::init_tracing;
 use lambda_http::{run, service_fn, Body, Error, Request, RequestExt, Response, http::Method};
+use lambda_http::tower::{Layer, Service};
 use runtime_app::RuntimeApp;
+use tracing::{info_span, Instrument};
 
 mod runtime_app;
 mod runtime_context;
+mod config;
 mod channel_client;
 mod slack_requests;
 mod slack_events;
+mod slack_commands;
+mod slack_oauth;
 mod slack_messages;
 mod slack_verification;
+mod slack_client;
+mod access_policy;
+mod access_control;
+mod dedupe;
+mod sqs_queue;
 
 use runtime_context::RuntimeContext;
+use config::Config;
 use slack_requests::SlackRequestHandler;
+use slack_oauth::SlackOAuthHandler;
+use slack_verification::SlackVerificationLayer;
+
+// only handled once the request is verified to come from Slack
+const SLACK_SIGNED_PATHS: &[&str] = &["/slack/events", "/slack/commands"];
+
+// carried to the worker in the InvokeMessage, set on the request before it is verified
+#[derive(Clone)]
+struct TraceId(String);
 
 // https://github.com/awslabs/aws-lambda-rust-runtime/tree/main/examples
 async fn function_handler(event: Request, context: &Arc<RuntimeContext>) -> Result<Response<Body>, Error> {
+    let trace_id = event.extensions().get::<TraceId>()
+        .map(|v| v.0.clone())
+        .unwrap_or_else(new_trace_id);
+    route(event, context, trace_id).await
+}
+
+async fn route(event: Request, context: &Arc<RuntimeContext>, trace_id: String) -> Result<Response<Body>, Error> {
     match (event.method(), event.raw_http_path()) {
         (&Method::POST, "/slack/events") => {
             let request_handler = SlackRequestHandler::new(context);
-            request_handler.handle_slack_request(event).await
+            request_handler.handle_slack_request(event, trace_id).await
+        },
+        (&Method::POST, "/slack/commands") => {
+            let request_handler = SlackRequestHandler::new(context);
+            request_handler.handle_slack_command_request(event, trace_id).await
+        },
+        (&Method::GET, "/slack/install") => {
+            let oauth_handler = SlackOAuthHandler::new(context);
+            oauth_handler.handle_install_request(event).await
+        },
+        (&Method::GET, "/slack/oauth_redirect") => {
+            let oauth_handler = SlackOAuthHandler::new(context);
+            oauth_handler.handle_oauth_redirect_request(event).await
         },
         (&Method::GET, "/") => {
             handle_get_root(event).await
@@ -50,18 +92,33 @@ async fn handle_not_found(_event: Request) -> Result<Response<Body>, Error> {
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        // disable printing the name of the module in every log line.
-        .with_target(false)
-        // disabling time is handy because CloudWatch will add the ingestion time.
-        .without_time()
-        .init();
-    let runtime_context = RuntimeContext::new();
+    init_tracing("yoshino-radio")?;
+    init_metrics("yoshino-radio");
+    // fails fast on a missing or invalid setting instead of on the first message
+    let config = Config::load().await?;
+    let runtime_context = RuntimeContext::new(config);
     let runtime_app = RuntimeApp::new(&runtime_context);
     runtime_app.launch().await?;
-    let func = |event| async {
-        function_handler(event, &runtime_context).await
+    let handler_context = Arc::clone(&runtime_context);
+    let service = SlackVerificationLayer::new(runtime_context.config(), SLACK_SIGNED_PATHS)
+        .layer(service_fn(move |event| {
+            let context = Arc::clone(&handler_context);
+            async move { function_handler(event, &context).await }
+        }));
+    let func = |mut event: Request| {
+        let mut service = service.clone();
+        let runtime_app = Arc::clone(&runtime_app);
+        async move {
+            // the span covers the verification as well, so that its logs carry the trace ID
+            let trace_id = new_trace_id();
+            let span = info_span!("request", trace_id, method = %event.method(), path = event.raw_http_path());
+            event.extensions_mut().insert(TraceId(trace_id));
+            // rejected requests finish the invocation too,
+            // poll_ready is skipped as service_fn is always ready
+            let response = service.call(event).instrument(span).await;
+            runtime_app.finish_invocation();
+            response
+        }
     };
     run(service_fn(func)).await
 }
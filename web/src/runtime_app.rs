@@ -1,23 +1,110 @@
 
+
+use std::env;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use crate::runtime_context::RuntimeContext;
 
 use anyhow::Result;
+use cores::metrics::flush_metrics;
+use cores::telemetry::flush_tracing;
+use lambda_extension::{service_fn, Extension, LambdaEvent, NextEvent};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::timeout;
+use tracing::info;
+
+// Lambda sends SHUTDOWN about 2 seconds before terminating the execution environment
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_millis(1500);
 
+// Keeps the execution environment alive while background dispatches are in flight.
+// An internal extension is not frozen until it asks for the next event,
+// so it holds off the INVOKE event until the handler reports that its tasks are done.
+// https://docs.aws.amazon.com/lambda/latest/dg/runtimes-extensions-api.html
 pub struct RuntimeApp {
     runtime_context: Arc<RuntimeContext>,
+    invocation_sender: mpsc::UnboundedSender<()>,
+    invocation_receiver: Mutex<mpsc::UnboundedReceiver<()>>,
+    // the extension only runs on Lambda, nothing receives invocations otherwise
+    launched: AtomicBool,
 }
 
 impl RuntimeApp {
     pub fn new(runtime_context: &Arc<RuntimeContext>) -> Arc<Self> {
         let runtime_context = Arc::clone(runtime_context);
+        let (invocation_sender, invocation_receiver) = mpsc::unbounded_channel();
         let handler = Self {
             runtime_context,
+            invocation_sender,
+            invocation_receiver: Mutex::new(invocation_receiver),
+            launched: AtomicBool::new(false),
         };
         Arc::new(handler)
     }
 
     pub async fn launch(self: &Arc<Self>) -> Result<()> {
+        // not running on Lambda, nothing gets frozen
+        if env::var("AWS_LAMBDA_RUNTIME_API").is_err() {
+            return Ok(())
+        }
+        let this = Arc::clone(self);
+        let events_processor = service_fn(move |event: LambdaEvent| {
+            let this = Arc::clone(&this);
+            async move {
+                this.process_extension_event(event).await
+                    .map_err(lambda_extension::Error::from)
+            }
+        });
+        // internal extensions have to register before the runtime starts polling
+        let extension = Extension::new()
+            .with_events(&["INVOKE", "SHUTDOWN"])
+            .with_events_processor(events_processor)
+            .with_extension_name("yoshino-radio-background")
+            .register()
+            .await
+            .map_err(|error| anyhow::anyhow!("extension registration failed {:?}", error))?;
+        tokio::spawn(async move {
+            if let Err(error) = extension.run().await {
+                info!("extension terminated {:?}", error);
+            }
+        });
+        self.launched.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    // called once the handler returned its response
+    pub fn finish_invocation(self: &Arc<Self>) {
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            // the tasks this invocation spawned, a later invocation's tasks do not hold it up
+            this.runtime_context.task_tracker().flush().await;
+            // the extension worker handles what was handed off before we let the environment freeze
+            if let Err(error) = this.runtime_context.channel_client().flush().await {
+                info!("channel flush failed {:?}", error);
+            }
+            flush_metrics();
+            flush_tracing().await;
+            if this.launched.load(Ordering::Acquire) {
+                let _ = this.invocation_sender.send(());
+            }
+        });
+    }
+
+    async fn process_extension_event(&self, event: LambdaEvent) -> Result<()> {
+        match event.next {
+            NextEvent::Invoke(_) => {
+                let mut receiver = self.invocation_receiver.lock().await;
+                receiver.recv().await;
+            },
+            NextEvent::Shutdown(_) => {
+                let task_tracker = self.runtime_context.task_tracker();
+                task_tracker.close();
+                info!("draining {} background tasks", task_tracker.len());
+                if timeout(SHUTDOWN_DRAIN_TIMEOUT, task_tracker.wait()).await.is_err() {
+                    info!("shutdown drain timed out with {} tasks", task_tracker.len());
+                }
+            },
+        }
         Ok(())
     }
 }
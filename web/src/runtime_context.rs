@@ -1,29 +1,56 @@
 
 use std::sync::Arc;
-use tokio_util::task::TaskTracker;
+use cores::installations::InstallationStore;
+use cores::tasks::FlushTracker;
 
+use crate::access_control::AccessCache;
+use crate::access_policy::AccessPolicyStore;
 use crate::channel_client::ChannelClient;
+use crate::config::Config;
+use crate::dedupe::DedupeStore;
 
 pub struct RuntimeContext {
-    task_tracker: TaskTracker,
-    channel_client: Arc<ChannelClient>,
+    config: Arc<Config>,
+    task_tracker: FlushTracker,
+    access_cache: Arc<AccessCache>,
 }
 
 impl RuntimeContext {
-    pub fn new() -> Arc<Self> {
-        let channel_client = ChannelClient::new();
+    pub fn new(config: Arc<Config>) -> Arc<Self> {
+        let access_cache = AccessCache::new(config.access_cache_ttl);
         let context = Self {
-            task_tracker: TaskTracker::new(),
-            channel_client,
+            config,
+            task_tracker: FlushTracker::new(),
+            access_cache,
         };
         Arc::new(context)
     }
 
-    pub fn task_tracker(&self) -> &TaskTracker {
+    pub fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    pub fn task_tracker(&self) -> &FlushTracker {
         &self.task_tracker
     }
 
     pub fn channel_client(&self) -> &Arc<ChannelClient> {
-        &self.channel_client
+        &self.config.channel_client
+    }
+
+    pub fn dedupe_store(&self) -> &Arc<dyn DedupeStore> {
+        &self.config.dedupe_store
+    }
+
+    pub fn installation_store(&self) -> &Arc<dyn InstallationStore> {
+        &self.config.installation_store
+    }
+
+    pub fn access_policy_store(&self) -> &Arc<dyn AccessPolicyStore> {
+        &self.config.access_policy_store
+    }
+
+    pub fn access_cache(&self) -> &Arc<AccessCache> {
+        &self.access_cache
     }
 }
@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use cores::slack::{self, UserInfo, UserInfoResponseBody};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::config::Config;
+
+#[derive(Deserialize)]
+struct UsergroupUsersResponseBody {
+    users: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PostEphemeralRequestBody {
+    channel: String,
+    user: String,
+    text: String,
+}
+
+// the few Slack API methods the web runtime calls itself, everything else is left to the worker
+pub struct SlackClient {
+    client: Client,
+    bot_token: String,
+}
+
+impl SlackClient {
+    pub fn new(bot_token: String) -> Arc<Self> {
+        let this = Self {
+            client: reqwest::Client::new(),
+            bot_token,
+        };
+        Arc::new(this)
+    }
+
+    // the bot token of the workspace's installation, or SLACK_BOT_TOKEN
+    pub async fn for_team(config: &Config, team_id: Option<&str>) -> Result<Arc<Self>> {
+        let installation = match team_id {
+            Some(team_id) => config.installation_store.find(team_id).await?,
+            None => None,
+        };
+        let bot_token = match installation {
+            Some(installation) => installation.bot_token,
+            None => config.slack_bot_token.as_ref()
+                .with_context(|| format!("no installation for team {:?}", team_id))?
+                .get().await,
+        };
+        Ok(Self::new(bot_token))
+    }
+
+    // https://api.slack.com/methods/users.info
+    pub async fn user_info(&self, user: &str) -> Result<UserInfo> {
+        let request = self.client.get("https://slack.com/api/users.info")
+            .header("Authorization", ["Bearer", &self.bot_token].join(" "))
+            .query(&[("user", user)]);
+        let response = slack::send("users.info", request).await?;
+        let text = response.text().await?;
+        debug!(response = text, "slack users.info");
+        slack::check_status(&text)?;
+        let response: UserInfoResponseBody = serde_json::from_str(&text)?;
+        Ok(response.user)
+    }
+
+    // https://api.slack.com/methods/usergroups.users.list
+    pub async fn usergroup_users(&self, usergroup: &str) -> Result<Vec<String>> {
+        let request = self.client.get("https://slack.com/api/usergroups.users.list")
+            .header("Authorization", ["Bearer", &self.bot_token].join(" "))
+            .query(&[("usergroup", usergroup)]);
+        let response = slack::send("usergroups.users.list", request).await?;
+        let text = response.text().await?;
+        debug!(response = text, "slack usergroups.users.list");
+        slack::check_status(&text)?;
+        let response: UsergroupUsersResponseBody = serde_json::from_str(&text)?;
+        Ok(response.users)
+    }
+
+    // https://api.slack.com/methods/chat.postEphemeral
+    pub async fn post_ephemeral(&self, channel: &str, user: &str, text: &str) -> Result<()> {
+        let request_body = PostEphemeralRequestBody {
+            channel: channel.into(),
+            user: user.into(),
+            text: text.into(),
+        };
+        let request = self.client.post("https://slack.com/api/chat.postEphemeral")
+            .header("Content-type", "application/json; charset=utf-8")
+            .header("Authorization", ["Bearer", &self.bot_token].join(" "))
+            .json(&request_body);
+        let response = slack::send("chat.postEphemeral", request).await?;
+        let text = response.text().await?;
+        debug!(response = text, "slack chat.postEphemeral");
+        slack::check_status(&text)
+    }
+}
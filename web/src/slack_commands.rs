@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use cores::ipc::{InvokeMessage, InvokePayload};
+use lambda_http::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use anyhow::Result;
+use cores::metrics;
+use tracing::{info, Instrument};
+
+use crate::access_control::AccessControl;
+use crate::access_policy::{ACCESS_COMMAND_USAGE, policy_key};
+use crate::runtime_context::RuntimeContext;
+use crate::slack_verification::body_text;
+
+// https://api.slack.com/interactivity/slash-commands#app_command_handling
+#[derive(Deserialize, Debug)]
+struct SlashCommandBody {
+    team_id: Option<String>,
+    command: String,
+    #[serde(default)]
+    text: String,
+    user_id: String,
+    channel_id: String,
+    response_url: String,
+}
+
+// https://api.slack.com/interactivity/slash-commands#responding_immediate_response
+#[derive(Serialize)]
+struct ImmediateResponseBody {
+    response_type: String,
+    text: String,
+}
+
+pub struct SlackCommandHandler {
+    runtime_context: Arc<RuntimeContext>,
+}
+
+impl SlackCommandHandler {
+    pub fn new(runtime_context: &Arc<RuntimeContext>) -> Arc<Self> {
+        let runtime_context = Arc::clone(runtime_context);
+        let handler = Self {
+            runtime_context,
+        };
+        Arc::new(handler)
+    }
+
+    // the worker answers through response_url, the request is acknowledged right away
+    pub async fn handle_verified_command(&self, event: Request, trace_id: String) -> Result<Response<Body>> {
+        let body = body_text(&event)?;
+        let body: SlashCommandBody = serde_urlencoded::from_str(body)?;
+        info!(command = body.command, user = body.user_id, "slash command");
+        if body.command == "/yoshino-access" {
+            let text = self.access_command(&body).await?;
+            return self.ephemeral_response(text)
+        }
+        let access_control = AccessControl::new(&self.runtime_context);
+        if let Some(denial) = access_control.check(body.team_id.as_deref(), &body.user_id, &body.channel_id).await {
+            access_control.record_denied(&body.user_id, &body.channel_id, denial);
+            return self.ephemeral_response(denial.text().into())
+        }
+        let payload = InvokePayload::SlashCommand {
+            command: body.command,
+            text: body.text,
+            user_id: body.user_id,
+            channel_id: body.channel_id,
+            response_url: body.response_url,
+        };
+        let message = InvokeMessage::new(trace_id, body.team_id, payload);
+        let channel_client = Arc::clone(self.runtime_context.channel_client());
+        self.runtime_context.task_tracker().spawn(async move {
+            if let Err(error) = channel_client.dispatch(message).await {
+                info!(error = ?error, "slash command dispatch failed");
+                metrics::increment("errors", &[("type", "dispatch")]);
+            }
+        }.in_current_span());
+        // an empty 200 shows nothing to the user until the worker responds
+        let response = Response::builder()
+            .status(200)
+            .body(Body::Empty)
+            .map_err(Box::new)?;
+        Ok(response)
+    }
+
+    // `/yoshino-access` shows or changes the workspace's access policy, admins only
+    async fn access_command(&self, body: &SlashCommandBody) -> Result<String> {
+        if !self.runtime_context.config().admin_users.contains(&body.user_id) {
+            return Ok("そちらは管理のお役目の方にのみ、お任せできるものでしてー".into())
+        }
+        let store = self.runtime_context.access_policy_store();
+        let key = policy_key(body.team_id.as_deref());
+        let mut policy = store.find(key).await?.unwrap_or_default();
+        match policy.apply_command(&body.text, &body.user_id) {
+            Ok(false) => Ok(policy.describe()),
+            Ok(true) => {
+                store.save(key, &policy).await?;
+                self.runtime_context.access_cache().forget_policy(key);
+                info!(user = body.user_id, text = body.text, "access policy updated");
+                Ok(format!("承りましたー\n{}", policy.describe()))
+            },
+            Err(error) => Ok(format!("{}\n{}", error, ACCESS_COMMAND_USAGE)),
+        }
+    }
+
+    // only the user who typed the command sees it
+    fn ephemeral_response(&self, text: String) -> Result<Response<Body>> {
+        let body = ImmediateResponseBody {
+            response_type: "ephemeral".into(),
+            text,
+        };
+        let response = Response::builder()
+            .status(200)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&body)?.into())
+            .map_err(Box::new)?;
+        Ok(response)
+    }
+}
@@ -5,9 +5,15 @@ use lambda_http::{Body, Request, Response};
 use serde_json;
 
 use serde::Deserialize;
-use anyhow::{Result, bail};
+use anyhow::Result;
+
+use cores::events::EventCallback;
+use cores::metrics;
+use tracing::{info, Instrument};
 
 use crate::{slack_messages::SlackEventMessageHandler, runtime_context::RuntimeContext};
+use crate::dedupe::DEDUPE_TTL;
+use crate::slack_verification::body_text;
 
 // https://api.slack.com/apis/connections/events-api#handshake
 #[derive(Deserialize, Debug)]
@@ -22,26 +28,27 @@ struct Handshake {
 }
 
 pub struct SlackEventHandler {
+    runtime_context: Arc<RuntimeContext>,
     message_handler: Arc<SlackEventMessageHandler>,
 }
 
 impl SlackEventHandler {
     pub fn new(runtime_context: &Arc<RuntimeContext>) -> Arc<Self> {
         let message_handler = SlackEventMessageHandler::new(runtime_context);
+        let runtime_context = Arc::clone(runtime_context);
         let handler = Self {
+            runtime_context,
             message_handler,
         };
         Arc::new(handler)
     }
 
-    pub async fn handle_verified_events(&self, event: Request) -> Result<Response<Body>> {
-        let Body::Text(body) = event.body() else {
-            bail!("no body");
-        };
+    pub async fn handle_verified_events(&self, event: Request, trace_id: String) -> Result<Response<Body>> {
+        let body = body_text(&event)?;
         let content: TopLevelContent = serde_json::from_str(body)?;
         match content.r#type.as_str() {
             "url_verification" => self.url_verification(event),
-            "event_callback" => self.event_callback(event, content.r#type).await,
+            "event_callback" => self.event_callback(event, trace_id).await,
             _ => {
                 let response = Response::builder()
                     .status(403)
@@ -54,9 +61,7 @@ impl SlackEventHandler {
     }
 
     fn url_verification(&self, event: Request) -> Result<Response<Body>> {
-        let Body::Text(body) = event.body() else {
-            bail!("no body");
-        };
+        let body = body_text(&event)?;
         let handshake: Handshake = serde_json::from_str(body)?;
         let response = Response::builder()
             .status(200)
@@ -67,11 +72,44 @@ impl SlackEventHandler {
     }
 
     // https://api.slack.com/apis/connections/events-api#responding
-    async fn event_callback(&self, event: Request, event_type: String) -> Result<Response<Body>> {
-        let Body::Text(body) = event.body() else {
-            bail!("no body");
-        };
-        self.message_handler.process_event_callback(event_type, body.clone()).await?;
+    async fn event_callback(&self, event: Request, trace_id: String) -> Result<Response<Body>> {
+        let body = body_text(&event)?;
+        let callback: EventCallback = serde_json::from_str(body)?;
+        // https://api.slack.com/apis/connections/events-api#retries
+        let retry_num = event.headers().get("X-Slack-Retry-Num")
+            .and_then(|v| v.to_str().ok());
+        let retry_reason = event.headers().get("X-Slack-Retry-Reason")
+            .and_then(|v| v.to_str().ok());
+        if let Some(retry_reason) = retry_reason {
+            metrics::increment("retries", &[("source", "slack"), ("reason", retry_reason)]);
+        }
+        let dedupe_key = callback.dedupe_key();
+        let dedupe_store = self.runtime_context.dedupe_store();
+        if let Some(ref key) = dedupe_key {
+            if !dedupe_store.claim(key, DEDUPE_TTL).await? {
+                info!(key, retry_num, retry_reason, "duplicate delivery acknowledged");
+                metrics::increment("dedupes", &[]);
+                return self.ok_response();
+            }
+        }
+        // acknowledge within 3 seconds and dispatch in the background
+        // https://api.slack.com/apis/connections/events-api#responding
+        let message_handler = Arc::clone(&self.message_handler);
+        let dedupe_store = Arc::clone(dedupe_store);
+        self.runtime_context.task_tracker().spawn(async move {
+            let result = message_handler.process_event_callback(callback, trace_id).await;
+            if let Err(error) = result {
+                info!(error = ?error, "event dispatch failed");
+                metrics::increment("errors", &[("type", "dispatch")]);
+                if let Some(ref key) = dedupe_key {
+                    let _ = dedupe_store.release(key).await;
+                }
+            }
+        }.in_current_span());
+        self.ok_response()
+    }
+
+    fn ok_response(&self) -> Result<Response<Body>> {
         // respond to events with a HTTP 200 OK as soon as we can
         let response = Response::builder()
             .status(200)
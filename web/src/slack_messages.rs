@@ -1,24 +1,14 @@
 
 use std::sync::Arc;
-use cores::ipc::InvokeMessage;
+use cores::events::{EventCallback, RevokedTokens};
+use cores::ipc::{InvokeMessage, InvokePayload};
 
 use anyhow::Result;
-use serde::Deserialize;
+use tracing::info;
 
+use crate::access_control::AccessControl;
 use crate::runtime_context::RuntimeContext;
 
-#[derive(Deserialize)]
-struct AnyEventCallbackBody {
-    event: AnyEvent,
-}
-
-#[derive(Deserialize)]
-struct AnyEvent {
-    r#type: String,
-    subtype: Option<String>,
-    bot_id: Option<String>,
-}
-
 pub struct SlackEventMessageHandler {
     runtime_context: Arc<RuntimeContext>,
 }
@@ -32,38 +22,94 @@ impl SlackEventMessageHandler {
         Arc::new(handler)
     }
 
-    pub async fn process_event_callback(self: &Arc<Self>, event_type: String, body: String) -> Result<()> {
-        match event_type.as_str() {
-            "event_callback" => self.handle_slack_event_callback(event_type, body).await,
-            _ => Ok(())
-        }
-    }
-
-    async fn handle_slack_event_callback(&self, event_type: String, body: String) -> Result<()> {
-        let any_event: AnyEventCallbackBody = serde_json::from_str(&body)?;
-        let r#type = any_event.event.r#type.as_str();
-        // ignore message updates
-        let subtype = any_event.event.subtype
-            .as_ref()
-            .map(|v| v.as_str());
+    pub async fn process_event_callback(self: &Arc<Self>, callback: EventCallback, trace_id: String) -> Result<()> {
+        let event = &callback.event;
+        let r#type = event.r#type.as_str();
+        let subtype = event.subtype.as_deref();
         // ignore bot's messages
-        let bot_id = any_event.event.bot_id;
-        if bot_id.is_some() {
+        if event.bot_id.is_some() {
+            return Ok(())
+        }
+        // ignore bot's updates of its own replies, they arrive for every streamed chunk
+        if event.message.as_ref().is_some_and(|v| v.bot_id.is_some() || v.subtype.as_deref() == Some("bot_message")) {
             return Ok(())
         }
+        // link unfurls and thread replies change the message without changing the text
+        if let (Some(message), Some(previous_message)) = (&event.message, &event.previous_message) {
+            if message.text == previous_message.text {
+                return Ok(())
+            }
+        }
+        let team_id = callback.team_id.as_deref();
+        let channel = event.channel.as_deref();
         match (r#type, subtype) {
-            ("message", None) | ("message", Some("file_share")) => self.handle_slack_message(event_type, body).await,
+            ("message", None) | ("message", Some("file_share")) => {
+                if !self.permitted(team_id, event.user.as_deref(), channel).await? {
+                    return Ok(())
+                }
+                self.handle_slack_message(trace_id, callback).await
+            },
+            // an edit may be answered again
+            ("message", Some("message_changed")) => {
+                let user = event.message.as_ref().and_then(|v| v.user.as_deref());
+                if !self.permitted(team_id, user, channel).await? {
+                    return Ok(())
+                }
+                self.handle_slack_message(trace_id, callback).await
+            },
+            // lets the worker stop a reply in flight
+            ("message", Some("message_deleted")) => self.handle_slack_message(trace_id, callback).await,
+            // https://api.slack.com/events/app_uninstalled
+            ("app_uninstalled", _) => self.handle_app_uninstalled(team_id).await,
+            ("tokens_revoked", _) => self.handle_tokens_revoked(team_id, event.tokens.as_deref()).await,
             _ => Ok(()),
         }
     }
 
-    async fn handle_slack_message(&self, event_type: String, body: String) -> Result<()>  {
+    async fn handle_app_uninstalled(&self, team_id: Option<&str>) -> Result<()> {
+        let Some(team_id) = team_id else { return Ok(()) };
+        self.runtime_context.installation_store().delete(team_id).await?;
+        info!(team = team_id, "uninstalled");
+        Ok(())
+    }
+
+    // a revoked bot token ends the installation, a revoked user token only drops the user token
+    // https://api.slack.com/events/tokens_revoked
+    async fn handle_tokens_revoked(&self, team_id: Option<&str>, tokens: Option<&RevokedTokens>) -> Result<()> {
+        let (Some(team_id), Some(tokens)) = (team_id, tokens) else { return Ok(()) };
+        let installation_store = self.runtime_context.installation_store();
+        let Some(mut installation) = installation_store.find(team_id).await? else { return Ok(()) };
+        let bot_revoked = !tokens.bot.is_empty() && installation.bot_user_id.as_ref()
+            .is_none_or(|v| tokens.bot.contains(v));
+        if bot_revoked {
+            installation_store.delete(team_id).await?;
+            info!(team = team_id, "bot token revoked");
+            return Ok(())
+        }
+        if installation.user_id.as_ref().is_some_and(|v| tokens.oauth.contains(v)) {
+            installation.user_token = None;
+            installation.user_id = None;
+            installation_store.save(&installation).await?;
+            info!(team = team_id, "user token revoked");
+        }
+        Ok(())
+    }
+
+    // turns the user away with an ephemeral message when the access policy does not allow them,
+    // or when it cannot be checked
+    async fn permitted(&self, team_id: Option<&str>, user: Option<&str>, channel: Option<&str>) -> Result<bool> {
+        let (Some(user), Some(channel)) = (user, channel) else { return Ok(true) };
+        let access_control = AccessControl::new(&self.runtime_context);
+        let Some(denial) = access_control.check(team_id, user, channel).await else { return Ok(true) };
+        access_control.notify_denied(team_id, user, channel, denial).await?;
+        Ok(false)
+    }
+
+    async fn handle_slack_message(&self, trace_id: String, callback: EventCallback) -> Result<()>  {
         let channel_client = self.runtime_context.channel_client();
-        let message = InvokeMessage {
-            event_type,
-            body,
-        };
-        channel_client.invoke(message).await?;
+        let team_id = callback.team_id.clone();
+        let message = InvokeMessage::new(trace_id, team_id, InvokePayload::EventCallback(callback));
+        channel_client.dispatch(message).await?;
         Ok(())
     }
 }
@@ -0,0 +1,220 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use cores::installations::Installation;
+use cores::metrics;
+use hmac::{Hmac, Mac};
+use lambda_http::{Body, Error, Request, RequestExt, Response};
+use serde::Deserialize;
+use sha2::Sha256;
+use tracing::info;
+
+use crate::config::OAuthConfig;
+use crate::runtime_context::RuntimeContext;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// https://api.slack.com/authentication/oauth-v2#asking
+const AUTHORIZE_URL: &str = "https://slack.com/oauth/v2/authorize";
+
+// how long an install link stays valid
+const STATE_TTL: Duration = Duration::from_secs(10 * 60);
+
+const STATE_COOKIE: &str = "yoshino_oauth_state";
+
+// https://api.slack.com/methods/oauth.v2.access
+#[derive(Deserialize, Debug)]
+struct OAuthAccessResponseBody {
+    ok: bool,
+    error: Option<String>,
+    access_token: Option<String>,
+    bot_user_id: Option<String>,
+    team: Option<OAuthTeam>,
+    authed_user: Option<OAuthAuthedUser>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OAuthTeam {
+    id: String,
+    name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OAuthAuthedUser {
+    id: Option<String>,
+    access_token: Option<String>,
+}
+
+// "Add to Slack" for any number of workspaces, each installation is kept in the installation store
+pub struct SlackOAuthHandler {
+    runtime_context: Arc<RuntimeContext>,
+    client: reqwest::Client,
+}
+
+impl SlackOAuthHandler {
+    pub fn new(runtime_context: &Arc<RuntimeContext>) -> Arc<Self> {
+        let runtime_context = Arc::clone(runtime_context);
+        let handler = Self {
+            runtime_context,
+            client: reqwest::Client::new(),
+        };
+        Arc::new(handler)
+    }
+
+    pub async fn handle_install_request(&self, event: Request) -> Result<Response<Body>, Error> {
+        match self.install(event).await {
+            Ok(response) => Ok(response),
+            Err(error) => {
+                info!(error = ?error, "/slack/install error");
+                metrics::increment("errors", &[("type", "oauth")]);
+                Ok(self.page_response(500, "Installation is not available.")?)
+            }
+        }
+    }
+
+    pub async fn handle_oauth_redirect_request(&self, event: Request) -> Result<Response<Body>, Error> {
+        match self.oauth_redirect(event).await {
+            Ok(response) => Ok(response),
+            Err(error) => {
+                info!(error = ?error, "/slack/oauth_redirect error");
+                metrics::increment("errors", &[("type", "oauth")]);
+                Ok(self.page_response(500, "Installation failed. Please try again from the install link.")?)
+            }
+        }
+    }
+
+    fn oauth_config(&self) -> Result<&OAuthConfig> {
+        self.runtime_context.config().oauth.as_ref()
+            .context("OAuth is not configured, SLACK_CLIENT_ID is not set")
+    }
+
+    async fn install(&self, _event: Request) -> Result<Response<Body>> {
+        let oauth_config = self.oauth_config()?;
+        let state = sign_state(&oauth_config.client_secret.get().await, unix_now())?;
+        let mut query = vec![
+            ("client_id", oauth_config.client_id.as_str()),
+            ("scope", oauth_config.scopes.as_str()),
+            ("user_scope", oauth_config.user_scopes.as_str()),
+            ("state", state.as_str()),
+        ];
+        if let Some(ref redirect_uri) = oauth_config.redirect_uri {
+            query.push(("redirect_uri", redirect_uri));
+        }
+        let location = format!("{}?{}", AUTHORIZE_URL, serde_urlencoded::to_string(&query)?);
+        // binds the state to the browser that started the installation
+        let cookie = format!("{}={}; Path=/slack; Max-Age={}; HttpOnly; Secure; SameSite=Lax", STATE_COOKIE, state, STATE_TTL.as_secs());
+        let response = Response::builder()
+            .status(302)
+            .header("location", location)
+            .header("set-cookie", cookie)
+            .body(Body::Empty)
+            .map_err(Box::new)?;
+        Ok(response)
+    }
+
+    // https://api.slack.com/authentication/oauth-v2#exchanging
+    async fn oauth_redirect(&self, event: Request) -> Result<Response<Body>> {
+        let params = event.query_string_parameters();
+        if let Some(error) = params.first("error") {
+            info!(error, "installation declined");
+            return self.page_response(200, "Installation was cancelled.")
+        }
+        let state = params.first("state").context("no state")?;
+        let cookie_state = event.headers().get_all("cookie").iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|v| v.trim().strip_prefix(STATE_COOKIE)?.strip_prefix('='))
+            .next();
+        if cookie_state != Some(state) {
+            bail!("state does not match the cookie");
+        }
+        verify_state(&self.oauth_config()?.client_secret.get().await, state, unix_now())?;
+        let code = params.first("code").context("no code")?;
+        let installation = self.exchange(code).await?;
+        self.runtime_context.installation_store().save(&installation).await?;
+        info!(team = installation.team_id, "installed");
+        let team_name = installation.team_name.unwrap_or(installation.team_id);
+        let response = Response::builder()
+            .status(200)
+            .header("content-type", "text/plain; charset=utf-8")
+            // the state is single use
+            .header("set-cookie", format!("{}=; Path=/slack; Max-Age=0", STATE_COOKIE))
+            .body(format!("Yoshino Radio is installed to {}. You can close this page.", team_name).into())
+            .map_err(Box::new)?;
+        Ok(response)
+    }
+
+    async fn exchange(&self, code: &str) -> Result<Installation> {
+        let oauth_config = self.oauth_config()?;
+        let client_secret = oauth_config.client_secret.get().await;
+        let mut form = vec![
+            ("client_id", oauth_config.client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+            ("code", code),
+        ];
+        if let Some(ref redirect_uri) = oauth_config.redirect_uri {
+            form.push(("redirect_uri", redirect_uri));
+        }
+        let response = self.client.post("https://slack.com/api/oauth.v2.access")
+            .form(&form)
+            .send()
+            .await?;
+        let body: OAuthAccessResponseBody = response.json().await?;
+        if !body.ok {
+            bail!("oauth.v2.access failed {:?}", body.error);
+        }
+        let team = body.team.context("no team in the oauth.v2.access response")?;
+        let installation = Installation {
+            team_id: team.id,
+            team_name: team.name,
+            bot_user_id: body.bot_user_id,
+            bot_token: body.access_token.context("no bot token in the oauth.v2.access response")?,
+            user_token: body.authed_user.as_ref().and_then(|v| v.access_token.clone()),
+            user_id: body.authed_user.and_then(|v| v.id),
+            installed_at: Utc::now(),
+        };
+        Ok(installation)
+    }
+
+    fn page_response(&self, status: u16, text: &str) -> Result<Response<Body>> {
+        let response = Response::builder()
+            .status(status)
+            .header("content-type", "text/plain; charset=utf-8")
+            .body(text.into())
+            .map_err(Box::new)?;
+        Ok(response)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or_default()
+}
+
+fn state_mac(client_secret: &str, issued_at: u64) -> Result<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(client_secret.as_bytes())?;
+    mac.update(format!("state:{}", issued_at).as_bytes());
+    Ok(mac)
+}
+
+// `<issued_at>.<signature>` needs no server-side storage between the two requests
+fn sign_state(client_secret: &str, issued_at: u64) -> Result<String> {
+    let signature = hex::encode(state_mac(client_secret, issued_at)?.finalize().into_bytes());
+    Ok(format!("{}.{}", issued_at, signature))
+}
+
+fn verify_state(client_secret: &str, state: &str, now: u64) -> Result<()> {
+    let (issued_at, signature) = state.split_once('.')
+        .context("malformed state")?;
+    let issued_at: u64 = issued_at.parse()?;
+    if now.saturating_sub(issued_at) > STATE_TTL.as_secs() {
+        bail!("state expired");
+    }
+    state_mac(client_secret, issued_at)?.verify_slice(&hex::decode(signature)?)
+        .map_err(|_| anyhow::anyhow!("state signature mismatch"))?;
+    Ok(())
+}
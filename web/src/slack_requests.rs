@@ -3,39 +3,47 @@ use std::sync::Arc;
 
 use lambda_http::Error;
 use lambda_http::{Body, Request, Response};
+use cores::metrics;
+use tracing::info;
 
-use crate::{slack_events::SlackEventHandler, runtime_context::RuntimeContext};
-use crate::slack_verification::verify_slack_request;
+use crate::{slack_events::SlackEventHandler, slack_commands::SlackCommandHandler, runtime_context::RuntimeContext};
 
 pub struct SlackRequestHandler {
     event_handler: Arc<SlackEventHandler>,
+    command_handler: Arc<SlackCommandHandler>,
 }
 
 impl SlackRequestHandler {
     pub fn new(runtime_context: &Arc<RuntimeContext>) -> Arc<Self> {
         let event_handler = SlackEventHandler::new(runtime_context);
+        let command_handler = SlackCommandHandler::new(runtime_context);
         let handler = Self {
             event_handler,
+            command_handler,
         };
         Arc::new(handler)
     }
 
-    pub async fn handle_slack_request(&self, event: Request) -> Result<Response<Body>, Error> {
-        let verification_result = verify_slack_request(&event);
-        match verification_result {
-            Ok(()) => {
-                let result = self.event_handler.handle_verified_events(event).await;
-                match result {
-                    Ok(response) => Ok(response),
-                    Err(error) => {
-                        tracing::info!("/slack/events error {:?}", error);
-                        self.internal_server_error_response()
-                    }
-                }
-            },
+    pub async fn handle_slack_request(&self, event: Request, trace_id: String) -> Result<Response<Body>, Error> {
+        let result = self.event_handler.handle_verified_events(event, trace_id).await;
+        match result {
+            Ok(response) => Ok(response),
             Err(error) => {
-                tracing::info!("/slack/events verification failed {:?}", error);
-                self.forbidden_response()
+                info!(error = ?error, "/slack/events error");
+                metrics::increment("errors", &[("type", "events")]);
+                self.internal_server_error_response()
+            }
+        }
+    }
+
+    pub async fn handle_slack_command_request(&self, event: Request, trace_id: String) -> Result<Response<Body>, Error> {
+        let result = self.command_handler.handle_verified_command(event, trace_id).await;
+        match result {
+            Ok(response) => Ok(response),
+            Err(error) => {
+                info!(error = ?error, "/slack/commands error");
+                metrics::increment("errors", &[("type", "commands")]);
+                self.internal_server_error_response()
             }
         }
     }
@@ -48,13 +56,4 @@ impl SlackRequestHandler {
             .map_err(Box::new)?;
         Ok(response)
     }
-
-    fn forbidden_response(&self) -> Result<Response<Body>, Error> {
-        let response = Response::builder()
-            .status(403)
-            .header("content-type", "text/plain")
-            .body("forbidden".into())
-            .map_err(Box::new)?;
-        Ok(response)
-    }
 }
@@ -1,54 +1,222 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::str;
+use std::sync::Arc;
+use std::task::{self, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-// SLACK_SIGNING_SECRET
-use std::env;
-
-use sha2::Sha256;
+use anyhow::{Context, Result, bail};
+use cores::metrics;
 use hmac::{Hmac, Mac};
-use hex;
+use lambda_http::tower::{Layer, Service};
+use lambda_http::{Body, Error, Request, RequestExt, Response};
+use sha2::Sha256;
+use tracing::{info, info_span};
 
-use lambda_http::{Request, Body};
-use std::time::{SystemTime, Duration};
-use anyhow::{Context, Result, bail};
+use crate::config::Config;
 
 type HmacSha256 = Hmac<Sha256>;
 
+// requests signed further than this from local time are rejected, in either direction,
+// so that a Slack clock ahead of ours is tolerated as much as one behind
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+// the raw body Slack signed, function URLs deliver base64 encoded bodies as Body::Binary
+fn body_bytes(request: &Request) -> Result<&[u8]> {
+    match request.body() {
+        Body::Text(text) => Ok(text.as_bytes()),
+        Body::Binary(bytes) => Ok(bytes),
+        Body::Empty => bail!("no body"),
+    }
+}
+
+// Slack sends JSON or form encoded bodies, either of which is text
+pub fn body_text(request: &Request) -> Result<&str> {
+    Ok(str::from_utf8(body_bytes(request)?)?)
+}
+
 // https://api.slack.com/authentication/verifying-requests-from-slack
-pub fn verify_slack_request(request: &Request) -> Result<()> {
+pub fn verify_slack_request(request: &Request, signing_secrets: &[String]) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
     let headers = request.headers();
-    let Body::Text(body_text) = request.body() else {
-        bail!("no body");
-    };
     let slack_signature = headers.get("X-Slack-Signature")
         .context("X-Slack-Signature is empty")?
         .to_str()?;
     let slack_timestamp = headers.get("X-Slack-Request-Timestamp")
         .context("X-Slack-Request-Timestamp is empty")?
         .to_str()?;
-    let slack_timestamp_time: u64 = slack_timestamp.parse()?;
-    let slack_timestamp_time = Duration::from_secs(slack_timestamp_time);
-    let now = SystemTime::now();
-    let now = now.duration_since(SystemTime::UNIX_EPOCH)?;
-    let delta = now - slack_timestamp_time;
-    if delta > Duration::from_secs(5 * 60) {
+    verify_signature(signing_secrets, slack_timestamp, body_bytes(request)?, slack_signature, now)
+}
+
+// signing_secrets holds the current secret, and the previous one during a rotation
+// https://api.slack.com/authentication/verifying-requests-from-slack#making__validating-a-request
+pub fn verify_signature(signing_secrets: &[String], timestamp: &str, body: &[u8], signature: &str, now: u64) -> Result<()> {
+    let timestamp_secs: u64 = timestamp.parse()
+        .context("malformed X-Slack-Request-Timestamp")?;
+    if now.abs_diff(timestamp_secs) > MAX_CLOCK_SKEW.as_secs() {
         bail!("The request timestamp is more than five minutes from local time");
     }
-    let verification_result = verify_signature(slack_timestamp, body_text, slack_signature)?;
-    if verification_result {
-        Ok(())
-    } else {
-        bail!("verification failed")
+    let signature = signature.strip_prefix("v0=")
+        .context("unknown signature version")?;
+    let signature = hex::decode(signature)
+        .context("malformed X-Slack-Signature")?;
+    if signing_secrets.is_empty() {
+        bail!("no signing secret");
+    }
+    // every secret is tried so that the time taken does not tell which one matched
+    let mut verified = false;
+    for signing_secret in signing_secrets {
+        let mut mac = HmacSha256::new_from_slice(signing_secret.as_bytes())?;
+        mac.update(b"v0:");
+        mac.update(timestamp.as_bytes());
+        mac.update(b":");
+        mac.update(body);
+        // compares in constant time
+        verified |= mac.verify_slice(&signature).is_ok();
     }
+    if !verified {
+        bail!("verification failed");
+    }
+    Ok(())
 }
 
-// https://api.slack.com/authentication/verifying-requests-from-slack#making__validating-a-request
-fn verify_signature(timestamp: &str, body: &str, signature_actual: &str) -> Result<bool> {
-    let signing_secret = env::var("SLACK_SIGNING_SECRET")?;
-    let mut mac = HmacSha256::new_from_slice(signing_secret.as_bytes())?;
-    let message = ["v0", timestamp, body].join(":");
-    mac.update(message.as_bytes());
-    let mac = mac.finalize();
-    let signature_expected = mac.into_bytes();
-    let signature_expected = hex::encode(&signature_expected);
-    let signature_expected = ["v0=", signature_expected.as_str()].join("");
-    Ok(signature_actual == signature_expected)
+// Answers 403 to requests for `paths` that are not signed by Slack, other requests pass through
+#[derive(Clone)]
+pub struct SlackVerificationLayer {
+    config: Arc<Config>,
+    paths: &'static [&'static str],
+}
+
+impl SlackVerificationLayer {
+    pub fn new(config: &Arc<Config>, paths: &'static [&'static str]) -> Self {
+        Self {
+            config: Arc::clone(config),
+            paths,
+        }
+    }
+}
+
+impl<S> Layer<S> for SlackVerificationLayer {
+    type Service = SlackVerification<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SlackVerification {
+            inner,
+            config: Arc::clone(&self.config),
+            paths: self.paths,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SlackVerification<S> {
+    inner: S,
+    config: Arc<Config>,
+    paths: &'static [&'static str],
+}
+
+impl<S> Service<Request> for SlackVerification<S>
+where
+    S: Service<Request, Response = Response<Body>, Error = Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<(), Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if !self.paths.contains(&request.raw_http_path()) {
+            return Box::pin(self.inner.call(request))
+        }
+        // the instance polled ready serves this request, its clone the next one
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let config = Arc::clone(&self.config);
+        Box::pin(async move {
+            let signing_secrets = config.signing_secrets().await;
+            let path = request.raw_http_path().to_string();
+            let verification_result = info_span!("verify", path)
+                .in_scope(|| verify_slack_request(&request, &signing_secrets));
+            match verification_result {
+                Ok(()) => inner.call(request).await,
+                Err(error) => {
+                    info!(error = ?error, path, "verification failed");
+                    metrics::increment("errors", &[("type", "verification")]);
+                    let response = Response::builder()
+                        .status(403)
+                        .header("content-type", "text/plain")
+                        .body("forbidden".into())
+                        .map_err(Box::new)?;
+                    Ok(response)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // https://api.slack.com/authentication/verifying-requests-from-slack#a_recipe_for_security
+    const SIGNING_SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+    const TIMESTAMP: u64 = 1531420618;
+    const BODY: &str = "token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow&channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner&command=%2Fwebhook-collect&text=&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN&trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+    const SIGNATURE: &str = "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503";
+
+    fn verify(signing_secrets: &[&str], body: &str, signature: &str, now: u64) -> Result<()> {
+        let signing_secrets: Vec<String> = signing_secrets.iter().map(|v| v.to_string()).collect();
+        verify_signature(&signing_secrets, &TIMESTAMP.to_string(), body.as_bytes(), signature, now)
+    }
+
+    #[test]
+    fn accepts_the_slack_example() {
+        verify(&[SIGNING_SECRET], BODY, SIGNATURE, TIMESTAMP).unwrap();
+    }
+
+    #[test]
+    fn accepts_the_previous_secret_during_a_rotation() {
+        verify(&["new-secret", SIGNING_SECRET], BODY, SIGNATURE, TIMESTAMP).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_tampered_body() {
+        let body = BODY.replace("user_name=roadrunner", "user_name=coyote");
+        assert!(verify(&[SIGNING_SECRET], &body, SIGNATURE, TIMESTAMP).is_err());
+    }
+
+    #[test]
+    fn rejects_another_secret() {
+        assert!(verify(&["another-secret"], BODY, SIGNATURE, TIMESTAMP).is_err());
+        assert!(verify(&[], BODY, SIGNATURE, TIMESTAMP).is_err());
+    }
+
+    #[test]
+    fn accepts_skew_up_to_the_window_in_either_direction() {
+        let window = MAX_CLOCK_SKEW.as_secs();
+        verify(&[SIGNING_SECRET], BODY, SIGNATURE, TIMESTAMP + window).unwrap();
+        verify(&[SIGNING_SECRET], BODY, SIGNATURE, TIMESTAMP - window).unwrap();
+    }
+
+    #[test]
+    fn rejects_skew_beyond_the_window_in_either_direction() {
+        let window = MAX_CLOCK_SKEW.as_secs();
+        assert!(verify(&[SIGNING_SECRET], BODY, SIGNATURE, TIMESTAMP + window + 1).is_err());
+        assert!(verify(&[SIGNING_SECRET], BODY, SIGNATURE, TIMESTAMP - window - 1).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let signature = SIGNATURE.strip_prefix("v0=").unwrap();
+        assert!(verify(&[SIGNING_SECRET], BODY, signature, TIMESTAMP).is_err());
+        assert!(verify(&[SIGNING_SECRET], BODY, &format!("v1={}", signature), TIMESTAMP).is_err());
+        assert!(verify(&[SIGNING_SECRET], BODY, "v0=not-hex", TIMESTAMP).is_err());
+        let signing_secrets = vec![SIGNING_SECRET.to_string()];
+        assert!(verify_signature(&signing_secrets, "yesterday", BODY.as_bytes(), SIGNATURE, TIMESTAMP).is_err());
+        assert!(verify_signature(&signing_secrets, "", BODY.as_bytes(), SIGNATURE, TIMESTAMP).is_err());
+    }
 }
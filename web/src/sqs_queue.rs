@@ -0,0 +1,78 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_sqs::types::{MessageSystemAttributeName, QueueAttributeName};
+
+use cores::queue::{MessageQueue, QueueMessage};
+
+// Dead-lettering is configured on the queue itself with a redrive policy,
+// set its maxReceiveCount to the consumer's max attempts.
+// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/sqs-dead-letter-queues.html
+pub struct SqsQueue {
+    client: aws_sdk_sqs::Client,
+    queue_url: String,
+}
+
+impl SqsQueue {
+    pub async fn new(queue_url: &str) -> Arc<Self> {
+        let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+        let client = aws_sdk_sqs::Client::new(&config);
+        let this = Self {
+            client,
+            queue_url: queue_url.into(),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl MessageQueue for SqsQueue {
+    async fn send(&self, body: String) -> Result<()> {
+        self.client.send_message()
+            .queue_url(&self.queue_url)
+            .message_body(body)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn receive(&self, max_messages: usize, visibility_timeout: Duration) -> Result<Vec<QueueMessage>> {
+        let output = self.client.receive_message()
+            .queue_url(&self.queue_url)
+            // SQS returns at most 10 messages per call
+            .max_number_of_messages(max_messages.clamp(1, 10) as i32)
+            .visibility_timeout(visibility_timeout.as_secs() as i32)
+            .wait_time_seconds(20)
+            .attribute_names(QueueAttributeName::All)
+            .send()
+            .await?;
+        let messages = output.messages.unwrap_or_default()
+            .into_iter()
+            .map(|message| {
+                let attempts = message.attributes()
+                    .and_then(|v| v.get(&MessageSystemAttributeName::ApproximateReceiveCount))
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(1);
+                QueueMessage {
+                    id: message.message_id.unwrap_or_default(),
+                    receipt: message.receipt_handle.unwrap_or_default(),
+                    body: message.body.unwrap_or_default(),
+                    attempts,
+                }
+            })
+            .collect();
+        Ok(messages)
+    }
+
+    async fn ack(&self, receipt: &str) -> Result<()> {
+        self.client.delete_message()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt)
+            .send()
+            .await?;
+        Ok(())
+    }
+}
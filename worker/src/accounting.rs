@@ -0,0 +1,368 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Result, Context};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use cores::config::{ConfigLoader, StoreKind};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::openai_client::CompletionsUsage;
+
+// what one answer cost
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UsageRecord {
+    pub recorded_at: DateTime<Utc>,
+    pub trace_id: String,
+    pub team_id: Option<String>,
+    pub channel: String,
+    pub user: String,
+    pub persona: String,
+    pub model: String,
+    // as reported by the API, includes the tokens of the images
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    // attached to the prompt
+    pub images: u64,
+    // the part of prompt_tokens spent on the images
+    #[serde(default)]
+    pub image_tokens: u64,
+    pub cost_usd: f64,
+}
+
+// images are sent with detail `low`, which costs a fixed number of tokens whatever their size
+// https://platform.openai.com/docs/guides/vision/calculating-costs
+pub const LOW_DETAIL_IMAGE_TOKENS: u64 = 85;
+
+pub fn image_tokens(images: u64) -> u64 {
+    images * LOW_DETAIL_IMAGE_TOKENS
+}
+
+// USD per million tokens
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+// https://openai.com/pricing
+pub struct PriceTable {
+    prices: HashMap<String, ModelPrice>,
+}
+
+impl PriceTable {
+    // YOSHINO_PRICE_TABLE_PATH points at a JSON object of model names to prices,
+    // e.g. {"gpt-4-turbo": {"prompt": 10.0, "completion": 30.0}}, which override the built-in prices
+    pub fn load(loader: &mut ConfigLoader) -> Arc<Self> {
+        let mut prices = HashMap::from([
+            ("gpt-4-turbo".to_string(), ModelPrice { prompt: 10.0, completion: 30.0 }),
+            ("gpt-4o".to_string(), ModelPrice { prompt: 5.0, completion: 15.0 }),
+            ("gpt-3.5-turbo".to_string(), ModelPrice { prompt: 0.5, completion: 1.5 }),
+        ]);
+        if let Some(path) = loader.optional("YOSHINO_PRICE_TABLE_PATH") {
+            match Self::overrides(&path) {
+                Ok(overrides) => prices.extend(overrides),
+                Err(error) => loader.invalid(format!("YOSHINO_PRICE_TABLE_PATH {:#}", error)),
+            }
+        }
+        Arc::new(Self { prices })
+    }
+
+    fn overrides(path: &str) -> Result<HashMap<String, ModelPrice>> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read price table {}", path))?;
+        let overrides = serde_json::from_str(&text)
+            .with_context(|| format!("broken price table {}", path))?;
+        Ok(overrides)
+    }
+
+    // Config::load refuses a completions model without a price
+    pub fn has_price(&self, model: &str) -> bool {
+        self.prices.contains_key(model)
+    }
+
+    pub fn cost(&self, model: &str, usage: &CompletionsUsage) -> f64 {
+        let Some(price) = self.prices.get(model) else {
+            info!("no price for model {}", model);
+            return 0.0
+        };
+        (usage.prompt_tokens as f64 * price.prompt + usage.completion_tokens as f64 * price.completion) / 1_000_000.0
+    }
+}
+
+#[async_trait]
+pub trait UsageLedger: Send + Sync {
+    async fn record(&self, record: &UsageRecord) -> Result<()>;
+    // month is formatted as YYYY-MM
+    async fn records(&self, month: &str) -> Result<Vec<UsageRecord>>;
+}
+
+// YOSHINO_USAGE_LEDGER selects the implementation
+// * `dynamodb` (default) the table YOSHINO_USAGE_TABLE, YOSHINO_USAGE_DYNAMODB_ENDPOINT points at DynamoDB Local
+// * `file` the JSON lines file at YOSHINO_USAGE_LEDGER_PATH, one record per answer
+pub async fn load_usage_ledger(loader: &mut ConfigLoader) -> Arc<dyn UsageLedger> {
+    match loader.parse("YOSHINO_USAGE_LEDGER", StoreKind::DynamoDb) {
+        StoreKind::DynamoDb => {
+            let table = loader.optional("YOSHINO_USAGE_TABLE")
+                .unwrap_or_else(|| "yoshino-radio-usage".into());
+            let endpoint = loader.optional("YOSHINO_USAGE_DYNAMODB_ENDPOINT");
+            DynamoDbUsageLedger::new(&table, endpoint.as_deref()).await
+        },
+        StoreKind::File => FileUsageLedger::new(PathBuf::from(loader.required("YOSHINO_USAGE_LEDGER_PATH"))),
+    }
+}
+
+pub struct FileUsageLedger {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileUsageLedger {
+    pub fn new(path: PathBuf) -> Arc<Self> {
+        let this = Self {
+            path,
+            lock: Mutex::new(()),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl UsageLedger for FileUsageLedger {
+    async fn record(&self, record: &UsageRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let _guard = self.lock.lock().await;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    async fn records(&self, month: &str) -> Result<Vec<UsageRecord>> {
+        let _guard = self.lock.lock().await;
+        if !self.path.exists() {
+            return Ok(vec![])
+        }
+        let text = fs::read_to_string(&self.path)?;
+        let records = text.lines()
+            .filter_map(|v| serde_json::from_str::<UsageRecord>(v).ok())
+            .filter(|v| v.recorded_at.format("%Y-%m").to_string() == month)
+            .collect();
+        Ok(records)
+    }
+}
+
+// Expects a table with the string partition key `month` (YYYY-MM) and the string sort key `id`,
+// the record's time followed by its trace ID, and keeps the record as JSON in `record`.
+pub struct DynamoDbUsageLedger {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+}
+
+impl DynamoDbUsageLedger {
+    pub async fn new(table: &str, endpoint: Option<&str>) -> Arc<Self> {
+        let client = cores::dynamodb::client(endpoint).await;
+        let this = Self {
+            client,
+            table: table.into(),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl UsageLedger for DynamoDbUsageLedger {
+    async fn record(&self, record: &UsageRecord) -> Result<()> {
+        let month = record.recorded_at.format("%Y-%m").to_string();
+        let id = format!("{}#{}", record.recorded_at.to_rfc3339(), record.trace_id);
+        self.client.put_item()
+            .table_name(&self.table)
+            .item("month", AttributeValue::S(month))
+            .item("id", AttributeValue::S(id))
+            .item("record", AttributeValue::S(serde_json::to_string(record)?))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn records(&self, month: &str) -> Result<Vec<UsageRecord>> {
+        let mut pages = self.client.query()
+            .table_name(&self.table)
+            .key_condition_expression("#month = :month")
+            .expression_attribute_names("#month", "month")
+            .expression_attribute_values(":month", AttributeValue::S(month.into()))
+            .into_paginator()
+            .items()
+            .send();
+        let mut records = vec![];
+        while let Some(item) = pages.next().await {
+            let item = item?;
+            let Some(AttributeValue::S(record)) = item.get("record") else { continue };
+            records.push(serde_json::from_str(record)?);
+        }
+        Ok(records)
+    }
+}
+
+#[derive(Default)]
+struct UsageTotal {
+    answers: u64,
+    tokens: u64,
+    cost_usd: f64,
+}
+
+impl UsageTotal {
+    fn add(&mut self, record: &UsageRecord) {
+        self.answers += 1;
+        self.tokens += record.prompt_tokens + record.completion_tokens;
+        self.cost_usd += record.cost_usd;
+    }
+}
+
+// a summary for the usage slash command
+pub fn usage_report(month: &str, records: &[UsageRecord]) -> String {
+    let mut total = UsageTotal::default();
+    let mut by_user: BTreeMap<&str, UsageTotal> = BTreeMap::new();
+    let mut by_channel: BTreeMap<&str, UsageTotal> = BTreeMap::new();
+    for record in records {
+        total.add(record);
+        by_user.entry(&record.user).or_default().add(record);
+        by_channel.entry(&record.channel).or_default().add(record);
+    }
+    let mut lines = vec![
+        format!("*{}* {} answers, {} tokens, ${:.2}", month, total.answers, total.tokens, total.cost_usd),
+        "*by user*".into(),
+    ];
+    for (user, v) in by_user {
+        lines.push(format!("<@{}> {} answers, {} tokens, ${:.2}", user, v.answers, v.tokens, v.cost_usd));
+    }
+    lines.push("*by channel*".into());
+    for (channel, v) in by_channel {
+        lines.push(format!("<#{}> {} answers, {} tokens, ${:.2}", channel, v.answers, v.tokens, v.cost_usd));
+    }
+    lines.join("\n")
+}
+
+// quotes a field holding a comma, a quote or a line break, doubling its quotes
+// https://www.rfc-editor.org/rfc/rfc4180#section-2
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.into()
+    }
+}
+
+// lines end with CRLF as RFC 4180 asks
+pub fn usage_csv(records: &[UsageRecord]) -> String {
+    let mut lines = vec![
+        "recorded_at,trace_id,team_id,channel,user,persona,model,prompt_tokens,completion_tokens,images,image_tokens,cost_usd".to_string(),
+    ];
+    for v in records {
+        let fields = [
+            v.recorded_at.to_rfc3339(),
+            v.trace_id.clone(),
+            v.team_id.clone().unwrap_or_default(),
+            v.channel.clone(),
+            v.user.clone(),
+            v.persona.clone(),
+            v.model.clone(),
+            v.prompt_tokens.to_string(),
+            v.completion_tokens.to_string(),
+            v.images.to_string(),
+            v.image_tokens.to_string(),
+            format!("{:.6}", v.cost_usd),
+        ];
+        lines.push(fields.iter().map(|v| csv_field(v)).collect::<Vec<_>>().join(","));
+    }
+    let mut csv = lines.join("\r\n");
+    csv.push_str("\r\n");
+    csv
+}
+
+pub fn usage_json(records: &[UsageRecord]) -> Result<String> {
+    Ok(serde_json::to_string_pretty(records)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn record(recorded_at: DateTime<Utc>, user: &str, cost_usd: f64) -> UsageRecord {
+        UsageRecord {
+            recorded_at,
+            trace_id: "0b9c6a54-6f1e-4f43-9d7e-6d2a0c1e8f12".into(),
+            team_id: Some("T0123ABCD".into()),
+            channel: "D0123ABCD".into(),
+            user: user.into(),
+            persona: "yoshino".into(),
+            model: "gpt-4-turbo".into(),
+            prompt_tokens: 1085,
+            completion_tokens: 200,
+            images: 1,
+            image_tokens: 85,
+            cost_usd,
+        }
+    }
+
+    fn april(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 4, day, 9, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn prices_prompt_and_completion_tokens() {
+        let price_table = PriceTable { prices: HashMap::from([("gpt-4-turbo".to_string(), ModelPrice { prompt: 10.0, completion: 30.0 })]) };
+        let usage = CompletionsUsage { prompt_tokens: 1_000_000, completion_tokens: 500_000 };
+        assert_eq!(price_table.cost("gpt-4-turbo", &usage), 25.0);
+        assert!(price_table.has_price("gpt-4-turbo"));
+        assert!(!price_table.has_price("gpt-5"));
+        assert_eq!(image_tokens(2), 170);
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        let mut quoted = record(april(1), "U0123ABCD", 0.0161);
+        quoted.persona = "yoshino, \"radio\"".into();
+        let csv = usage_csv(&[quoted]);
+        let lines: Vec<&str> = csv.split("\r\n").collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("images,image_tokens,cost_usd"));
+        assert!(lines[1].contains(",U0123ABCD,\"yoshino, \"\"radio\"\"\",gpt-4-turbo,1085,200,1,85,0.016100"));
+        assert_eq!(lines[2], "");
+    }
+
+    #[test]
+    fn sums_the_month_by_user_and_channel() {
+        let records = [record(april(1), "U0123ABCD", 1.0), record(april(2), "U0123ABCD", 0.5), record(april(2), "U0456EFGH", 0.25)];
+        let report = usage_report("2024-04", &records);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "*2024-04* 3 answers, 3855 tokens, $1.75");
+        assert_eq!(lines[2], "<@U0123ABCD> 2 answers, 2570 tokens, $1.50");
+        assert_eq!(lines[3], "<@U0456EFGH> 1 answers, 1285 tokens, $0.25");
+        assert_eq!(lines[5], "<#D0123ABCD> 3 answers, 3855 tokens, $1.75");
+    }
+
+    #[tokio::test]
+    async fn filters_the_file_ledger_by_month() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = FileUsageLedger::new(dir.path().join("usage.jsonl"));
+        assert!(ledger.records("2024-04").await.unwrap().is_empty());
+        ledger.record(&record(april(30), "U0123ABCD", 1.0)).await.unwrap();
+        ledger.record(&record(Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap(), "U0123ABCD", 1.0)).await.unwrap();
+        let records = ledger.records("2024-04").await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].recorded_at, april(30));
+        assert_eq!(ledger.records("2024-05").await.unwrap().len(), 1);
+    }
+}
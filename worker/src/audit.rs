@@ -0,0 +1,451 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use cores::config::{ConfigLoader, StoreKind};
+use cores::metrics;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::Mutex;
+use tracing::info;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Answered,
+    // a newer prompt or a deletion stopped the answer, the answer is the part posted so far
+    Stopped,
+    // moderation refused the prompt
+    Refused,
+    // moderation withdrew the answer, the answer is what the model generated
+    Withdrawn,
+    // answered with the support message, see Safety
+    Escalated,
+    Throttled,
+    Command,
+    // handling the prompt failed, the answer is none
+    Failed,
+}
+
+// one line of the audit log, the content is recorded only with YOSHINO_AUDIT_CONTENT
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub recorded_at: DateTime<Utc>,
+    pub trace_id: String,
+    pub team_id: Option<String>,
+    pub channel: String,
+    pub user: Option<String>,
+    // the prompt's message
+    pub ts: String,
+    pub persona: String,
+    // none when nothing was generated
+    pub model: Option<String>,
+    pub tools: Vec<String>,
+    pub outcome: AuditOutcome,
+    // HMAC-SHA256 with YOSHINO_AUDIT_HMAC_KEY, so that a short prompt cannot be guessed from its hash,
+    // the prompt includes the transcript of a voice message
+    pub prompt_hmac: String,
+    pub image_hmac: Option<String>,
+    pub answer_hmac: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub answer: Option<String>,
+}
+
+// Records are only ever appended, the retention removes whole days.
+// `from` and `to` are inclusive.
+#[async_trait]
+pub trait AuditLog: Send + Sync {
+    async fn record(&self, record: &AuditRecord) -> Result<()>;
+    async fn records(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<AuditRecord>>;
+    async fn prune(&self, before: NaiveDate) -> Result<()>;
+}
+
+// YOSHINO_AUDIT_LOG selects the implementation
+// * `dynamodb` (default) the table YOSHINO_AUDIT_TABLE, YOSHINO_AUDIT_DYNAMODB_ENDPOINT points at DynamoDB Local
+// * `file` a JSON lines file per day in YOSHINO_AUDIT_DIR, e.g. 2024-04-01.jsonl
+async fn load_audit_log(loader: &mut ConfigLoader, retention_days: u64) -> Arc<dyn AuditLog> {
+    match loader.parse("YOSHINO_AUDIT_LOG", StoreKind::DynamoDb) {
+        StoreKind::DynamoDb => {
+            let table = loader.optional("YOSHINO_AUDIT_TABLE")
+                .unwrap_or_else(|| "yoshino-radio-audit".into());
+            let endpoint = loader.optional("YOSHINO_AUDIT_DYNAMODB_ENDPOINT");
+            DynamoDbAuditLog::new(&table, endpoint.as_deref(), retention_days).await
+        },
+        StoreKind::File => FileAuditLog::new(PathBuf::from(loader.required("YOSHINO_AUDIT_DIR"))),
+    }
+}
+
+pub struct FileAuditLog {
+    dir: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileAuditLog {
+    pub fn new(dir: PathBuf) -> Arc<Self> {
+        let this = Self {
+            dir,
+            lock: Mutex::new(()),
+        };
+        Arc::new(this)
+    }
+
+    fn path(&self, date: NaiveDate) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", date.format("%Y-%m-%d")))
+    }
+}
+
+#[async_trait]
+impl AuditLog for FileAuditLog {
+    async fn record(&self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let _guard = self.lock.lock().await;
+        fs::create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(record.recorded_at.date_naive()))?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    async fn records(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<AuditRecord>> {
+        let _guard = self.lock.lock().await;
+        let mut records = vec![];
+        for date in from.iter_days().take_while(|v| *v <= to) {
+            let path = self.path(date);
+            if !path.exists() {
+                continue;
+            }
+            let text = fs::read_to_string(&path)?;
+            records.extend(text.lines().filter_map(|v| serde_json::from_str::<AuditRecord>(v).ok()));
+        }
+        Ok(records)
+    }
+
+    async fn prune(&self, before: NaiveDate) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        if !self.dir.exists() {
+            return Ok(())
+        }
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let Some(stem) = path.file_stem().and_then(|v| v.to_str()) else { continue };
+            let Ok(date) = NaiveDate::parse_from_str(stem, "%Y-%m-%d") else { continue };
+            if date < before {
+                info!(path = ?path, "removing audit records past retention");
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Expects a table with the string partition key `day` (YYYY-MM-DD) and the string sort key `id`, the record's time
+// followed by a random ID, and keeps the record as JSON in `record`. Past the retention, the table's TTL on
+// `expires_at` removes records, which needs to be enabled on the table.
+pub struct DynamoDbAuditLog {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+    // 0 keeps the records forever
+    retention_days: u64,
+}
+
+impl DynamoDbAuditLog {
+    pub async fn new(table: &str, endpoint: Option<&str>, retention_days: u64) -> Arc<Self> {
+        let client = cores::dynamodb::client(endpoint).await;
+        let this = Self {
+            client,
+            table: table.into(),
+            retention_days,
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl AuditLog for DynamoDbAuditLog {
+    async fn record(&self, record: &AuditRecord) -> Result<()> {
+        let day = record.recorded_at.format("%Y-%m-%d").to_string();
+        let id = format!("{}#{}", record.recorded_at.to_rfc3339(), Uuid::new_v4());
+        let mut request = self.client.put_item()
+            .table_name(&self.table)
+            .item("day", AttributeValue::S(day))
+            .item("id", AttributeValue::S(id))
+            .item("record", AttributeValue::S(serde_json::to_string(record)?));
+        if self.retention_days > 0 {
+            let expires_at = record.recorded_at + Duration::days(self.retention_days as i64 + 1);
+            request = request.item("expires_at", AttributeValue::N(expires_at.timestamp().to_string()));
+        }
+        request.send().await?;
+        Ok(())
+    }
+
+    async fn records(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<AuditRecord>> {
+        let mut records = vec![];
+        for date in from.iter_days().take_while(|v| *v <= to) {
+            let mut pages = self.client.query()
+                .table_name(&self.table)
+                .key_condition_expression("#day = :day")
+                .expression_attribute_names("#day", "day")
+                .expression_attribute_values(":day", AttributeValue::S(date.format("%Y-%m-%d").to_string()))
+                .into_paginator()
+                .items()
+                .send();
+            while let Some(item) = pages.next().await {
+                let item = item?;
+                let Some(AttributeValue::S(record)) = item.get("record") else { continue };
+                records.push(serde_json::from_str(record)?);
+            }
+        }
+        Ok(records)
+    }
+
+    // left to the TTL
+    async fn prune(&self, _before: NaiveDate) -> Result<()> {
+        Ok(())
+    }
+}
+
+// who asked what, shared by the records of one prompt
+#[derive(Clone, Copy)]
+pub struct Interaction<'a> {
+    pub trace_id: &'a str,
+    pub team_id: Option<&'a str>,
+    pub channel: &'a str,
+    pub user: Option<&'a str>,
+    pub ts: &'a str,
+    pub prompt: &'a str,
+    pub image_url: Option<&'a str>,
+}
+
+pub struct AuditTrail {
+    audit_log: Arc<dyn AuditLog>,
+    persona: String,
+    hmac_key: String,
+    // records the prompt and the answer besides their hashes
+    content: bool,
+    // 0 keeps the audit log forever
+    retention_days: u64,
+    // the day records were last pruned in this process, pruning runs at most once a day
+    pruned_on: Mutex<Option<NaiveDate>>,
+}
+
+impl AuditTrail {
+    // YOSHINO_AUDIT_CONTENT records the content, YOSHINO_AUDIT_RETENTION_DAYS sets the retention
+    pub async fn load(loader: &mut ConfigLoader, persona: &str) -> Arc<Self> {
+        let hmac_key = loader.required_secret("YOSHINO_AUDIT_HMAC_KEY").await;
+        let content = loader.flag("YOSHINO_AUDIT_CONTENT");
+        let retention_days = loader.parse("YOSHINO_AUDIT_RETENTION_DAYS", 0);
+        let audit_log = load_audit_log(loader, retention_days).await;
+        Self::new(audit_log, persona, &hmac_key, content, retention_days)
+    }
+
+    pub fn new(audit_log: Arc<dyn AuditLog>, persona: &str, hmac_key: &str, content: bool, retention_days: u64) -> Arc<Self> {
+        let this = Self {
+            audit_log,
+            persona: persona.into(),
+            hmac_key: hmac_key.into(),
+            content,
+            retention_days,
+            pruned_on: Mutex::new(None),
+        };
+        Arc::new(this)
+    }
+
+    fn hmac(&self, text: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(self.hmac_key.as_bytes())
+            .expect("HMAC takes keys of any size");
+        mac.update(text.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    pub async fn record(&self, interaction: &Interaction<'_>, outcome: AuditOutcome, model: Option<&str>, tools: Vec<String>, answer: Option<&str>) -> Result<()> {
+        let record = AuditRecord {
+            recorded_at: Utc::now(),
+            trace_id: interaction.trace_id.into(),
+            team_id: interaction.team_id.map(|v| v.into()),
+            channel: interaction.channel.into(),
+            user: interaction.user.map(|v| v.into()),
+            ts: interaction.ts.into(),
+            persona: self.persona.clone(),
+            model: model.map(|v| v.into()),
+            tools,
+            outcome,
+            prompt_hmac: self.hmac(interaction.prompt),
+            image_hmac: interaction.image_url.map(|v| self.hmac(v)),
+            answer_hmac: answer.map(|v| self.hmac(v)),
+            prompt: self.content.then(|| interaction.prompt.into()),
+            answer: answer.filter(|_| self.content).map(|v| v.into()),
+        };
+        self.audit_log.record(&record).await
+            .with_context(|| format!("failed to record the audit trail of {}", record.trace_id))?;
+        // the interaction is recorded by now, a failed prune is retried with the next record
+        if let Err(error) = self.prune(record.recorded_at.date_naive()).await {
+            info!(error = ?error, "failed to prune the audit log");
+            metrics::increment("errors", &[("type", "audit_prune")]);
+        }
+        Ok(())
+    }
+
+    async fn prune(&self, today: NaiveDate) -> Result<()> {
+        if self.retention_days == 0 {
+            return Ok(())
+        }
+        let mut pruned_on = self.pruned_on.lock().await;
+        if *pruned_on == Some(today) {
+            return Ok(())
+        }
+        self.audit_log.prune(today - Duration::days(self.retention_days as i64)).await?;
+        *pruned_on = Some(today);
+        Ok(())
+    }
+
+    // `from` and `to` are YYYY-MM-DD, `from` defaults to today and `to` to `from`
+    pub fn days(from: Option<&str>, to: Option<&str>) -> Result<(NaiveDate, NaiveDate)> {
+        let today = Utc::now().date_naive();
+        let parse = |v: Option<&str>| -> Result<Option<NaiveDate>> {
+            v.map(|v| NaiveDate::parse_from_str(v, "%Y-%m-%d").with_context(|| format!("{} is not YYYY-MM-DD", v)))
+                .transpose()
+        };
+        let from = parse(from)?.unwrap_or(today);
+        let to = parse(to)?.unwrap_or(from);
+        Ok((from, to))
+    }
+
+    // the records of the days as JSON lines, and a filename for them
+    pub async fn export(&self, from: NaiveDate, to: NaiveDate) -> Result<(String, String)> {
+        let records = self.audit_log.records(from, to).await?;
+        let mut lines = String::new();
+        for record in &records {
+            lines.push_str(&serde_json::to_string(record)?);
+            lines.push('\n');
+        }
+        let filename = format!("yoshino-radio-audit-{}-{}.jsonl", from.format("%Y-%m-%d"), to.format("%Y-%m-%d"));
+        Ok((filename, lines))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use anyhow::bail;
+
+    use super::*;
+
+    fn interaction() -> Interaction<'static> {
+        Interaction {
+            trace_id: "0b9c6a54-6f1e-4f43-9d7e-6d2a0c1e8f12",
+            team_id: Some("T0123ABCD"),
+            channel: "D0123ABCD",
+            user: Some("U0123ABCD"),
+            ts: "1712000000.000000",
+            prompt: "はい",
+            image_url: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn records_keyed_hashes_without_the_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_log = FileAuditLog::new(dir.path().into());
+        let audit_trail = AuditTrail::new(audit_log.clone(), "yoshino", "key", false, 0);
+        audit_trail.record(&interaction(), AuditOutcome::Answered, Some("gpt-4-turbo"), vec![], Some("そうですねー")).await.unwrap();
+        let other_key = AuditTrail::new(audit_log.clone(), "yoshino", "another key", false, 0);
+        other_key.record(&interaction(), AuditOutcome::Answered, Some("gpt-4-turbo"), vec![], Some("そうですねー")).await.unwrap();
+        let today = Utc::now().date_naive();
+        let records = audit_log.records(today, today).await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].prompt_hmac.len(), 64);
+        assert!(records[0].prompt.is_none() && records[0].answer.is_none());
+        // a short prompt cannot be looked up without the key
+        assert_ne!(records[0].prompt_hmac, records[1].prompt_hmac);
+        assert_ne!(records[0].prompt_hmac, hex::encode(<Sha256 as sha2::Digest>::digest("はい")));
+    }
+
+    #[tokio::test]
+    async fn exports_the_days_as_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_trail = AuditTrail::new(FileAuditLog::new(dir.path().into()), "yoshino", "key", true, 0);
+        audit_trail.record(&interaction(), AuditOutcome::Failed, None, vec![], None).await.unwrap();
+        let (from, to) = AuditTrail::days(None, None).unwrap();
+        let (filename, lines) = audit_trail.export(from, to).await.unwrap();
+        let day = from.format("%Y-%m-%d");
+        assert_eq!(filename, format!("yoshino-radio-audit-{}-{}.jsonl", day, day));
+        let record: AuditRecord = serde_json::from_str(lines.lines().next().unwrap()).unwrap();
+        assert!(matches!(record.outcome, AuditOutcome::Failed));
+        assert_eq!(record.prompt.as_deref(), Some("はい"));
+        assert!(AuditTrail::days(Some("2024-04-01"), Some("04/02")).is_err());
+    }
+
+    #[derive(Default)]
+    struct CountingAuditLog {
+        prunes: AtomicUsize,
+        failing: bool,
+        failing_prune: bool,
+    }
+
+    #[async_trait]
+    impl AuditLog for CountingAuditLog {
+        async fn record(&self, _record: &AuditRecord) -> Result<()> {
+            if self.failing {
+                bail!("audit table is unavailable")
+            }
+            Ok(())
+        }
+
+        async fn records(&self, _from: NaiveDate, _to: NaiveDate) -> Result<Vec<AuditRecord>> {
+            Ok(vec![])
+        }
+
+        async fn prune(&self, _before: NaiveDate) -> Result<()> {
+            self.prunes.fetch_add(1, Ordering::SeqCst);
+            if self.failing_prune {
+                bail!("audit directory is read-only")
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn prunes_once_a_day() {
+        let audit_log = Arc::new(CountingAuditLog::default());
+        let audit_trail = AuditTrail::new(audit_log.clone(), "yoshino", "key", false, 30);
+        for _ in 0..3 {
+            audit_trail.record(&interaction(), AuditOutcome::Answered, None, vec![], None).await.unwrap();
+        }
+        assert_eq!(audit_log.prunes.load(Ordering::SeqCst), 1);
+        let tomorrow = Utc::now().date_naive() + Duration::days(1);
+        audit_trail.prune(tomorrow).await.unwrap();
+        assert_eq!(audit_log.prunes.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_record_is_not_kept() {
+        let audit_log = Arc::new(CountingAuditLog { failing: true, ..Default::default() });
+        let audit_trail = AuditTrail::new(audit_log, "yoshino", "key", false, 30);
+        assert!(audit_trail.record(&interaction(), AuditOutcome::Answered, None, vec![], None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_record_when_pruning_fails() {
+        let audit_log = Arc::new(CountingAuditLog { failing_prune: true, ..Default::default() });
+        let audit_trail = AuditTrail::new(audit_log.clone(), "yoshino", "key", false, 30);
+        audit_trail.record(&interaction(), AuditOutcome::Command, None, vec![], None).await.unwrap();
+        audit_trail.record(&interaction(), AuditOutcome::Command, None, vec![], None).await.unwrap();
+        // retried with the next record until it succeeds
+        assert_eq!(audit_log.prunes.load(Ordering::SeqCst), 2);
+    }
+}
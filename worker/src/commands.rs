@@ -0,0 +1,102 @@
+
+// commands typed into the DM instead of a question, prefixed with `!` so that
+// a question such as "remember when we met?" is never taken for one
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Remember(String),
+    ListMemories,
+    Forget(u64),
+    ForgetAll,
+    // admins only, the usage of the mentioned user or of the sender
+    Usage(Option<String>),
+    // agrees to be put in touch with the wellbeing team after an escalation
+    Connect,
+}
+
+impl Command {
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let text = text.strip_prefix('!')
+            .or_else(|| text.strip_prefix('！'))?
+            .trim_start();
+        let lowercased = text.to_lowercase();
+        if matches!(lowercased.as_str(), "memories" | "覚えていること") {
+            return Some(Self::ListMemories)
+        }
+        if matches!(lowercased.as_str(), "connect" | "つないで" | "つないでください") {
+            return Some(Self::Connect)
+        }
+        if let Some(rest) = strip_keyword(text, &["remember", "覚えて"]) {
+            if rest.is_empty() {
+                return None
+            }
+            return Some(Self::Remember(rest.into()))
+        }
+        if let Some(rest) = strip_keyword(text, &["forget", "忘れて"]) {
+            if rest.eq_ignore_ascii_case("all") || rest == "全部" {
+                return Some(Self::ForgetAll)
+            }
+            let id = rest.trim_start_matches('#').parse().ok()?;
+            return Some(Self::Forget(id))
+        }
+        if let Some(rest) = strip_keyword(text, &["usage", "使用量"]) {
+            if rest.is_empty() {
+                return Some(Self::Usage(None))
+            }
+            // https://api.slack.com/reference/surfaces/formatting#mentioning-users
+            let user = rest.strip_prefix("<@")?.strip_suffix('>')?;
+            let user = user.split('|').next()?;
+            return Some(Self::Usage(Some(user.into())))
+        }
+        None
+    }
+}
+
+impl Command {
+    // logged instead of the command, which carries what the user asked to be remembered
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Remember(_) => "remember",
+            Self::ListMemories => "list_memories",
+            Self::Forget(_) => "forget",
+            Self::ForgetAll => "forget_all",
+            Self::Usage(_) => "usage",
+            Self::Connect => "connect",
+        }
+    }
+}
+
+// matches `keyword rest`, `keyword: rest` and `keyword：rest`
+fn strip_keyword<'a>(text: &'a str, keywords: &[&str]) -> Option<&'a str> {
+    for keyword in keywords {
+        let Some(head) = text.get(..keyword.len()) else { continue };
+        if !head.eq_ignore_ascii_case(keyword) {
+            continue;
+        }
+        let rest = &text[keyword.len()..];
+        let separated = rest.is_empty() || rest.starts_with(|c: char| c.is_whitespace() || c == ':' || c == '：');
+        if !separated {
+            continue;
+        }
+        let rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ':' || c == '：');
+        return Some(rest.trim())
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_the_prefix() {
+        assert_eq!(Command::parse("!remember I take my coffee black"), Some(Command::Remember("I take my coffee black".into())));
+        assert_eq!(Command::parse("！覚えて：朝はコーヒー"), Some(Command::Remember("朝はコーヒー".into())));
+        assert_eq!(Command::parse("! forget #3"), Some(Command::Forget(3)));
+        assert_eq!(Command::parse("!つないで"), Some(Command::Connect));
+        assert_eq!(Command::parse("!usage <@U123|someone>"), Some(Command::Usage(Some("U123".into()))));
+        assert_eq!(Command::parse("remember when we first met?"), None);
+        assert_eq!(Command::parse("覚えていること"), None);
+        assert_eq!(Command::parse("!remembering"), None);
+    }
+}
@@ -1,32 +1,61 @@
 
 
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use async_stream::stream;
 
 use crate::buffer_stream::periodic_buffered_window;
 use crate::openai_client::CompletionsRequestMessage;
+use crate::openai_client::CompletionsRequestMessageContent;
 use crate::openai_client::OpenAIClient;
 use crate::openai_client::CompletionsMessageChunk;
+use crate::openai_client::CompletionsUsage;
+use crate::tools::ToolCallAssembler;
+use crate::tools::ToolRegistry;
 
 use anyhow::Result;
+use cores::metrics;
 use futures_util::StreamExt;
 use futures_util::Stream;
 use futures_util::future;
+use tracing::info;
+
+// upper bound of request rounds spent on tool calls before forcing a final answer
+const MAX_TOOL_ROUNDS: usize = 5;
+
+// answered when the request after the tool calls fails or returns no content
+const TOOL_FAILURE_TEXT: &str = "申し訳ありませぬー。お調べしたのですが、お返事をまとめられませんでしたー";
 
 pub struct Completions {
     client: Arc<OpenAIClient>,
+    tools: Arc<ToolRegistry>,
+    // summed over the requests of every tool round
+    usage: Arc<Mutex<CompletionsUsage>>,
+    // names of the tools called, in order
+    tools_used: Arc<Mutex<Vec<String>>>,
 }
 
 impl Completions {
-    pub fn new(client: &Arc<OpenAIClient>) -> Result<Arc<Self>> {
+    pub fn new(client: &Arc<OpenAIClient>, tools: &Arc<ToolRegistry>) -> Result<Arc<Self>> {
         let client = Arc::clone(client);
-        let this = Self { client };
+        let tools = Arc::clone(tools);
+        let usage = Arc::new(Mutex::new(CompletionsUsage::default()));
+        let tools_used = Arc::new(Mutex::new(vec![]));
+        let this = Self { client, tools, usage, tools_used };
         let this = Arc::new(this);
         Ok(this)
     }
 
+    // the usage reported so far, complete once the content stream has ended
+    pub fn usage(&self) -> CompletionsUsage {
+        *self.usage.lock().unwrap()
+    }
+
+    pub fn tools_used(&self) -> Vec<String> {
+        self.tools_used.lock().unwrap().clone()
+    }
+
     // convenience wrapper for completions()
     pub async fn periodic_contents(&self, messages: Vec<CompletionsRequestMessage>) -> Result<impl Stream<Item = String>> {
         let contents = self.concatenated_contents(messages).await?;
@@ -40,26 +69,136 @@ impl Completions {
         Ok(latest_content_stream)
     }
 
+    // runs the requested tools and feeds the results back until the model produces a final answer
     async fn concatenated_contents(&self, messages: Vec<CompletionsRequestMessage>) -> Result<impl Stream<Item = String>> {
-        let completions_stream = self.client.completions(messages).await?;
+        let client = Arc::clone(&self.client);
+        let tools = Arc::clone(&self.tools);
+        let usage = Arc::clone(&self.usage);
+        let tools_used = Arc::clone(&self.tools_used);
+        let started = Instant::now();
+        let completions_stream = client.completions(messages.clone(), tools.definitions(), None).await?;
         let content_stream = stream! {
+            let mut messages = messages;
             let mut concatenated_content = String::new();
             let mut completions_stream = completions_stream;
-            while let Some(item) = completions_stream.next().await {
-                let Ok(chunks) = item else { continue };
-                let chunks: Vec<CompletionsMessageChunk> = chunks;
-                for chunk in chunks {
-                    for choise in chunk.choices {
-                        if let Some(content) = choise.delta.content {
-                            concatenated_content += &content;
-                            if !concatenated_content.is_empty() {
-                                yield concatenated_content.clone();
+            let mut round = 0;
+            let mut first_token = true;
+            loop {
+                let mut round_content = String::new();
+                let mut assembler = ToolCallAssembler::new();
+                let mut failed = false;
+                while let Some(item) = completions_stream.next().await {
+                    let chunks: Vec<CompletionsMessageChunk> = match item {
+                        Ok(v) => v,
+                        Err(error) => {
+                            info!("completions stream failed {:?}", error);
+                            metrics::increment("errors", &[("type", "completions")]);
+                            failed = true;
+                            break;
+                        }
+                    };
+                    for chunk in chunks {
+                        if let Some(ref chunk_usage) = chunk.usage {
+                            usage.lock().unwrap().add(chunk_usage);
+                        }
+                        for choise in chunk.choices {
+                            if let Some(tool_calls) = choise.delta.tool_calls {
+                                for tool_call in tool_calls {
+                                    assembler.push(tool_call);
+                                }
+                            }
+                            if let Some(content) = choise.delta.content {
+                                // as it arrives from the API, before the one second windows Slack is updated in
+                                if first_token && !content.is_empty() {
+                                    first_token = false;
+                                    metrics::observe("time_to_first_token_ms", &[("model", client.completions_model())], started.elapsed());
+                                }
+                                round_content += &content;
+                                concatenated_content += &content;
+                                if !concatenated_content.is_empty() {
+                                    yield concatenated_content.clone();
+                                }
                             }
                         }
                     }
                 }
+                // after a tool call the last thing yielded is its progress, which must not stay as the answer
+                if failed {
+                    if round > 0 {
+                        yield Self::tool_failure_text(&concatenated_content);
+                    }
+                    break;
+                }
+                let tool_calls = assembler.finish();
+                if tool_calls.is_empty() {
+                    if round > 0 && round_content.trim().is_empty() {
+                        info!("completions after tool calls returned no content");
+                        yield Self::tool_failure_text(&concatenated_content);
+                    }
+                    break;
+                }
+                round += 1;
+                messages.push(CompletionsRequestMessage {
+                    role: "assistant".into(),
+                    content: Self::text_content(round_content),
+                    tool_calls: Some(tool_calls.clone()),
+                    tool_call_id: None,
+                });
+                for tool_call in tool_calls {
+                    tools_used.lock().unwrap().push(tool_call.function.name.clone());
+                    let progress = tools.progress(&tool_call);
+                    if concatenated_content.is_empty() {
+                        yield progress;
+                    } else {
+                        yield format!("{}\n\n{}", concatenated_content, progress);
+                    }
+                    let result = tools.execute(&tool_call).await;
+                    messages.push(CompletionsRequestMessage {
+                        role: "tool".into(),
+                        content: Self::text_content(result),
+                        tool_calls: None,
+                        tool_call_id: Some(tool_call.id),
+                    });
+                }
+                if !concatenated_content.is_empty() {
+                    concatenated_content += "\n\n";
+                }
+                // out of rounds, ask for an answer without further tool calls
+                let tool_choice = (round >= MAX_TOOL_ROUNDS).then(|| "none".to_string());
+                completions_stream = match client.completions(messages.clone(), tools.definitions(), tool_choice).await {
+                    Ok(v) => v,
+                    Err(error) => {
+                        info!("completions after tool calls failed {:?}", error);
+                        metrics::increment("errors", &[("type", "completions")]);
+                        yield Self::tool_failure_text(&concatenated_content);
+                        break;
+                    }
+                };
             }
         };
         Ok(content_stream)
     }
+
+    // replaces the tool progress with the content answered before the tool calls and TOOL_FAILURE_TEXT
+    fn tool_failure_text(concatenated_content: &str) -> String {
+        let answered = concatenated_content.trim_end();
+        if answered.is_empty() {
+            TOOL_FAILURE_TEXT.to_string()
+        } else {
+            format!("{}\n\n{}", answered, TOOL_FAILURE_TEXT)
+        }
+    }
+
+    fn text_content(text: String) -> Vec<CompletionsRequestMessageContent> {
+        if text.is_empty() {
+            return vec![]
+        }
+        vec![
+            CompletionsRequestMessageContent {
+                r#type: "text".into(),
+                text: Some(text),
+                image_url: None,
+            }
+        ]
+    }
 }
@@ -0,0 +1,132 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+use cores::config::ConfigLoader;
+use cores::installations::{InstallationStore, load_installation_store};
+use cores::secrets::Secret;
+use cores::slack::AdminUsers;
+
+use crate::accounting::{PriceTable, UsageLedger, load_usage_ledger};
+use crate::audit::AuditTrail;
+use crate::generations::{GenerationStore, load_generation_store};
+use crate::memory::{MemoryStore, load_memory_store};
+use crate::message::PERSONA;
+use crate::moderation::{ModerationConfig, Moderator};
+use crate::quotas::{Quotas, Tokenizer};
+use crate::openai_client::{OpenAIClient, DEFAULT_COMPLETIONS_MODEL};
+use crate::queue_consumer::QueueConfig;
+use crate::retrieval::Retriever;
+use crate::safety::SafetyConfig;
+
+// YOSHINO_WORKER_MODE
+pub enum WorkerMode {
+    // invoked by web or the SQS event source mapping
+    Lambda,
+    // runs inside the web runtime's execution environment
+    Extension,
+    // consumes the file queue as a long-running process
+    Queue,
+}
+
+impl FromStr for WorkerMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lambda" => Ok(Self::Lambda),
+            "extension" => Ok(Self::Extension),
+            "queue" => Ok(Self::Queue),
+            _ => bail!("expected lambda, extension or queue"),
+        }
+    }
+}
+
+// loaded once on startup, see ConfigLoader for where the values come from
+pub struct Config {
+    pub mode: WorkerMode,
+    // has to support both images and tool calls
+    pub completions_model: String,
+    pub openai_client: Arc<OpenAIClient>,
+    // serve a workspace that was not installed through OAuth
+    pub slack_bot_token: Option<Secret>,
+    pub slack_user_token: Option<Secret>,
+    // the tokens of the workspaces installed through OAuth
+    pub installation_store: Arc<dyn InstallationStore>,
+    // grounds answers in the documents at YOSHINO_CORPUS_DIR
+    pub retriever: Option<Arc<Retriever>>,
+    pub memory_store: Arc<dyn MemoryStore>,
+    pub generation_store: Arc<dyn GenerationStore>,
+    pub quotas: Arc<Quotas>,
+    // counts the tokens of streams stopped before the API reported their usage
+    pub tokenizer: Arc<Tokenizer>,
+    pub usage_ledger: Arc<dyn UsageLedger>,
+    pub price_table: Arc<PriceTable>,
+    // may check usage and export the usage and audit records
+    pub admin_users: AdminUsers,
+    pub spoken_replies: bool,
+    pub reanswer_on_edit: bool,
+    // prompts and answers are not moderated without it
+    pub moderator: Option<Arc<Moderator>>,
+    pub safety: SafetyConfig,
+    pub audit_trail: Arc<AuditTrail>,
+    pub queue: QueueConfig,
+}
+
+impl Config {
+    pub async fn load() -> Result<Arc<Self>> {
+        let mut loader = ConfigLoader::from_env().await?;
+        let mode = loader.parse("YOSHINO_WORKER_MODE", WorkerMode::Lambda);
+        let openai_api_key = loader.required_refreshed_secret("OPENAI_API_KEY").await;
+        let completions_model = loader.optional("YOSHINO_COMPLETIONS_MODEL")
+            .unwrap_or_else(|| DEFAULT_COMPLETIONS_MODEL.into());
+        let slack_bot_token = loader.optional_refreshed_secret("SLACK_BOT_TOKEN").await;
+        let slack_user_token = loader.optional_refreshed_secret("SLACK_USER_TOKEN").await;
+        let installation_store = load_installation_store(&mut loader).await;
+        let retriever = Retriever::load(&mut loader, &openai_api_key);
+        let memory_store = load_memory_store(&mut loader).await;
+        let generation_store = load_generation_store(&mut loader).await;
+        let quotas = Quotas::load(&mut loader).await;
+        let usage_ledger = load_usage_ledger(&mut loader).await;
+        let price_table = PriceTable::load(&mut loader);
+        // an unpriced model would be recorded as free
+        if !price_table.has_price(&completions_model) {
+            loader.invalid(format!("YOSHINO_COMPLETIONS_MODEL {} has no price, add it to the file at YOSHINO_PRICE_TABLE_PATH", completions_model));
+        }
+        let admin_users = AdminUsers::load(&mut loader);
+        let spoken_replies = loader.flag("YOSHINO_SPOKEN_REPLIES");
+        let reanswer_on_edit = loader.flag("YOSHINO_REANSWER_ON_EDIT");
+        let moderation = ModerationConfig::load(&mut loader);
+        let safety = SafetyConfig::load(&mut loader).await;
+        let audit_trail = AuditTrail::load(&mut loader, PERSONA).await;
+        let queue = QueueConfig::load(&mut loader);
+        loader.finish()?;
+        let tokenizer = Tokenizer::new(&completions_model)?;
+        let openai_client = OpenAIClient::new(openai_api_key, &completions_model)?;
+        let moderator = moderation.as_ref()
+            .map(|v| Moderator::new(v, &openai_client));
+        let this = Self {
+            mode,
+            completions_model,
+            openai_client,
+            slack_bot_token,
+            slack_user_token,
+            installation_store,
+            retriever,
+            memory_store,
+            generation_store,
+            quotas,
+            tokenizer,
+            usage_ledger,
+            price_table,
+            admin_users,
+            spoken_replies,
+            reanswer_on_edit,
+            moderator,
+            safety,
+            audit_trail,
+            queue,
+        };
+        Ok(Arc::new(this))
+    }
+}
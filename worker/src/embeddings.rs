@@ -0,0 +1,185 @@
+
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+use cores::config::ConfigLoader;
+use cores::secrets::Secret;
+use reqwest::{self, Client};
+use serde::{Deserialize, Serialize};
+
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    // identifies the vector space, an index built by another model is rebuilt
+    fn model(&self) -> String;
+    async fn embed(&self, inputs: &[String]) -> Result<Vec<Vec<f32>>>;
+}
+
+// YOSHINO_EMBEDDER selects the implementation
+// * `openai` (default) the OpenAI embeddings endpoint with the worker's API key
+// * `http` any OpenAI compatible endpoint at YOSHINO_EMBEDDINGS_URL, e.g. a local model server
+// * `hashing` a deterministic offline embedder
+pub fn load_embedder(loader: &mut ConfigLoader, openai_api_key: &Secret) -> Option<Arc<dyn Embedder>> {
+    let kind = loader.optional("YOSHINO_EMBEDDER").unwrap_or_else(|| "openai".into());
+    let embedder: Arc<dyn Embedder> = match kind.as_str() {
+        "openai" => {
+            let model = loader.optional("YOSHINO_EMBEDDINGS_MODEL").unwrap_or_else(|| "text-embedding-3-small".into());
+            HttpEmbedder::new("https://api.openai.com/v1/embeddings", &model, Some(openai_api_key.clone()))
+        },
+        "http" => {
+            let url = loader.required("YOSHINO_EMBEDDINGS_URL");
+            let model = loader.required("YOSHINO_EMBEDDINGS_MODEL");
+            let api_key = loader.optional("YOSHINO_EMBEDDINGS_API_KEY").map(Secret::fixed);
+            HttpEmbedder::new(&url, &model, api_key)
+        },
+        "hashing" => HashingEmbedder::new(256),
+        _ => {
+            loader.invalid(format!("YOSHINO_EMBEDDER has an invalid value {:?}: expected openai, http or hashing", kind));
+            return None
+        },
+    };
+    Some(embedder)
+}
+
+#[derive(Serialize)]
+struct EmbeddingsRequestBody<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsResponseBody {
+    data: Vec<EmbeddingsResponseData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsResponseData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+// https://platform.openai.com/docs/api-reference/embeddings/create
+pub struct HttpEmbedder {
+    client: Client,
+    url: String,
+    model: String,
+    api_key: Option<Secret>,
+}
+
+impl HttpEmbedder {
+    pub fn new(url: &str, model: &str, api_key: Option<Secret>) -> Arc<Self> {
+        let client = reqwest::Client::new();
+        let this = Self {
+            client,
+            url: url.into(),
+            model: model.into(),
+            api_key,
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl Embedder for HttpEmbedder {
+    fn model(&self) -> String {
+        self.model.clone()
+    }
+
+    async fn embed(&self, inputs: &[String]) -> Result<Vec<Vec<f32>>> {
+        let request_body = EmbeddingsRequestBody {
+            model: &self.model,
+            input: inputs,
+        };
+        let mut request = self.client.post(&self.url)
+            .header("Content-type", "application/json; charset=utf-8");
+        if let Some(ref api_key) = self.api_key {
+            request = request.header("Authorization", ["Bearer", &api_key.get().await].join(" "));
+        }
+        let response = request
+            .json(&request_body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let text = response.text().await?;
+            bail!("embeddings response failure. {}", text);
+        }
+        let mut response: EmbeddingsResponseBody = response.json().await?;
+        response.data.sort_by_key(|v| v.index);
+        let embeddings = response.data.into_iter()
+            .map(|v| v.embedding)
+            .collect();
+        Ok(embeddings)
+    }
+}
+
+// feature hashing over words and character bigrams,
+// bigrams keep it usable for Japanese text which has no word boundaries
+pub struct HashingEmbedder {
+    dimensions: usize,
+}
+
+impl HashingEmbedder {
+    pub fn new(dimensions: usize) -> Arc<Self> {
+        Arc::new(Self { dimensions })
+    }
+
+    fn embed_one(&self, input: &str) -> Vec<f32> {
+        let mut vector = vec![0f32; self.dimensions];
+        let lowercased = input.to_lowercase();
+        let words = lowercased
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|v| !v.is_empty())
+            .map(String::from);
+        let chars: Vec<char> = lowercased.chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        let bigrams = chars.windows(2)
+            .map(|v| v.iter().collect::<String>());
+        for feature in words.chain(bigrams) {
+            let hash = fnv1a(feature.as_bytes());
+            let index = (hash % self.dimensions as u64) as usize;
+            let sign = if (hash >> 63) == 0 { 1.0 } else { -1.0 };
+            vector[index] += sign;
+        }
+        normalize(&mut vector);
+        vector
+    }
+}
+
+#[async_trait]
+impl Embedder for HashingEmbedder {
+    fn model(&self) -> String {
+        format!("hashing-{}", self.dimensions)
+    }
+
+    async fn embed(&self, inputs: &[String]) -> Result<Vec<Vec<f32>>> {
+        let embeddings = inputs.iter()
+            .map(|v| self.embed_one(v))
+            .collect();
+        Ok(embeddings)
+    }
+}
+
+// stable across builds unlike std's DefaultHasher, indexes stay valid after upgrades
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm_a = a.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0
+    }
+    dot / (norm_a * norm_b)
+}
@@ -0,0 +1,63 @@
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use cores::ipc::{InvokeMessage, IpcHandler, IpcServer, IPC_EXTENSION_ENDPOINT};
+use cores::metrics::flush_metrics;
+use lambda_extension::{service_fn, Error, Extension, LambdaEvent, NextEvent};
+use tokio::time::timeout;
+use tracing::info;
+
+use crate::config::Config;
+use crate::message::MessageHandle;
+
+// Lambda sends SHUTDOWN about 2 seconds before terminating the execution environment
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_millis(1500);
+
+struct MessageIpcHandler {
+    config: Arc<Config>,
+}
+
+#[async_trait]
+impl IpcHandler for MessageIpcHandler {
+    async fn handle(&self, message: InvokeMessage) -> Result<()> {
+        let handle = MessageHandle::new(&self.config, message.team_id.as_deref()).await?;
+        let result = handle.handle_message(message).await;
+        flush_metrics();
+        result
+    }
+}
+
+// Runs the worker as an external extension next to the web runtime.
+// The web runtime hands messages off over IPC_EXTENSION_ENDPOINT and flushes before the invocation ends,
+// which keeps the execution environment unfrozen while messages are handled.
+// https://docs.aws.amazon.com/lambda/latest/dg/runtimes-extensions-api.html
+pub async fn run_extension(config: Arc<Config>) -> Result<(), Error> {
+    let server = IpcServer::bind(IPC_EXTENSION_ENDPOINT).await?;
+    let task_tracker = server.task_tracker();
+    tokio::spawn(async move {
+        if let Err(error) = server.serve(Arc::new(MessageIpcHandler { config })).await {
+            info!("ipc server terminated {:?}", error);
+        }
+    });
+    let events_processor = service_fn(move |event: LambdaEvent| {
+        let task_tracker = task_tracker.clone();
+        async move {
+            if let NextEvent::Shutdown(_) = event.next {
+                task_tracker.close();
+                info!("draining {} messages", task_tracker.len());
+                if timeout(SHUTDOWN_DRAIN_TIMEOUT, task_tracker.wait()).await.is_err() {
+                    info!("shutdown drain timed out with {} messages", task_tracker.len());
+                }
+            }
+            Ok::<(), Error>(())
+        }
+    });
+    Extension::new()
+        .with_events(&["SHUTDOWN"])
+        .with_events_processor(events_processor)
+        .run()
+        .await
+}
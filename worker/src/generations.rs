@@ -0,0 +1,300 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Result, Context, bail};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{Duration, Utc};
+use cores::config::{ConfigLoader, StoreKind};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+// the latest reply generated for a prompt, one per thread
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Generation {
+    pub id: String,
+    pub channel: String,
+    pub thread_ts: String,
+    // the user's message being answered
+    pub prompt_ts: String,
+    // the bot's message the answer streams into
+    pub reply_ts: String,
+    // kept after the reply is complete so that an edit of the prompt can answer again
+    #[serde(default)]
+    pub finished: bool,
+}
+
+// Tracks the latest generation of each thread so that whoever handles a later event of the thread can stop or redo it.
+// A generation in flight is cancelled once it is no longer the current one of its thread.
+#[async_trait]
+pub trait GenerationStore: Send + Sync {
+    // replaces the current generation of the thread, which cancels it
+    async fn begin(&self, channel: &str, thread_ts: &str, prompt_ts: &str, reply_ts: &str) -> Result<Generation>;
+    async fn current(&self, channel: &str, thread_ts: &str) -> Result<Option<Generation>>;
+    async fn is_current(&self, generation: &Generation) -> Result<bool>;
+    // no-op unless the generation is still the current one
+    async fn cancel(&self, generation: &Generation) -> Result<()>;
+    // keeps the generation as the thread's latest answer
+    async fn finish(&self, generation: &Generation) -> Result<()>;
+}
+
+// The store has to be shared by every worker instance, the events of a thread are handled wherever they land.
+// YOSHINO_GENERATION_STORE selects the implementation
+// * `dynamodb` (default) the table YOSHINO_GENERATION_TABLE, YOSHINO_GENERATION_DYNAMODB_ENDPOINT points at DynamoDB Local
+// * `file` one JSON file per thread in YOSHINO_GENERATION_DIR
+pub async fn load_generation_store(loader: &mut ConfigLoader) -> Arc<dyn GenerationStore> {
+    match loader.parse("YOSHINO_GENERATION_STORE", StoreKind::DynamoDb) {
+        StoreKind::DynamoDb => {
+            let table = loader.optional("YOSHINO_GENERATION_TABLE")
+                .unwrap_or_else(|| "yoshino-radio-generations".into());
+            let endpoint = loader.optional("YOSHINO_GENERATION_DYNAMODB_ENDPOINT");
+            DynamoDbGenerationStore::new(&table, endpoint.as_deref()).await
+        },
+        StoreKind::File => FileGenerationStore::new(PathBuf::from(loader.required("YOSHINO_GENERATION_DIR"))),
+    }
+}
+
+fn new_generation(channel: &str, thread_ts: &str, prompt_ts: &str, reply_ts: &str) -> Generation {
+    Generation {
+        id: Uuid::new_v4().to_string(),
+        channel: channel.into(),
+        thread_ts: thread_ts.into(),
+        prompt_ts: prompt_ts.into(),
+        reply_ts: reply_ts.into(),
+        finished: false,
+    }
+}
+
+pub struct FileGenerationStore {
+    dir: PathBuf,
+    // serializes read-modify-write cycles within the process
+    lock: Mutex<()>,
+}
+
+impl FileGenerationStore {
+    pub fn new(dir: PathBuf) -> Arc<Self> {
+        let this = Self {
+            dir,
+            lock: Mutex::new(()),
+        };
+        Arc::new(this)
+    }
+
+    fn path(&self, channel: &str, thread_ts: &str) -> PathBuf {
+        // channel IDs are alphanumeric and timestamps are digits with a dot, anything else is dropped
+        let key: String = format!("{}_{}", channel, thread_ts).chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.')
+            .collect();
+        self.dir.join(format!("{}.json", key))
+    }
+
+    fn read(&self, channel: &str, thread_ts: &str) -> Result<Option<Generation>> {
+        let path = self.path(channel, thread_ts);
+        if !path.exists() {
+            return Ok(None)
+        }
+        let text = fs::read_to_string(&path)?;
+        let generation = serde_json::from_str(&text)
+            .with_context(|| format!("broken generation file {:?}", path))?;
+        Ok(Some(generation))
+    }
+
+    fn write(&self, generation: &Generation) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(&generation.channel, &generation.thread_ts), serde_json::to_string(generation)?)?;
+        Ok(())
+    }
+
+    fn remove_if_current(&self, generation: &Generation) -> Result<()> {
+        let current = self.read(&generation.channel, &generation.thread_ts)?;
+        if current.is_some_and(|v| v.id == generation.id) {
+            fs::remove_file(self.path(&generation.channel, &generation.thread_ts))?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl GenerationStore for FileGenerationStore {
+    async fn begin(&self, channel: &str, thread_ts: &str, prompt_ts: &str, reply_ts: &str) -> Result<Generation> {
+        let _guard = self.lock.lock().await;
+        let generation = new_generation(channel, thread_ts, prompt_ts, reply_ts);
+        self.write(&generation)?;
+        Ok(generation)
+    }
+
+    async fn current(&self, channel: &str, thread_ts: &str) -> Result<Option<Generation>> {
+        let _guard = self.lock.lock().await;
+        self.read(channel, thread_ts)
+    }
+
+    async fn is_current(&self, generation: &Generation) -> Result<bool> {
+        let _guard = self.lock.lock().await;
+        let current = self.read(&generation.channel, &generation.thread_ts)?;
+        Ok(current.is_some_and(|v| v.id == generation.id))
+    }
+
+    async fn cancel(&self, generation: &Generation) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        self.remove_if_current(generation)
+    }
+
+    async fn finish(&self, generation: &Generation) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let current = self.read(&generation.channel, &generation.thread_ts)?;
+        if let Some(mut current) = current.filter(|v| v.id == generation.id) {
+            current.finished = true;
+            self.write(&current)?;
+        }
+        Ok(())
+    }
+}
+
+// Expects a table with the string partition key `thread`, `channel:thread_ts`.
+// The generation is kept as JSON in `generation` next to its `generation_id`, which conditions cancel and finish
+// so that they never touch a generation that has since replaced it.
+// Enable TTL on `expires_at` to drop the generations of old threads.
+pub struct DynamoDbGenerationStore {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+}
+
+// an edit of a prompt older than this is not answered again
+const GENERATION_TTL_DAYS: i64 = 7;
+
+impl DynamoDbGenerationStore {
+    pub async fn new(table: &str, endpoint: Option<&str>) -> Arc<Self> {
+        let client = cores::dynamodb::client(endpoint).await;
+        let this = Self {
+            client,
+            table: table.into(),
+        };
+        Arc::new(this)
+    }
+
+    fn key(channel: &str, thread_ts: &str) -> AttributeValue {
+        AttributeValue::S(format!("{}:{}", channel, thread_ts))
+    }
+
+    async fn put(&self, generation: &Generation, condition: Option<&str>) -> Result<bool> {
+        let expires_at = Utc::now() + Duration::days(GENERATION_TTL_DAYS);
+        let mut request = self.client.put_item()
+            .table_name(&self.table)
+            .item("thread", Self::key(&generation.channel, &generation.thread_ts))
+            .item("generation_id", AttributeValue::S(generation.id.clone()))
+            .item("generation", AttributeValue::S(serde_json::to_string(generation)?))
+            .item("expires_at", AttributeValue::N(expires_at.timestamp().to_string()));
+        if let Some(condition) = condition {
+            request = request.condition_expression(condition)
+                .expression_attribute_values(":id", AttributeValue::S(generation.id.clone()));
+        }
+        match request.send().await {
+            Ok(_) => Ok(true),
+            Err(error) => {
+                let error = error.into_service_error();
+                if error.is_conditional_check_failed_exception() {
+                    return Ok(false)
+                }
+                Err(error.into())
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl GenerationStore for DynamoDbGenerationStore {
+    async fn begin(&self, channel: &str, thread_ts: &str, prompt_ts: &str, reply_ts: &str) -> Result<Generation> {
+        let generation = new_generation(channel, thread_ts, prompt_ts, reply_ts);
+        self.put(&generation, None).await?;
+        Ok(generation)
+    }
+
+    async fn current(&self, channel: &str, thread_ts: &str) -> Result<Option<Generation>> {
+        let output = self.client.get_item()
+            .table_name(&self.table)
+            .key("thread", Self::key(channel, thread_ts))
+            .consistent_read(true)
+            .send()
+            .await?;
+        let Some(item) = output.item else { return Ok(None) };
+        let Some(AttributeValue::S(text)) = item.get("generation") else {
+            bail!("generation of {}:{} without the generation attribute", channel, thread_ts);
+        };
+        Ok(Some(serde_json::from_str(text)?))
+    }
+
+    async fn is_current(&self, generation: &Generation) -> Result<bool> {
+        let output = self.client.get_item()
+            .table_name(&self.table)
+            .key("thread", Self::key(&generation.channel, &generation.thread_ts))
+            .projection_expression("generation_id")
+            .consistent_read(true)
+            .send()
+            .await?;
+        let id = output.item.as_ref()
+            .and_then(|v| v.get("generation_id"))
+            .and_then(|v| v.as_s().ok());
+        Ok(id == Some(&generation.id))
+    }
+
+    async fn cancel(&self, generation: &Generation) -> Result<()> {
+        let result = self.client.delete_item()
+            .table_name(&self.table)
+            .key("thread", Self::key(&generation.channel, &generation.thread_ts))
+            .condition_expression("generation_id = :id")
+            .expression_attribute_values(":id", AttributeValue::S(generation.id.clone()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                let error = error.into_service_error();
+                if error.is_conditional_check_failed_exception() {
+                    return Ok(())
+                }
+                Err(error.into())
+            },
+        }
+    }
+
+    async fn finish(&self, generation: &Generation) -> Result<()> {
+        let finished = Generation {
+            finished: true,
+            ..generation.clone()
+        };
+        self.put(&finished, Some("generation_id = :id")).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // re-answering an edit relies on these, whichever store keeps the generations
+    #[tokio::test]
+    async fn a_newer_generation_takes_over_the_thread() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileGenerationStore::new(dir.path().to_path_buf());
+        let first = store.begin("D0123", "100.000001", "100.000001", "100.000002").await.unwrap();
+        let second = store.begin("D0123", "100.000001", "100.000003", "100.000002").await.unwrap();
+        assert!(!store.is_current(&first).await.unwrap());
+        assert!(store.is_current(&second).await.unwrap());
+
+        // the replaced generation can neither finish nor cancel its successor
+        store.finish(&first).await.unwrap();
+        store.cancel(&first).await.unwrap();
+        let current = store.current("D0123", "100.000001").await.unwrap().unwrap();
+        assert_eq!((current.id.as_str(), current.finished), (second.id.as_str(), false));
+
+        // a finished answer is kept for an edit to find
+        store.finish(&second).await.unwrap();
+        let current = store.current("D0123", "100.000001").await.unwrap().unwrap();
+        assert_eq!((current.prompt_ts.as_str(), current.reply_ts.as_str(), current.finished), ("100.000003", "100.000002", true));
+
+        store.cancel(&second).await.unwrap();
+        assert!(store.current("D0123", "100.000001").await.unwrap().is_none());
+    }
+}
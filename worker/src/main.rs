@@ -1,43 +1,79 @@
+use std::sync::Arc;
+
 use cores::ipc::InvokeMessage;
+use cores::metrics::{init_metrics, flush_metrics};
+use cores::telemetry::{init_tracing, flush_tracing};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tracing::info;
 
+use crate::config::{Config, WorkerMode};
 use crate::message::MessageHandle;
+use crate::queue_consumer::{SqsEvent, handle_sqs_event};
 
+mod config;
 mod message;
 mod slack_client;
 mod openai_client;
 mod buffer_stream;
 mod completions;
 mod images;
+mod speech;
+mod tools;
+mod slack_tools;
+mod embeddings;
+mod retrieval;
+mod memory;
+mod commands;
+mod extension;
+mod queue_consumer;
+mod generations;
+mod quotas;
+mod accounting;
+mod moderation;
+mod safety;
+mod audit;
 
 #[derive(Serialize)]
 struct Response {
     req_id: String,
 }
 
-async fn function_handler(event: LambdaEvent<InvokeMessage>) -> Result<Response, Error> {
-    let message = event.payload;
-    let handle = MessageHandle::new()?;
-    info!("got body {}", message.body);
-    handle.handle_message(message).await?;
+// invoked either directly with an InvokeMessage or with a batch from the SQS event source mapping
+async fn function_handler(event: LambdaEvent<Value>, config: &Arc<Config>) -> Result<Value, Error> {
+    if event.payload.get("Records").is_some() {
+        let sqs_event: SqsEvent = serde_json::from_value(event.payload)?;
+        let resp = handle_sqs_event(config, sqs_event).await;
+        flush_metrics();
+        flush_tracing().await;
+        return Ok(serde_json::to_value(resp)?)
+    }
+    let message = InvokeMessage::try_from(event.payload)?;
+    let handle = MessageHandle::new(config, message.team_id.as_deref()).await?;
+    info!(trace_id = message.trace_id, schema_version = %message.schema_version, "got message");
+    let result = handle.handle_message(message).await;
+    // the execution environment is frozen once the response is returned
+    flush_metrics();
+    flush_tracing().await;
+    result?;
     let resp = Response {
         req_id: event.context.request_id,
     };
-    Ok(resp)
+    Ok(serde_json::to_value(resp)?)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        // disable printing the name of the module in every log line.
-        .with_target(false)
-        // disabling time is handy because CloudWatch will add the ingestion time.
-        .without_time()
-        .init();
-
-    run(service_fn(function_handler)).await
+    init_tracing("yoshino-radio-worker")?;
+    init_metrics("yoshino-radio-worker");
+    // fails fast on a missing or invalid setting instead of on the first message
+    let config = Config::load().await?;
+
+    match config.mode {
+        WorkerMode::Extension => extension::run_extension(config).await,
+        WorkerMode::Queue => Ok(queue_consumer::run_queue_consumer(config).await?),
+        WorkerMode::Lambda => run(service_fn(|event| function_handler(event, &config))).await,
+    }
 }
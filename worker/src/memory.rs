@@ -0,0 +1,294 @@
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Result, Context, bail};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use cores::config::{ConfigLoader, StoreKind};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use crate::tools::Tool;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MemoryFact {
+    pub id: u64,
+    pub text: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// long-term facts about a Slack user, shared across conversations
+#[async_trait]
+pub trait MemoryStore: Send + Sync {
+    async fn list(&self, user: &str) -> Result<Vec<MemoryFact>>;
+    async fn add(&self, user: &str, text: &str) -> Result<MemoryFact>;
+    // returns false when there was no such fact
+    async fn forget(&self, user: &str, id: u64) -> Result<bool>;
+    async fn forget_all(&self, user: &str) -> Result<()>;
+}
+
+// YOSHINO_MEMORY_STORE selects the implementation
+// * `dynamodb` (default) the table YOSHINO_MEMORY_TABLE, YOSHINO_MEMORY_DYNAMODB_ENDPOINT points at DynamoDB Local
+// * `file` one JSON file per user in YOSHINO_MEMORY_DIR
+pub async fn load_memory_store(loader: &mut ConfigLoader) -> Arc<dyn MemoryStore> {
+    match loader.parse("YOSHINO_MEMORY_STORE", StoreKind::DynamoDb) {
+        StoreKind::DynamoDb => {
+            let table = loader.optional("YOSHINO_MEMORY_TABLE")
+                .unwrap_or_else(|| "yoshino-radio-memory".into());
+            let endpoint = loader.optional("YOSHINO_MEMORY_DYNAMODB_ENDPOINT");
+            DynamoDbMemoryStore::new(&table, endpoint.as_deref()).await
+        },
+        StoreKind::File => FileMemoryStore::new(PathBuf::from(loader.required("YOSHINO_MEMORY_DIR"))),
+    }
+}
+
+pub struct FileMemoryStore {
+    dir: PathBuf,
+    // serializes read-modify-write cycles within the process
+    lock: Mutex<()>,
+}
+
+impl FileMemoryStore {
+    pub fn new(dir: PathBuf) -> Arc<Self> {
+        let this = Self {
+            dir,
+            lock: Mutex::new(()),
+        };
+        Arc::new(this)
+    }
+
+    fn path(&self, user: &str) -> PathBuf {
+        // user IDs are alphanumeric, anything else is dropped to keep the path inside the directory
+        let user: String = user.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect();
+        self.dir.join(format!("{}.json", user))
+    }
+
+    fn read(&self, user: &str) -> Result<Vec<MemoryFact>> {
+        let path = self.path(user);
+        if !path.exists() {
+            return Ok(vec![])
+        }
+        let text = fs::read_to_string(&path)?;
+        let facts = serde_json::from_str(&text)
+            .with_context(|| format!("broken memory file {:?}", path))?;
+        Ok(facts)
+    }
+
+    fn write(&self, user: &str, facts: &[MemoryFact]) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(user), serde_json::to_string(facts)?)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MemoryStore for FileMemoryStore {
+    async fn list(&self, user: &str) -> Result<Vec<MemoryFact>> {
+        let _guard = self.lock.lock().await;
+        self.read(user)
+    }
+
+    async fn add(&self, user: &str, text: &str) -> Result<MemoryFact> {
+        let _guard = self.lock.lock().await;
+        let mut facts = self.read(user)?;
+        let id = facts.iter().map(|v| v.id).max().unwrap_or(0) + 1;
+        let fact = MemoryFact {
+            id,
+            text: text.trim().into(),
+            created_at: Utc::now(),
+        };
+        facts.push(fact.clone());
+        self.write(user, &facts)?;
+        Ok(fact)
+    }
+
+    async fn forget(&self, user: &str, id: u64) -> Result<bool> {
+        let _guard = self.lock.lock().await;
+        let mut facts = self.read(user)?;
+        let count = facts.len();
+        facts.retain(|v| v.id != id);
+        if facts.len() == count {
+            return Ok(false)
+        }
+        self.write(user, &facts)?;
+        Ok(true)
+    }
+
+    async fn forget_all(&self, user: &str) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        self.write(user, &[])
+    }
+}
+
+// expects a table with the string partition key `user`,
+// the facts are kept as JSON in `facts` and `version` guards concurrent updates
+pub struct DynamoDbMemoryStore {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+}
+
+// attempts of a read-modify-write cycle before giving up to a concurrent writer
+const UPDATE_ATTEMPTS: usize = 5;
+
+impl DynamoDbMemoryStore {
+    pub async fn new(table: &str, endpoint: Option<&str>) -> Arc<Self> {
+        let client = cores::dynamodb::client(endpoint).await;
+        let this = Self {
+            client,
+            table: table.into(),
+        };
+        Arc::new(this)
+    }
+
+    async fn read(&self, user: &str) -> Result<(Vec<MemoryFact>, u64)> {
+        let output = self.client.get_item()
+            .table_name(&self.table)
+            .key("user", AttributeValue::S(user.into()))
+            .consistent_read(true)
+            .send()
+            .await?;
+        let Some(item) = output.item else { return Ok((vec![], 0)) };
+        let Some(AttributeValue::S(text)) = item.get("facts") else {
+            bail!("memory of {} without the facts attribute", user);
+        };
+        let version = match item.get("version") {
+            Some(AttributeValue::N(version)) => version.parse()?,
+            _ => 0,
+        };
+        Ok((serde_json::from_str(text)?, version))
+    }
+
+    // applies `update` to the facts and writes them back unless another writer got there first, then retries;
+    // `update` returns None when there is nothing to write
+    async fn update<T>(&self, user: &str, update: impl Fn(&mut Vec<MemoryFact>) -> Option<T> + Send + Sync) -> Result<Option<T>> where T: Send {
+        for _ in 0..UPDATE_ATTEMPTS {
+            let (mut facts, version) = self.read(user).await?;
+            let Some(result) = update(&mut facts) else { return Ok(None) };
+            let request = self.client.put_item()
+                .table_name(&self.table)
+                .item("user", AttributeValue::S(user.into()))
+                .item("facts", AttributeValue::S(serde_json::to_string(&facts)?))
+                .item("version", AttributeValue::N((version + 1).to_string()));
+            let request = if version == 0 {
+                request.condition_expression("attribute_not_exists(#user)")
+                    .expression_attribute_names("#user", "user")
+            } else {
+                request.condition_expression("version = :version")
+                    .expression_attribute_values(":version", AttributeValue::N(version.to_string()))
+            };
+            match request.send().await {
+                Ok(_) => return Ok(Some(result)),
+                Err(error) => {
+                    let error = error.into_service_error();
+                    if !error.is_conditional_check_failed_exception() {
+                        return Err(error.into())
+                    }
+                },
+            }
+        }
+        bail!("memory of {} kept changing while updating it", user)
+    }
+}
+
+#[async_trait]
+impl MemoryStore for DynamoDbMemoryStore {
+    async fn list(&self, user: &str) -> Result<Vec<MemoryFact>> {
+        let (facts, _) = self.read(user).await?;
+        Ok(facts)
+    }
+
+    async fn add(&self, user: &str, text: &str) -> Result<MemoryFact> {
+        let fact = self.update(user, |facts| {
+            let id = facts.iter().map(|v| v.id).max().unwrap_or(0) + 1;
+            let fact = MemoryFact {
+                id,
+                text: text.trim().into(),
+                created_at: Utc::now(),
+            };
+            facts.push(fact.clone());
+            Some(fact)
+        }).await?;
+        fact.context("memory was not added")
+    }
+
+    async fn forget(&self, user: &str, id: u64) -> Result<bool> {
+        let forgotten = self.update(user, |facts| {
+            let count = facts.len();
+            facts.retain(|v| v.id != id);
+            (facts.len() < count).then_some(())
+        }).await?;
+        Ok(forgotten.is_some())
+    }
+
+    async fn forget_all(&self, user: &str) -> Result<()> {
+        self.update(user, |facts| {
+            let forgotten = !facts.is_empty();
+            facts.clear();
+            forgotten.then_some(())
+        }).await?;
+        Ok(())
+    }
+}
+
+pub fn memories_prompt(facts: &[MemoryFact]) -> String {
+    let lines: Vec<String> = facts.iter()
+        .map(|v| format!("- {}", v.text))
+        .collect();
+    format!("そなたについて覚えていること（以前の会話で教えてもらったこと）:\n{}", lines.join("\n"))
+}
+
+// lets the model save facts the user shares in passing
+pub struct RememberTool {
+    store: Arc<dyn MemoryStore>,
+    user: String,
+}
+
+impl RememberTool {
+    pub fn new(store: &Arc<dyn MemoryStore>, user: &str) -> Arc<Self> {
+        let store = Arc::clone(store);
+        let this = Self {
+            store,
+            user: user.into(),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl Tool for RememberTool {
+    fn name(&self) -> &str {
+        "remember_fact"
+    }
+
+    fn description(&self) -> &str {
+        "Saves a lasting fact about the user (preferences, how to address them, ongoing goals) for future conversations. Do not save secrets or passing remarks."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "fact": { "type": "string", "description": "the fact in one short sentence" },
+            },
+            "required": ["fact"],
+        })
+    }
+
+    fn progress(&self) -> String {
+        "🔧 remembering…".into()
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<String> {
+        let fact = arguments.get("fact")
+            .and_then(|v| v.as_str())
+            .context("missing argument fact")?;
+        let fact = self.store.add(&self.user, fact).await?;
+        Ok(format!("saved as memory #{}", fact.id))
+    }
+}
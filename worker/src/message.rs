@@ -1,67 +1,116 @@
 
-use std::{sync::Arc, time::Duration};
+use std::{sync::Arc, time::{Duration, Instant}};
 
 use anyhow::{Result, Context};
-use cores::ipc::InvokeMessage;
-use serde::Deserialize;
-use tracing::info;
+use cores::events::{Event, EventCallback, File};
+use cores::ipc::{InvokeMessage, InvokePayload};
+use cores::metrics;
+use cores::telemetry::set_remote_parent;
+use tracing::{debug, info, info_span, Instrument};
 use futures_util::StreamExt;
 use crate::{
     slack_client::SlackClient, 
     openai_client::{OpenAIClient, CompletionsRequestMessage, CompletionsMessageChunk, CompletionsRequestMessageContent, CompletionsRequestMessageImageURL},
-    buffer_stream::periodic_buffered_window, images::ImageProcess};
+    buffer_stream::periodic_buffered_window, images::ImageProcess,
+    speech::{Transcriber, Synthesizer, is_audio_mimetype, transcribe_audio, with_transcript, speak}};
 
-use serde_json;
 use crate::completions::Completions;
+use crate::tools::ToolRegistry;
+use crate::slack_tools::{RequesterScope, register_slack_tools};
+use crate::retrieval::{Passage, passages_prompt, citations_footer};
+use crate::memory::{MemoryStore, RememberTool, memories_prompt};
+use crate::commands::Command;
+use crate::generations::GenerationStore;
+use crate::moderation::{Moderator, Stage, Verdict, BLOCKED_PROMPT_TEXT, BLOCKED_ANSWER_TEXT, WARNING_TEXT};
+use crate::safety::{Safety, EscalationSubject, recent_user_turns};
+use crate::audit::{AuditTrail, AuditOutcome, Interaction};
+use crate::quotas::{Quotas, QuotaSubject, QuotaExceeded, QuotaMetric};
+use crate::accounting::{UsageLedger, UsageRecord, PriceTable, usage_report, usage_csv, usage_json, image_tokens};
+use crate::openai_client::CompletionsUsage;
+use crate::config::Config;
+use chrono::Utc;
 
-#[derive(Deserialize)]
-struct AnyEventCallbackBody {
-    event: AnyEvent,
-}
-
-#[derive(Deserialize)]
-struct AnyEvent {
-    r#type: String,
-    subtype: Option<String>,
-    bot_id: Option<String>,
-}
-
-#[derive(Deserialize)]
-struct MessageEventCallbackBody {
-    event: MessageEvent,
-}
+// recorded with the usage of every answer
+pub(crate) const PERSONA: &str = "yoshino";
 
-#[derive(Deserialize)]
+// the prompt to answer
 struct MessageEvent {
+    user: Option<String>,
     text: String,
     channel: String,
     ts: String,
     thread_ts: Option<String>,
-    files: Option<Vec<MessageFile>>,
+    files: Vec<File>,
+}
+
+impl MessageEvent {
+    // https://api.slack.com/events/message.im
+    fn new(event: &Event) -> Result<Self> {
+        let this = Self {
+            user: event.user.clone(),
+            text: event.text.clone(),
+            channel: event.channel.clone().context("message without channel")?,
+            ts: event.ts.clone().context("message without ts")?,
+            thread_ts: event.thread_ts.clone(),
+            files: event.files.clone(),
+        };
+        Ok(this)
+    }
 }
 
-// https://api.slack.com/events/message/file_share
-// https://api.slack.com/types/file
-#[derive(Deserialize)]
-struct MessageFile {
-    id: String,
-    mimetype: String,
-    url_private_download: Option<String>,
-    size: u64,
+// a reply already in the thread that the answer goes into instead of a new one
+enum ExistingReply {
+    // the answer to the prompt before it was edited
+    Edited(String),
+    // left by an earlier attempt of the same message
+    Resumed(String),
+}
+
+// where a message came from, carried along for quotas and accounting
+#[derive(Clone, Copy)]
+struct Invocation<'a> {
+    trace_id: &'a str,
+    team_id: Option<&'a str>,
 }
 
 pub struct MessageHandle {
+    config: Arc<Config>,
     slack_client: Arc<SlackClient>,
     openai_client: Arc<OpenAIClient>,
+    transcriber: Arc<dyn Transcriber>,
+    synthesizer: Arc<dyn Synthesizer>,
+    memory_store: Arc<dyn MemoryStore>,
+    generation_store: Arc<dyn GenerationStore>,
+    quotas: Arc<Quotas>,
+    usage_ledger: Arc<dyn UsageLedger>,
+    price_table: Arc<PriceTable>,
+    moderator: Option<Arc<Moderator>>,
+    safety: Arc<Safety>,
+    audit_trail: Arc<AuditTrail>,
 }
 
 impl MessageHandle {
-    pub fn new() -> Result<Arc<Self>> {
-        let slack_client = SlackClient::new()?;
-        let openai_client = OpenAIClient::new()?;
+    // the Slack client acts in the workspace of team_id
+    pub async fn new(config: &Arc<Config>, team_id: Option<&str>) -> Result<Arc<Self>> {
+        let slack_client = SlackClient::for_team(config, team_id).await?;
+        let openai_client = Arc::clone(&config.openai_client);
+        let transcriber: Arc<dyn Transcriber> = openai_client.clone();
+        let synthesizer: Arc<dyn Synthesizer> = openai_client.clone();
+        let safety = Safety::new(&config.safety, &openai_client, &slack_client);
         let this = Self {
+            config: Arc::clone(config),
             slack_client,
             openai_client,
+            transcriber,
+            synthesizer,
+            memory_store: Arc::clone(&config.memory_store),
+            generation_store: Arc::clone(&config.generation_store),
+            quotas: Arc::clone(&config.quotas),
+            usage_ledger: Arc::clone(&config.usage_ledger),
+            price_table: Arc::clone(&config.price_table),
+            moderator: config.moderator.clone(),
+            safety,
+            audit_trail: Arc::clone(&config.audit_trail),
         };
         let this = Arc::new(this);
         Ok(this)
@@ -69,52 +118,261 @@ impl MessageHandle {
 
     // https://api.slack.com/events/message.im
     pub async fn handle_message(&self, message: InvokeMessage) -> Result<()> {
-        info!("worker received {}, {}", message.event_type, message.body);
-        match message.event_type.as_str() {
-            "event_callback" => self.handle_slack_event_callback(&message.body).await,
-            _ => Ok(())
+        let span = info_span!("message", trace_id = message.trace_id, team_id = message.team_id);
+        set_remote_parent(&span, message.traceparent.as_deref());
+        self.handle_payload(message).instrument(span).await
+    }
+
+    async fn handle_payload(&self, message: InvokeMessage) -> Result<()> {
+        let invocation = Invocation {
+            trace_id: &message.trace_id,
+            team_id: message.team_id.as_deref(),
+        };
+        let result = match message.payload {
+            InvokePayload::EventCallback(ref callback) => {
+                info!("worker received event_callback");
+                debug!(event = ?callback.event, "event_callback event");
+                self.handle_slack_event_callback(invocation, callback).await
+            },
+            InvokePayload::SlashCommand { ref command, ref text, ref user_id, ref response_url, .. } => {
+                info!(command, "worker received slash command");
+                debug!(text, "slash command text");
+                match command.as_str() {
+                    "/yoshino-audit" => self.handle_audit_command(text, user_id, response_url).await,
+                    _ => self.handle_slash_command(text, user_id, response_url).await,
+                }
+            },
+            InvokePayload::Unknown => {
+                info!(schema_version = %message.schema_version, "worker skipped a payload of a newer schema version");
+                Ok(())
+            },
+        };
+        if result.is_err() {
+            metrics::increment("errors", &[("type", "message")]);
         }
+        result
     }
 
-    async fn handle_slack_event_callback(&self, body: &str) -> Result<()> {
-        let any_event: AnyEventCallbackBody = serde_json::from_str(&body)?;
-        let r#type = any_event.event.r#type.as_str();
-        // ignore message updates
-        let subtype = any_event.event.subtype
-            .as_ref()
-            .map(|v| v.as_str());
+    async fn handle_slack_event_callback(&self, invocation: Invocation<'_>, callback: &EventCallback) -> Result<()> {
+        let event = &callback.event;
         // ignore bot's messages
-        let bot_id = any_event.event.bot_id;
-        if bot_id.is_some() {
+        if event.bot_id.is_some() {
             return Ok(())
         }
-        match (r#type, subtype) {
-            ("message", None) | ("message", Some("file_share")) => self.handle_slack_message(body).await,
+        match (event.r#type.as_str(), event.subtype.as_deref()) {
+            ("message", None) | ("message", Some("file_share")) => self.handle_slack_message(invocation, event).await,
+            ("message", Some("message_deleted")) => self.handle_slack_message_deleted(event).await,
+            ("message", Some("message_changed")) => self.handle_slack_message_changed(invocation, event).await,
             _ => Ok(()),
         }
     }
 
-    async fn handle_slack_message(&self, body: &str) -> Result<()> {
-        let body: MessageEventCallbackBody = serde_json::from_str(body)?;
-        let message_event = body.event;
+    // stops the reply to a prompt deleted while it was being generated
+    // https://api.slack.com/events/message/message_deleted
+    async fn handle_slack_message_deleted(&self, event: &Event) -> Result<()> {
+        let channel = event.channel.as_deref().context("message_deleted without channel")?;
+        let previous_message = event.previous_message.as_ref().context("message_deleted without previous_message")?;
+        let thread_ts = previous_message.thread_ts
+            .as_deref()
+            .unwrap_or(&previous_message.ts);
+        let Some(generation) = self.generation_store.current(channel, thread_ts).await? else { return Ok(()) };
+        if generation.finished || generation.prompt_ts != previous_message.ts {
+            return Ok(())
+        }
+        info!(generation = generation.id, "prompt deleted, cancelling generation");
+        self.generation_store.cancel(&generation).await
+    }
+
+    // regenerates the reply to a prompt edited while it was being generated,
+    // or after it was answered when re-answering on edit is enabled
+    // https://api.slack.com/events/message/message_changed
+    async fn handle_slack_message_changed(&self, invocation: Invocation<'_>, event: &Event) -> Result<()> {
+        let channel = event.channel.as_deref().context("message_changed without channel")?;
+        let message = event.message.as_ref().context("message_changed without message")?;
+        // ignore our own updates while streaming
+        if message.bot_id.is_some() {
+            return Ok(())
+        }
+        // link unfurls and thread replies change the message without changing the text
+        if event.previous_message.as_ref().is_some_and(|v| v.text == message.text) {
+            return Ok(())
+        }
+        let thread_ts = message.thread_ts
+            .as_deref()
+            .unwrap_or(&message.ts);
+        // only the last prompt of the thread is answered again
+        let Some(generation) = self.generation_store.current(channel, thread_ts).await? else { return Ok(()) };
+        if generation.prompt_ts != message.ts {
+            return Ok(())
+        }
+        let reanswer = self.config.reanswer_on_edit;
+        if generation.finished && !reanswer {
+            return Ok(())
+        }
+        info!(generation = generation.id, "prompt edited, regenerating generation");
+        // the regenerated reply takes over the thread, which stops the one in flight
+        let message_event = MessageEvent {
+            user: message.user.clone(),
+            text: message.text.clone(),
+            channel: channel.into(),
+            ts: message.ts.clone(),
+            thread_ts: message.thread_ts.clone(),
+            files: message.files.clone(),
+        };
+        let existing_reply = reanswer.then_some(ExistingReply::Edited(generation.reply_ts));
+        self.respond(invocation, message_event, existing_reply).await
+    }
+
+    // Web deduplicates Slack's retries, but a queued message is delivered again after a failed attempt.
+    // The generation the earlier attempt began tells how far it got: a finished answer or a newer prompt
+    // in the thread drop the redelivery, an unfinished one is answered again into the same reply.
+    async fn handle_slack_message(&self, invocation: Invocation<'_>, event: &Event) -> Result<()> {
+        let message_event = MessageEvent::new(event)?;
+        let thread_ts = message_event.thread_ts
+            .as_deref()
+            .unwrap_or(&message_event.ts);
+        let mut existing_reply = None;
+        if let Some(generation) = self.generation_store.current(&message_event.channel, thread_ts).await? {
+            // timestamps are seconds of the same magnitude, they compare as strings
+            if generation.prompt_ts > message_event.ts {
+                info!(generation = generation.id, "a newer prompt took over the thread, dropping the redelivery");
+                return Ok(())
+            }
+            if generation.prompt_ts == message_event.ts {
+                if generation.finished {
+                    info!(generation = generation.id, "prompt already answered, dropping the redelivery");
+                    return Ok(())
+                }
+                info!(generation = generation.id, "resuming the reply of an earlier attempt");
+                existing_reply = Some(ExistingReply::Resumed(generation.reply_ts));
+            }
+        }
+        self.respond(invocation, message_event, existing_reply).await
+    }
+
+    // every way out of here leaves a record in the audit log, a failure included
+    async fn respond(&self, invocation: Invocation<'_>, message_event: MessageEvent, existing_reply: Option<ExistingReply>) -> Result<()> {
+        let interaction = Interaction {
+            trace_id: invocation.trace_id,
+            team_id: invocation.team_id,
+            channel: &message_event.channel,
+            user: message_event.user.as_deref(),
+            ts: &message_event.ts,
+            prompt: &message_event.text,
+            image_url: None,
+        };
+        let result = self.answer(invocation, &message_event, existing_reply, interaction).await;
+        if let Err(ref error) = result {
+            if let Err(audit_error) = self.audit_trail.record(&interaction, AuditOutcome::Failed, None, vec![], None).await {
+                info!(error = ?audit_error, cause = ?error, "failed to record the failure in the audit log");
+                metrics::increment("errors", &[("type", "audit")]);
+            }
+        }
+        result
+    }
+
+    // answers into the existing reply instead of posting a new one when given
+    async fn answer(&self, invocation: Invocation<'_>, message_event: &MessageEvent, existing_reply: Option<ExistingReply>, interaction: Interaction<'_>) -> Result<()> {
         let text = &message_event.text;
         let channel = &message_event.channel;
-        let post_result = self.slack_client.post(
-            channel,
-            Some(&message_event.ts),
-             format!("Hi! `[Processing {}...]`", text)).await?;
-        let thread_ts = post_result.thread_ts
-            .as_ref()
-            .context("missing thread_ts")?;
+        if let (Some(user), Some(command)) = (&message_event.user, Command::parse(text)) {
+            let answer = self.handle_command(invocation, channel, &message_event.ts, user, command).await?;
+            self.audit_trail.record(&interaction, AuditOutcome::Command, None, vec![], Some(&answer)).await?;
+            return Ok(())
+        }
+        let quota_subject = message_event.user.as_deref()
+            .map(|user| QuotaSubject { team: invocation.team_id, channel, user });
+        let images = Self::image_file_count(message_event);
+        // the earlier attempt was admitted already
+        let resumed = matches!(existing_reply, Some(ExistingReply::Resumed(_)));
+        if let Some(quota_subject) = quota_subject.as_ref().filter(|_| !resumed) {
+            if let Some(exceeded) = self.quotas.admit(quota_subject, images).await? {
+                info!(scope = %exceeded.scope, metric = %exceeded.metric, "quota exceeded");
+                let answer = Self::quota_exceeded_text(&exceeded);
+                self.slack_client.post(channel, Some(&message_event.ts), answer.into()).await?;
+                self.audit_trail.record(&interaction, AuditOutcome::Throttled, None, vec![], Some(answer)).await?;
+                return Ok(())
+            }
+        }
+        let edited = matches!(existing_reply, Some(ExistingReply::Edited(_)));
+        let replaced = existing_reply.is_some();
+        let (reply_ts, thread_ts) = match existing_reply {
+            Some(ExistingReply::Edited(reply_ts) | ExistingReply::Resumed(reply_ts)) => {
+                self.slack_client.update(channel, &reply_ts, format!("Hi! `[Processing {}...]`", text)).await?;
+                let thread_ts = message_event.thread_ts.clone()
+                    .unwrap_or_else(|| message_event.ts.clone());
+                (reply_ts, thread_ts)
+            },
+            None => {
+                let post_result = self.slack_client.post(
+                    channel,
+                    Some(&message_event.ts),
+                     format!("Hi! `[Processing {}...]`", text)).await?;
+                let thread_ts = post_result.thread_ts
+                    .context("missing thread_ts")?;
+                (post_result.ts, thread_ts)
+            },
+        };
+        let thread_ts = &thread_ts;
+        // a small indicator that the reply answers the edited prompt
+        let edited_indicator = if edited { "\n_(edited)_" } else { "" };
+        // a newer prompt in the thread stops this generation by beginning its own
+        let generation = self.generation_store.begin(channel, thread_ts, &message_event.ts, &reply_ts).await?;
         // get image
-        let file_image_url = self.file_image_url(&message_event).await?;
+        let file_image_url = self.file_image_url(message_event).await?;
+        // get voice message transcript
+        let file_audio_transcript = self.file_audio_transcript(message_event).await?;
+        let query = with_transcript(&message_event.text, file_audio_transcript.as_deref());
+        let interaction = Interaction {
+            prompt: &query,
+            image_url: file_image_url.as_deref(),
+            ..interaction
+        };
         // get replies
         let replies = self.slack_client.replies(channel, thread_ts).await?;
+        // a conversation at risk is answered with the vetted support message instead of the model,
+        // the earlier turns are assessed too as a conversation may drift into risk over several messages
+        let recent_turns = recent_user_turns(&replies.messages, &message_event.ts, &query);
+        let mut escalation = self.safety.assess(&recent_turns, file_image_url.as_deref()).await
+            .map(|categories| ("safety", categories));
+        // the prompt is checked before anything of it reaches the model
+        let prompt_verdict = match (&self.moderator, &escalation) {
+            (Some(moderator), None) => moderator.check(Stage::Prompt, &query, file_image_url.as_deref()).await,
+            _ => Verdict::Allow,
+        };
+        if prompt_verdict == Verdict::SelfHarm {
+            escalation = Some(("moderation", vec!["self-harm".into()]));
+        }
+        if let Some((source, categories)) = escalation {
+            let subject = EscalationSubject {
+                trace_id: invocation.trace_id,
+                team_id: invocation.team_id,
+                channel,
+                user: message_event.user.as_deref(),
+            };
+            let escalation = self.safety.escalate(&subject, source, categories).await;
+            self.slack_client.update(channel, &reply_ts, format!("{}{}", escalation.text, edited_indicator)).await?;
+            self.safety.record(&escalation).await;
+            self.generation_store.finish(&generation).await?;
+            self.audit_trail.record(&interaction, AuditOutcome::Escalated, None, vec![], Some(&escalation.text)).await?;
+            return Ok(())
+        }
+        if prompt_verdict == Verdict::Block {
+            self.slack_client.update(channel, &reply_ts, format!("{}{}", BLOCKED_PROMPT_TEXT, edited_indicator)).await?;
+            self.generation_store.finish(&generation).await?;
+            self.audit_trail.record(&interaction, AuditOutcome::Refused, None, vec![], Some(BLOCKED_PROMPT_TEXT)).await?;
+            return Ok(())
+        }
         // construct completions request
         let messages: Vec<CompletionsRequestMessage> = replies.messages.into_iter()
             .filter_map(|message| {
+                // the previous answer to the edited prompt, or the earlier attempt's, is being replaced
+                if replaced && message.ts == reply_ts {
+                    return None
+                }
                 let message = match (message.r#type.as_str(), &message.bot_id) {
                     ("message", None) if &message.ts == &message_event.ts => {
+                        let text = with_transcript(&message.text, file_audio_transcript.as_deref());
                         // https://platform.openai.com/docs/guides/vision/uploading-base-64-encoded-images
                         if let Some(ref file_image_url) = file_image_url {
                             let image_url = CompletionsRequestMessageImageURL {
@@ -126,7 +384,7 @@ impl MessageHandle {
                                 content: vec![
                                     CompletionsRequestMessageContent {
                                         r#type: "text".into(),
-                                        text: Some(message.text),
+                                        text: Some(text),
                                         image_url: None,
                                     },
                                     CompletionsRequestMessageContent {
@@ -134,7 +392,9 @@ impl MessageHandle {
                                         text: None,
                                         image_url: Some(image_url),
                                     },
-                                ]
+                                ],
+                                tool_calls: None,
+                                tool_call_id: None,
                             }
                         } else {
                             CompletionsRequestMessage {
@@ -142,10 +402,12 @@ impl MessageHandle {
                                 content: vec![
                                     CompletionsRequestMessageContent {
                                         r#type: "text".into(),
-                                        text: Some(message.text),
+                                        text: Some(text),
                                         image_url: None,
                                     }
-                                ]
+                                ],
+                                tool_calls: None,
+                                tool_call_id: None,
                             }
                         }
                     },
@@ -158,7 +420,9 @@ impl MessageHandle {
                                     text: Some(message.text),
                                     image_url: None,
                                 }
-                            ]
+                            ],
+                            tool_calls: None,
+                            tool_call_id: None,
                         }
                     }
                     ("message", Some(_)) => CompletionsRequestMessage {
@@ -169,32 +433,349 @@ impl MessageHandle {
                                 text: Some(message.text),
                                 image_url: None,
                             }
-                        ]
+                        ],
+                        tool_calls: None,
+                        tool_call_id: None,
                     },
                     _ => return None,
                 };
                 Some(message)
             })
             .collect();
+        // retrieve passages relevant to the prompt from the document corpus
+        let passages = self.relevant_passages(&query).await;
+        // long-term memories of the user
+        let memories = match message_event.user {
+            Some(ref user) => self.memory_store.list(user).await?,
+            None => vec![],
+        };
         let messages = {
             let mut v = Self::system_messages();
+            if !memories.is_empty() {
+                v.push(Self::system_text_message(memories_prompt(&memories)));
+            }
+            if !passages.is_empty() {
+                v.push(Self::system_text_message(passages_prompt(&passages)));
+            }
             v.extend(messages);
             v
         };
-        info!("completions request messages {:?}", messages);
+        debug!(messages = ?messages, "completions request");
         // run completions
-        let completions = Completions::new(&self.openai_client)?;
-        let mut content_stream = completions.periodic_contents(messages).await?;
-        while let Some(content) = content_stream.next().await {
-            self.slack_client.update(channel, &post_result.ts, content.clone()).await?;
+        let tools = {
+            let mut registry = ToolRegistry::builtin();
+            if let Some(ref user) = message_event.user {
+                let scope = RequesterScope::new(self.slack_client.clone(), user, channel);
+                register_slack_tools(&mut registry, &self.slack_client, &scope);
+                registry.register(RememberTool::new(&self.memory_store, user));
+            }
+            Arc::new(registry)
+        };
+        let prompt_tokens: u64 = messages.iter()
+            .flat_map(|v| &v.content)
+            .filter_map(|v| v.text.as_deref())
+            .map(|v| self.config.tokenizer.count(v))
+            .sum();
+        let model = self.config.completions_model.as_str();
+        let started = Instant::now();
+        let completions = Completions::new(&self.openai_client, &tools)?;
+        // covers the requests and tool calls polled by the stream, not the slack updates in between
+        let completion_span = info_span!("completion", model, generation = generation.id);
+        let mut content_stream = completions.periodic_contents(messages)
+            .instrument(completion_span.clone())
+            .await?;
+        // an answer that may be withdrawn is only posted once it is checked
+        let held = self.moderator.as_ref().is_some_and(|v| v.holds_answers());
+        let mut final_content = None;
+        while let Some(content) = content_stream.next().instrument(completion_span.clone()).await {
+            // dropping the stream closes the completions request
+            if !self.generation_store.is_current(&generation).await? {
+                // a regeneration into the same reply owns the message now
+                let current = self.generation_store.current(channel, thread_ts).await?;
+                if current.is_none_or(|v| v.reply_ts != reply_ts) {
+                    let partial_content = if held { "" } else { final_content.as_deref().unwrap_or_default() };
+                    self.slack_client.update(channel, &reply_ts, format!("{}\n`[Stopped]`", partial_content)).await?;
+                }
+                info!(generation = generation.id, "generation stopped");
+                metrics::observe("generation_ms", &[("model", model), ("outcome", "stopped")], started.elapsed());
+                if let Some(ref quota_subject) = quota_subject {
+                    let usage = self.usage_or_estimate(completions.usage(), prompt_tokens, images, final_content.as_deref());
+                    self.record_usage(invocation, quota_subject, &usage, images).await?;
+                }
+                self.audit_trail.record(&interaction, AuditOutcome::Stopped, Some(model), completions.tools_used(), final_content.as_deref()).await?;
+                return Ok(())
+            }
+            if !held {
+                self.slack_client.update(channel, &reply_ts, format!("{}{}", content, edited_indicator)).await?;
+            }
+            final_content = Some(content);
         }
+        self.generation_store.finish(&generation).await?;
         info!("completions complete!");
+        metrics::observe("generation_ms", &[("model", model), ("outcome", "completed")], started.elapsed());
+        if let Some(ref quota_subject) = quota_subject {
+            let usage = self.usage_or_estimate(completions.usage(), prompt_tokens, images, final_content.as_deref());
+            self.record_usage(invocation, quota_subject, &usage, images).await?;
+        }
+        // the answer is checked once it is complete, a blocked one is withdrawn
+        let answer_verdict = match (&self.moderator, &final_content) {
+            (Some(moderator), Some(content)) => moderator.check(Stage::Answer, content, None).await,
+            _ => Verdict::Allow,
+        };
+        if answer_verdict == Verdict::Block {
+            self.slack_client.update(channel, &reply_ts, format!("{}{}", BLOCKED_ANSWER_TEXT, edited_indicator)).await?;
+            self.audit_trail.record(&interaction, AuditOutcome::Withdrawn, Some(model), completions.tools_used(), final_content.as_deref()).await?;
+            return Ok(())
+        }
+        let mut notes = vec![];
+        if prompt_verdict == Verdict::Warn || answer_verdict == Verdict::Warn {
+            notes.push(WARNING_TEXT.to_string());
+        }
+        if answer_verdict == Verdict::SelfHarm {
+            notes.push(self.safety.support_text(message_event.user.as_deref()).await);
+        }
+        // render citations of the passages the answer referred to, and the moderation notes
+        if let Some(ref final_content) = final_content {
+            let footer = citations_footer(final_content, &passages);
+            if held || footer.is_some() || !notes.is_empty() {
+                let content = footer.into_iter()
+                    .chain(notes)
+                    .fold(final_content.clone(), |content, v| format!("{}\n\n{}", content, v));
+                self.slack_client.update(channel, &reply_ts, format!("{}{}", content, edited_indicator)).await?;
+            }
+        }
+        self.audit_trail.record(&interaction, AuditOutcome::Answered, Some(model), completions.tools_used(), final_content.as_deref()).await?;
+        // read the reply aloud, fitting the radio theme
+        if let Some(final_content) = final_content {
+            if self.config.spoken_replies {
+                self.post_spoken_reply(channel, thread_ts, &final_content).await;
+            }
+        }
         Ok(())
     }
 
+    // returns the reply posted
+    async fn handle_command(&self, invocation: Invocation<'_>, channel: &str, ts: &str, user: &str, command: Command) -> Result<String> {
+        info!(command = command.kind(), "handling command");
+        let text = match command {
+            Command::Remember(fact) => {
+                let fact = self.memory_store.add(user, &fact).await?;
+                format!("承知しましたー。しかと覚えておきましょうー `#{}`", fact.id)
+            },
+            Command::ListMemories => {
+                let facts = self.memory_store.list(user).await?;
+                if facts.is_empty() {
+                    "そなたについて覚えていることは、まだありませぬー".into()
+                } else {
+                    let lines: Vec<String> = facts.iter()
+                        .map(|v| format!("`#{}` {}", v.id, v.text))
+                        .collect();
+                    format!("そなたについて覚えていることでしてー\n{}", lines.join("\n"))
+                }
+            },
+            Command::Forget(id) => {
+                if self.memory_store.forget(user, id).await? {
+                    format!("`#{}` は忘れることにいたしましょうー", id)
+                } else {
+                    format!("`#{}` という覚え書きは見当たりませぬー", id)
+                }
+            },
+            Command::ForgetAll => {
+                self.memory_store.forget_all(user).await?;
+                "すべて忘れましたー。また一から、ですねー".into()
+            },
+            Command::Connect => {
+                let subject = EscalationSubject {
+                    trace_id: invocation.trace_id,
+                    team_id: invocation.team_id,
+                    channel,
+                    user: Some(user),
+                };
+                self.safety.connect(&subject).await?
+            },
+            Command::Usage(_) if !self.config.admin_users.contains(user) => {
+                "そちらは管理のお役目の方にのみ、お伝えできるものでしてー".into()
+            },
+            Command::Usage(target) => {
+                let target = target.as_deref().unwrap_or(user);
+                let subject = QuotaSubject { team: invocation.team_id, channel, user: target };
+                let lines: Vec<String> = self.quotas.usage(&subject).await?.iter()
+                    .map(|v| {
+                        let limit = v.limit.map(|v| v.to_string()).unwrap_or_else(|| "-".into());
+                        format!("`{} {}` {} {}/{}", v.scope, v.id, v.metric, v.used, limit)
+                    })
+                    .collect();
+                format!("ただいまのご利用状況でしてー\n{}", lines.join("\n"))
+            },
+        };
+        self.slack_client.post(channel, Some(ts), text.clone()).await?;
+        Ok(text)
+    }
+
+    // called once a message has run out of attempts
+    pub async fn notify_failure(&self, message: &InvokeMessage) -> Result<()> {
+        let span = info_span!("notify_failure", trace_id = message.trace_id, team_id = message.team_id);
+        set_remote_parent(&span, message.traceparent.as_deref());
+        self.post_failure(message).instrument(span).await
+    }
+
+    async fn post_failure(&self, message: &InvokeMessage) -> Result<()> {
+        match message.payload {
+            InvokePayload::EventCallback(ref callback) => {
+                let message_event = MessageEvent::new(&callback.event)?;
+                let thread_ts = message_event.thread_ts
+                    .as_deref()
+                    .unwrap_or(&message_event.ts);
+                let text = "申し訳ありませぬー。何度か試みたのですが、お返事ができませんでしたー。少し時をおいて、もう一度お声がけくださいー";
+                self.slack_client.post(&message_event.channel, Some(thread_ts), text.into()).await?;
+                Ok(())
+            },
+            InvokePayload::SlashCommand { ref response_url, .. } => {
+                self.slack_client.respond(response_url, "申し訳ありませぬー。うまくお応えできませんでしたー".into()).await
+            },
+            InvokePayload::Unknown => Ok(()),
+        }
+    }
+
+    // a stream stopped before its end reports no usage
+    // the tokenizer counts the text of the prompt, the images are added at their fixed cost
+    fn usage_or_estimate(&self, usage: CompletionsUsage, prompt_tokens: u64, images: u64, content: Option<&str>) -> CompletionsUsage {
+        if usage.total_tokens() > 0 {
+            return usage
+        }
+        CompletionsUsage {
+            prompt_tokens: prompt_tokens + image_tokens(images),
+            completion_tokens: content.map(|v| self.config.tokenizer.count(v)).unwrap_or(0),
+        }
+    }
+
+    async fn record_usage(&self, invocation: Invocation<'_>, subject: &QuotaSubject<'_>, usage: &CompletionsUsage, images: u64) -> Result<()> {
+        let model = self.config.completions_model.as_str();
+        self.quotas.record_tokens(subject, usage.total_tokens()).await?;
+        metrics::add("tokens", &[("model", model), ("kind", "prompt")], usage.prompt_tokens);
+        metrics::add("tokens", &[("model", model), ("kind", "completion")], usage.completion_tokens);
+        let record = UsageRecord {
+            recorded_at: Utc::now(),
+            trace_id: invocation.trace_id.into(),
+            team_id: invocation.team_id.map(|v| v.into()),
+            channel: subject.channel.into(),
+            user: subject.user.into(),
+            persona: PERSONA.into(),
+            model: model.into(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            images,
+            image_tokens: image_tokens(images),
+            cost_usd: self.price_table.cost(model, usage),
+        };
+        info!(record = ?record, "usage");
+        self.usage_ledger.record(&record).await
+    }
+
+    // `/yoshino-usage [YYYY-MM]` reports the month's usage,
+    // `/yoshino-usage export csv|json [YYYY-MM]` sends the month's records to the admin by direct message,
+    // the channel the command was run in may have members who are not admins
+    async fn handle_slash_command(&self, text: &str, user: &str, response_url: &str) -> Result<()> {
+        if !self.config.admin_users.contains(user) {
+            return self.slack_client.respond(response_url, "そちらは管理のお役目の方にのみ、お伝えできるものでしてー".into()).await
+        }
+        let arguments: Vec<&str> = text.split_whitespace().collect();
+        let current_month = Utc::now().format("%Y-%m").to_string();
+        match arguments.as_slice() {
+            ["export", format, rest @ ..] => {
+                let month = rest.first().copied().unwrap_or(&current_month);
+                let records = self.usage_ledger.records(month).await?;
+                let (filename, data) = match *format {
+                    "csv" => (format!("yoshino-radio-usage-{}.csv", month), usage_csv(&records)),
+                    "json" => (format!("yoshino-radio-usage-{}.json", month), usage_json(&records)?),
+                    _ => return self.slack_client.respond(response_url, "形式は csv か json でお願いいたしますー".into()).await,
+                };
+                let direct_message = self.slack_client.open_direct_message(user).await?;
+                self.slack_client.upload_file(&direct_message, None, &filename, data.into_bytes()).await?;
+                self.slack_client.respond(response_url, "ダイレクトメッセージにお送りいたしましたー".into()).await
+            },
+            [] | [_] => {
+                let month = arguments.first().copied().unwrap_or(&current_month);
+                let records = self.usage_ledger.records(month).await?;
+                self.slack_client.respond(response_url, usage_report(month, &records)).await
+            },
+            _ => self.slack_client.respond(response_url, "`/yoshino-usage [YYYY-MM]` または `/yoshino-usage export csv|json [YYYY-MM]` とお使いくださいー".into()).await,
+        }
+    }
+
+    // `/yoshino-audit export [YYYY-MM-DD] [YYYY-MM-DD]` sends the audit records of the days by direct message as JSON lines
+    async fn handle_audit_command(&self, text: &str, user: &str, response_url: &str) -> Result<()> {
+        if !self.config.admin_users.contains(user) {
+            return self.slack_client.respond(response_url, "そちらは管理のお役目の方にのみ、お伝えできるものでしてー".into()).await
+        }
+        let arguments: Vec<&str> = text.split_whitespace().collect();
+        let (from, to) = match arguments.as_slice() {
+            ["export"] => (None, None),
+            ["export", from] => (Some(*from), None),
+            ["export", from, to] => (Some(*from), Some(*to)),
+            _ => return self.slack_client.respond(response_url, "`/yoshino-audit export [YYYY-MM-DD] [YYYY-MM-DD]` とお使いくださいー".into()).await,
+        };
+        let (from, to) = match AuditTrail::days(from, to) {
+            Ok(v) => v,
+            Err(error) => return self.slack_client.respond(response_url, format!("日付は YYYY-MM-DD でお願いいたしますー `{}`", error)).await,
+        };
+        let (filename, data) = self.audit_trail.export(from, to).await?;
+        info!(user, filename, "audit log exported");
+        let direct_message = self.slack_client.open_direct_message(user).await?;
+        self.slack_client.upload_file(&direct_message, None, &filename, data.into_bytes()).await?;
+        self.slack_client.respond(response_url, "ダイレクトメッセージにお送りいたしましたー".into()).await
+    }
+
+    // the text reply is already posted, a failure here must not fail the message and have it answered again
+    async fn post_spoken_reply(&self, channel: &str, thread_ts: &str, content: &str) {
+        info!("synthesizing spoken reply...");
+        let result = async {
+            let audio = speak(self.synthesizer.as_ref(), content).await?;
+            self.slack_client.upload_file(channel, Some(thread_ts), &audio.filename, audio.data).await
+        };
+        match result.await {
+            Ok(()) => info!("spoken reply uploaded"),
+            Err(error) => {
+                info!(error = ?error, "spoken reply failed");
+                metrics::increment("errors", &[("type", "spoken_reply")]);
+            },
+        }
+    }
+
+    // retrieval failures only cost the grounding, the conversation goes on
+    async fn relevant_passages(&self, query: &str) -> Vec<Passage> {
+        let result = async {
+            let Some(ref retriever) = self.config.retriever else { return Ok(vec![]) };
+            retriever.search(query).await
+        };
+        match result.await {
+            Ok(passages) => passages,
+            Err(error) => {
+                info!(error = ?error, "retrieval failed");
+                vec![]
+            }
+        }
+    }
+
+    fn quota_exceeded_text(exceeded: &QuotaExceeded) -> &'static str {
+        match exceeded.metric {
+            QuotaMetric::MessagesPerMinute => "少々お話が立て込んでおりましてー。ひと息ついてから、またお声がけくださいー",
+            QuotaMetric::TokensPerDay => "本日はたくさんお話しいたしましたゆえー、続きはまた明日にいたしましょうー",
+            QuotaMetric::ImagesPerDay => "本日拝見できるお写真は、ここまででしてー。また明日お見せくださいー",
+        }
+    }
+
+    // only the first file is passed to the model, see file_image_url()
+    fn image_file_count(message_event: &MessageEvent) -> u64 {
+        let Some(file) = message_event.files.first() else { return 0 };
+        match file.mimetype.as_str() {
+            "image/jpeg" | "image/png" => 1,
+            _ => 0,
+        }
+    }
+
     async fn file_image_url(&self, message_event: &MessageEvent) -> Result<Option<String>> {
-        let Some(files) = &message_event.files else { return Ok(None) };
-        let Some(file) = files.first() else { return Ok(None) };
+        let Some(file) = message_event.files.first() else { return Ok(None) };
         let Some(ref url_private_download) = file.url_private_download else { return Ok(None) };
         let mimetype = &file.mimetype;
         // check mimetype
@@ -202,14 +783,30 @@ impl MessageHandle {
             "image/jpeg" | "image/png" => (),
             _ => return Ok(None), 
         };
-        info!("downloading file {}...", file.id);
-        let data = self.slack_client.download_image(url_private_download).await?;
+        info!(file = file.id, "downloading file");
+        let data = self.slack_client.download_file(url_private_download).await?;
         let data_base64 = ImageProcess::new()?.base64(data)?;
         let data_url = format!("data:{mimetype};base64,{data_base64}");
-        info!("download file complete: size {}...", data_url.len());
+        info!(size = data_url.len(), "download file complete");
         Ok(Some(data_url))
     }
 
+    // https://platform.openai.com/docs/guides/speech-to-text
+    async fn file_audio_transcript(&self, message_event: &MessageEvent) -> Result<Option<String>> {
+        let Some(file) = message_event.files.first() else { return Ok(None) };
+        let Some(ref url_private_download) = file.url_private_download else { return Ok(None) };
+        let mimetype = &file.mimetype;
+        if !is_audio_mimetype(mimetype) {
+            return Ok(None)
+        }
+        info!(file = file.id, "downloading audio file");
+        let data = self.slack_client.download_file(url_private_download).await?;
+        info!(size = data.len(), "download audio file complete");
+        let transcript = transcribe_audio(self.transcriber.as_ref(), data, mimetype).await?;
+        info!(length = transcript.as_ref().map(|v| v.len()), "transcription complete");
+        Ok(transcript)
+    }
+
     // https://platform.openai.com/tokenizer
     fn system_messages() -> Vec<CompletionsRequestMessage> {
         // 704 tokens
@@ -240,16 +837,22 @@ impl MessageHandle {
 「ぱしゃぱしゃー。ふふー、冷たい水が心地良いですねー。それ、ぱしゃー」
 "#;
         vec![
-            CompletionsRequestMessage {
-                role: "system".into(),
-                content: vec![
-                    CompletionsRequestMessageContent {
-                        r#type: "text".into(),
-                        text: Some(text.into()),
-                        image_url: None,
-                    }
-                ]
-            }
+            Self::system_text_message(text.into())
         ]
     }
+
+    fn system_text_message(text: String) -> CompletionsRequestMessage {
+        CompletionsRequestMessage {
+            role: "system".into(),
+            content: vec![
+                CompletionsRequestMessageContent {
+                    r#type: "text".into(),
+                    text: Some(text),
+                    image_url: None,
+                }
+            ],
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
 }
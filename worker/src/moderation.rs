@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use cores::config::ConfigLoader;
+use cores::metrics;
+use regex::Regex;
+use tracing::info;
+
+use crate::openai_client::OpenAIClient;
+
+pub const BLOCKED_PROMPT_TEXT: &str = "申し訳ありませぬー。そのお話には、お応えいたしかねますー";
+pub const BLOCKED_ANSWER_TEXT: &str = "申し訳ありませぬー。このお返事は差し控えさせていただきましたー";
+pub const WARNING_TEXT: &str = "_(お話の中に、少々気がかりなところがございましたー)_";
+
+// matched by the keywords classifiers, in addition to the rules files
+const BUILTIN_RULES: [(&str, &str); 2] = [
+    ("self-harm", r"死にたい|消えたい|消えてしまいたい|自殺|自死|首を吊|リストカット|リスカ|生きていたくない|生きる意味(が|は)(ない|無い)"),
+    ("self-harm", r"(?i)\b(kill myself|suicid(e|al)|end my life|want to die|self[- ]harm|cut myself)\b"),
+];
+
+// what is done with a flagged prompt or answer, signs of self-harm are escalated regardless, see Safety
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModerationAction {
+    // the prompt is not answered, the answer is withdrawn
+    Block,
+    // a note is added to the answer
+    Warn,
+    // only logged and counted
+    Log,
+}
+
+impl ModerationAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ModerationAction::Block => "block",
+            ModerationAction::Warn => "warn",
+            ModerationAction::Log => "log",
+        }
+    }
+}
+
+impl FromStr for ModerationAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "block" => Ok(ModerationAction::Block),
+            "warn" => Ok(ModerationAction::Warn),
+            "log" => Ok(ModerationAction::Log),
+            _ => bail!("expected block, warn or log"),
+        }
+    }
+}
+
+// what a failing classifier does to the message, signs of self-harm are still looked for with the keyword rules
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModerationFailure {
+    // the message passes, so that moderation never takes the conversation down
+    Open,
+    // the message is handled as flagged
+    Closed,
+}
+
+impl FromStr for ModerationFailure {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "open" => Ok(ModerationFailure::Open),
+            "closed" => Ok(ModerationFailure::Closed),
+            _ => bail!("expected open or closed"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ClassifierKind {
+    // https://platform.openai.com/docs/guides/moderation
+    OpenAI,
+    Keywords,
+}
+
+impl FromStr for ClassifierKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "openai" => Ok(ClassifierKind::OpenAI),
+            "keywords" => Ok(ClassifierKind::Keywords),
+            _ => bail!("expected openai or keywords"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct KeywordRule {
+    pub category: String,
+    pub regex: Regex,
+}
+
+// YOSHINO_MODERATION selects the classifier, prompts and answers are not moderated without one
+// * `openai` the moderation endpoint, which also looks at images
+// * `keywords` regular expressions, the built-in self-harm ones and the ones in YOSHINO_MODERATION_RULES
+// YOSHINO_MODERATION_ACTION is one of block (default), warn or log
+// YOSHINO_MODERATION_ON_FAILURE is one of open (default) or closed
+pub struct ModerationConfig {
+    pub classifier: ClassifierKind,
+    pub action: ModerationAction,
+    pub on_failure: ModerationFailure,
+    pub rules: Vec<KeywordRule>,
+}
+
+impl ModerationConfig {
+    pub fn load(loader: &mut ConfigLoader) -> Option<Self> {
+        let classifier = loader.optional("YOSHINO_MODERATION")?;
+        let classifier: ClassifierKind = match classifier.parse() {
+            Ok(classifier) => classifier,
+            Err(error) => {
+                loader.invalid(format!("YOSHINO_MODERATION has an invalid value {:?}: {}", classifier, error));
+                return None
+            },
+        };
+        let action = loader.parse("YOSHINO_MODERATION_ACTION", ModerationAction::Block);
+        let on_failure = loader.parse("YOSHINO_MODERATION_ON_FAILURE", ModerationFailure::Open);
+        let rules = match keyword_rules(loader.optional("YOSHINO_MODERATION_RULES").as_deref()) {
+            Ok(rules) => rules,
+            Err(error) => {
+                loader.invalid(format!("YOSHINO_MODERATION_RULES {:#}", error));
+                vec![]
+            },
+        };
+        let this = Self {
+            classifier,
+            action,
+            on_failure,
+            rules,
+        };
+        Some(this)
+    }
+}
+
+// the built-in rules and the ones in the file at path,
+// a JSON object of categories to regular expressions, e.g. {"harassment": ["バカ|アホ"], "self-harm": ["いなくなりたい"]}
+pub fn keyword_rules(path: Option<&str>) -> Result<Vec<KeywordRule>> {
+    let mut rules = vec![];
+    for (category, pattern) in BUILTIN_RULES {
+        rules.push(KeywordRule { category: category.into(), regex: Regex::new(pattern)? });
+    }
+    let Some(path) = path else { return Ok(rules) };
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path))?;
+    let custom: HashMap<String, Vec<String>> = serde_json::from_str(&text)
+        .with_context(|| format!("broken rules {}", path))?;
+    for (category, patterns) in custom {
+        for pattern in patterns {
+            let regex = Regex::new(&pattern)
+                .with_context(|| format!("invalid pattern {} of {}", pattern, category))?;
+            rules.push(KeywordRule { category: category.clone(), regex });
+        }
+    }
+    Ok(rules)
+}
+
+// returns the flagged categories, named as the moderation endpoint names them, e.g. `self-harm/intent`
+#[async_trait]
+pub trait Classifier: Send + Sync {
+    async fn classify(&self, text: &str, image_url: Option<&str>) -> Result<Vec<String>>;
+}
+
+#[async_trait]
+impl Classifier for OpenAIClient {
+    async fn classify(&self, text: &str, image_url: Option<&str>) -> Result<Vec<String>> {
+        self.moderations(text, image_url).await
+    }
+}
+
+// images pass unchecked
+pub struct KeywordClassifier {
+    rules: Vec<KeywordRule>,
+}
+
+impl KeywordClassifier {
+    pub fn new(rules: Vec<KeywordRule>) -> Arc<Self> {
+        Arc::new(Self { rules })
+    }
+}
+
+#[async_trait]
+impl Classifier for KeywordClassifier {
+    async fn classify(&self, text: &str, _image_url: Option<&str>) -> Result<Vec<String>> {
+        let mut categories: Vec<String> = self.rules.iter()
+            .filter(|v| v.regex.is_match(text))
+            .map(|v| v.category.clone())
+            .collect();
+        categories.sort();
+        categories.dedup();
+        Ok(categories)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Stage {
+    Prompt,
+    Answer,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Prompt => "prompt",
+            Stage::Answer => "answer",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Warn,
+    Block,
+    SelfHarm,
+}
+
+fn is_self_harm(category: &str) -> bool {
+    category == "self-harm" || category.starts_with("self-harm/")
+}
+
+pub struct Moderator {
+    classifier: Arc<dyn Classifier>,
+    // the self-harm rules, looked at when the classifier fails
+    fallback: Arc<KeywordClassifier>,
+    action: ModerationAction,
+    on_failure: ModerationFailure,
+}
+
+impl Moderator {
+    pub fn new(config: &ModerationConfig, openai_client: &Arc<OpenAIClient>) -> Arc<Self> {
+        let classifier: Arc<dyn Classifier> = match config.classifier {
+            ClassifierKind::OpenAI => openai_client.clone(),
+            ClassifierKind::Keywords => KeywordClassifier::new(config.rules.clone()),
+        };
+        let self_harm_rules = config.rules.iter()
+            .filter(|v| is_self_harm(&v.category))
+            .cloned()
+            .collect();
+        let this = Self {
+            classifier,
+            fallback: KeywordClassifier::new(self_harm_rules),
+            action: config.action,
+            on_failure: config.on_failure,
+        };
+        Arc::new(this)
+    }
+
+    // with the block action an answer is held back until it is checked, instead of being streamed
+    pub fn holds_answers(&self) -> bool {
+        self.action == ModerationAction::Block
+    }
+
+    pub async fn check(&self, stage: Stage, text: &str, image_url: Option<&str>) -> Verdict {
+        let categories = match self.classifier.classify(text, image_url).await {
+            Ok(categories) => categories,
+            Err(error) => {
+                info!(error = ?error, stage = stage.name(), "moderation failed");
+                metrics::increment("errors", &[("type", "moderation")]);
+                return self.on_failure(text).await
+            },
+        };
+        if categories.is_empty() {
+            return Verdict::Allow
+        }
+        self.verdict(stage, &categories)
+    }
+
+    // signs of self-harm fail closed whatever the setting
+    async fn on_failure(&self, text: &str) -> Verdict {
+        let self_harm = self.fallback.classify(text, None).await
+            .is_ok_and(|v| !v.is_empty());
+        if self_harm {
+            return Verdict::SelfHarm
+        }
+        match self.on_failure {
+            ModerationFailure::Open => Verdict::Allow,
+            ModerationFailure::Closed => Verdict::Block,
+        }
+    }
+
+    fn verdict(&self, stage: Stage, categories: &[String]) -> Verdict {
+        let action = self.action.name();
+        info!(stage = stage.name(), categories = ?categories, action, "moderation flagged");
+        for category in categories {
+            metrics::increment("moderation_flagged", &[("stage", stage.name()), ("category", category), ("action", action)]);
+        }
+        if categories.iter().any(|v| is_self_harm(v)) {
+            return Verdict::SelfHarm
+        }
+        match self.action {
+            ModerationAction::Block => Verdict::Block,
+            ModerationAction::Warn => Verdict::Warn,
+            ModerationAction::Log => Verdict::Allow,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FailingClassifier {}
+
+    #[async_trait]
+    impl Classifier for FailingClassifier {
+        async fn classify(&self, _text: &str, _image_url: Option<&str>) -> Result<Vec<String>> {
+            bail!("moderation endpoint is down")
+        }
+    }
+
+    fn moderator(classifier: Arc<dyn Classifier>, on_failure: ModerationFailure) -> Moderator {
+        let rules = keyword_rules(None).unwrap();
+        Moderator {
+            classifier,
+            fallback: KeywordClassifier::new(rules),
+            action: ModerationAction::Block,
+            on_failure,
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_open_lets_the_message_through() {
+        let moderator = moderator(Arc::new(FailingClassifier {}), ModerationFailure::Open);
+        assert_eq!(moderator.check(Stage::Prompt, "今日の天気は？", None).await, Verdict::Allow);
+    }
+
+    #[tokio::test]
+    async fn failing_closed_blocks_the_message() {
+        let moderator = moderator(Arc::new(FailingClassifier {}), ModerationFailure::Closed);
+        assert_eq!(moderator.check(Stage::Prompt, "今日の天気は？", None).await, Verdict::Block);
+    }
+
+    #[tokio::test]
+    async fn self_harm_fails_closed() {
+        let moderator = moderator(Arc::new(FailingClassifier {}), ModerationFailure::Open);
+        assert_eq!(moderator.check(Stage::Prompt, "もう死にたい", None).await, Verdict::SelfHarm);
+    }
+
+    #[tokio::test]
+    async fn keywords_flag_by_action() {
+        let rules = keyword_rules(None).unwrap();
+        let moderator = moderator(KeywordClassifier::new(rules), ModerationFailure::Open);
+        assert_eq!(moderator.check(Stage::Answer, "I want to die", None).await, Verdict::SelfHarm);
+        assert_eq!(moderator.check(Stage::Answer, "good morning", None).await, Verdict::Allow);
+        assert!(moderator.holds_answers());
+    }
+}
@@ -1,29 +1,54 @@
 
+use std::collections::HashMap;
 use std::fmt;
-use std::{sync::Arc, env};
+use std::sync::Arc;
 use anyhow::{Result, bail};
+use cores::secrets::Secret;
 use reqwest::Response;
+use reqwest::multipart::{Form, Part};
 use reqwest::{self, Client};
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use tracing::debug;
 use futures_util::StreamExt;
 use futures_util::Stream;
 
+// gpt-4-vision-preview, used before tools were added, does not support tool calls,
+// gpt-4-turbo takes both images and tools
+// https://platform.openai.com/docs/models/gpt-4-turbo-and-gpt-4
+pub const DEFAULT_COMPLETIONS_MODEL: &str = "gpt-4-turbo";
+
 #[derive(Serialize)]
 struct CompletionsRequestBody {
     model: String,
     messages: Vec<CompletionsRequestMessage>,
     stream: bool,
+    stream_options: CompletionsStreamOptions,
     max_tokens: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<CompletionsRequestTool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<String>,
+}
+
+// https://platform.openai.com/docs/api-reference/chat/create#chat-create-stream_options
+#[derive(Serialize)]
+struct CompletionsStreamOptions {
+    include_usage: bool,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct CompletionsRequestMessage {
     pub role: String,
+    // assistant messages carrying tool calls have no content
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pub content: Vec<CompletionsRequestMessageContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<CompletionsToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct CompletionsRequestMessageContent {
     pub r#type: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -32,12 +57,39 @@ pub struct CompletionsRequestMessageContent {
     pub image_url: Option<CompletionsRequestMessageImageURL>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct CompletionsRequestMessageImageURL {
     pub url: String,
     pub detail: String,
 }
 
+// https://platform.openai.com/docs/guides/function-calling
+#[derive(Serialize, Debug, Clone)]
+pub struct CompletionsRequestTool {
+    pub r#type: String,
+    pub function: CompletionsRequestToolFunction,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CompletionsRequestToolFunction {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CompletionsToolCall {
+    pub id: String,
+    pub r#type: String,
+    pub function: CompletionsToolCallFunction,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CompletionsToolCallFunction {
+    pub name: String,
+    pub arguments: String,
+}
+
 impl fmt::Debug for CompletionsRequestMessageImageURL {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.url.len() < 1024 {
@@ -54,6 +106,8 @@ impl fmt::Debug for CompletionsRequestMessageImageURL {
 
 pub struct OpenAIClient {
     client: Client,
+    api_key: Secret,
+    completions_model: String,
 }
 
 // The chat completion chunk object
@@ -62,6 +116,26 @@ pub struct OpenAIClient {
 pub struct CompletionsMessageChunk {
     pub id: String,
     pub choices: Vec<CompletionsMessageChunkChoise>,
+    // only on the last chunk, which has no choices
+    pub usage: Option<CompletionsUsage>,
+}
+
+// https://platform.openai.com/docs/api-reference/chat/object#chat/object-usage
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct CompletionsUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl CompletionsUsage {
+    pub fn add(&mut self, other: &CompletionsUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -74,23 +148,88 @@ pub struct CompletionsMessageChunkChoise {
 pub struct CompletionsMessageChunkDelta {
     pub content: Option<String>,
     pub role: Option<String>,
+    pub tool_calls: Option<Vec<CompletionsMessageChunkToolCall>>,
+}
+
+// tool call arguments arrive as fragments keyed by index
+#[derive(Deserialize, Debug)]
+pub struct CompletionsMessageChunkToolCall {
+    pub index: usize,
+    pub id: Option<String>,
+    pub function: Option<CompletionsMessageChunkToolCallFunction>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CompletionsMessageChunkToolCallFunction {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TranscriptionsResponseBody {
+    text: String,
+}
+
+#[derive(Serialize)]
+struct SpeechRequestBody {
+    model: String,
+    input: String,
+    voice: String,
+    response_format: String,
+}
+
+// https://platform.openai.com/docs/api-reference/moderations/create
+#[derive(Serialize)]
+struct ModerationsRequestBody {
+    model: String,
+    input: Vec<ModerationsInput>,
+}
+
+#[derive(Serialize)]
+struct ModerationsInput {
+    r#type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image_url: Option<ModerationsImageURL>,
+}
+
+#[derive(Serialize)]
+struct ModerationsImageURL {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct ModerationsResponseBody {
+    results: Vec<ModerationsResult>,
+}
+
+#[derive(Deserialize)]
+struct ModerationsResult {
+    categories: HashMap<String, bool>,
 }
 
 // @see https://api.slack.com/rtm#sending_messages
 
 impl OpenAIClient {
-    pub fn new() -> Result<Arc<Self>> {
+    pub fn new(api_key: Secret, completions_model: &str) -> Result<Arc<Self>> {
         let client = reqwest::Client::new();
         let this = Self {
             client,
+            api_key,
+            completions_model: completions_model.into(),
         };
         let this = Arc::new(this);
         Ok(this)
     }
 
+    pub fn completions_model(&self) -> &str {
+        &self.completions_model
+    }
+
     // https://platform.openai.com/docs/api-reference/chat/create
-    pub async fn completions(&self, messages: Vec<CompletionsRequestMessage>) -> Result<impl Stream<Item = Result<Vec<CompletionsMessageChunk>, anyhow::Error>>> {
-        let response = self.completions_response(messages).await?;
+    pub async fn completions(&self, messages: Vec<CompletionsRequestMessage>, tools: Vec<CompletionsRequestTool>, tool_choice: Option<String>) -> Result<impl Stream<Item = Result<Vec<CompletionsMessageChunk>, anyhow::Error>>> {
+        let response = self.completions_response(messages, tools, tool_choice).await?;
         // check status
         if !response.status().is_success() {
             let text = response.text().await?;
@@ -115,7 +254,7 @@ impl OpenAIClient {
                 if let Some(data) = line.strip_prefix("data: ") {
                     // the stream terminated by a data: [DONE] message.
                     if !data.is_empty() && data != "[DONE]" {
-                        info!("chunk {:?}", data);
+                        debug!(data, "chunk");
                         let chunk: Option<CompletionsMessageChunk> = serde_json::from_str(data).ok();
                         chunk
                     } else {
@@ -123,7 +262,7 @@ impl OpenAIClient {
                     }
                 } else {
                     if !line.is_empty() {
-                        info!("ignoring line {:?}", line);
+                        debug!(line, "ignoring line");
                     }
                     None
                 }
@@ -136,21 +275,108 @@ impl OpenAIClient {
     }
 
     // https://platform.openai.com/docs/guides/vision
-    async fn completions_response(&self, messages: Vec<CompletionsRequestMessage>) -> Result<Response> {
-        let api_key = env::var("OPENAI_API_KEY")?;
+    async fn completions_response(&self, messages: Vec<CompletionsRequestMessage>, tools: Vec<CompletionsRequestTool>, tool_choice: Option<String>) -> Result<Response> {
         let request_body = CompletionsRequestBody {
-            model: "gpt-4-vision-preview".into(),
+            model: self.completions_model.clone(),
             messages,
             max_tokens: 2048,
             stream: true,
+            stream_options: CompletionsStreamOptions {
+                include_usage: true,
+            },
+            tools,
+            tool_choice,
         };
         let response = self.client.post("https://api.openai.com/v1/chat/completions")
             .header("Content-type", "application/json; charset=utf-8")
-            .header("Authorization", ["Bearer", &api_key].join(" "))
+            .header("Authorization", ["Bearer", &self.api_key.get().await].join(" "))
             .json(&request_body)
             .send()
             .await?;
-        info!("completions response {:?}", response);
+        debug!(response = ?response, "completions response");
         Ok(response)
     }
+
+    // https://platform.openai.com/docs/api-reference/audio/createTranscription
+    pub async fn transcriptions(&self, data: Vec<u8>, filename: &str, mimetype: &str) -> Result<String> {
+        let file = Part::bytes(data)
+            .file_name(filename.to_string())
+            .mime_str(mimetype)?;
+        let form = Form::new()
+            .text("model", "whisper-1")
+            .part("file", file);
+        let response = self.client.post("https://api.openai.com/v1/audio/transcriptions")
+            .header("Authorization", ["Bearer", &self.api_key.get().await].join(" "))
+            .multipart(form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let text = response.text().await?;
+            bail!("transcriptions response failure. {}", text);
+        }
+        let response: TranscriptionsResponseBody = response.json().await?;
+        Ok(response.text)
+    }
+
+    // https://platform.openai.com/docs/api-reference/audio/createSpeech
+    pub async fn speech(&self, input: &str) -> Result<Vec<u8>> {
+        let request_body = SpeechRequestBody {
+            model: "tts-1".into(),
+            input: input.into(),
+            voice: "nova".into(),
+            response_format: "mp3".into(),
+        };
+        let response = self.client.post("https://api.openai.com/v1/audio/speech")
+            .header("Content-type", "application/json; charset=utf-8")
+            .header("Authorization", ["Bearer", &self.api_key.get().await].join(" "))
+            .json(&request_body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let text = response.text().await?;
+            bail!("speech response failure. {}", text);
+        }
+        let bytes = response.bytes().await?;
+        Ok(Vec::from(bytes))
+    }
+
+    // returns the flagged categories, the image is given as a URL or a data URL
+    // https://platform.openai.com/docs/api-reference/moderations/create
+    pub async fn moderations(&self, text: &str, image_url: Option<&str>) -> Result<Vec<String>> {
+        let mut input = vec![
+            ModerationsInput {
+                r#type: "text".into(),
+                text: Some(text.into()),
+                image_url: None,
+            },
+        ];
+        if let Some(image_url) = image_url {
+            input.push(ModerationsInput {
+                r#type: "image_url".into(),
+                text: None,
+                image_url: Some(ModerationsImageURL { url: image_url.into() }),
+            });
+        }
+        let request_body = ModerationsRequestBody {
+            model: "omni-moderation-latest".into(),
+            input,
+        };
+        let response = self.client.post("https://api.openai.com/v1/moderations")
+            .header("Content-type", "application/json; charset=utf-8")
+            .header("Authorization", ["Bearer", &self.api_key.get().await].join(" "))
+            .json(&request_body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let text = response.text().await?;
+            bail!("moderations response failure. {}", text);
+        }
+        let response: ModerationsResponseBody = response.json().await?;
+        let categories = response.results.into_iter()
+            .flat_map(|v| v.categories)
+            .filter(|(_, flagged)| *flagged)
+            .map(|(category, _)| category)
+            .collect();
+        Ok(categories)
+    }
 }
@@ -0,0 +1,183 @@
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use cores::config::ConfigLoader;
+use cores::ipc::InvokeMessage;
+use cores::metrics;
+use cores::queue::{DeadLetterSink, FileDeadLetterSink, FileQueue, MessageQueue, QueueMessage, QueuePolicy};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::config::Config;
+use crate::message::MessageHandle;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct QueueConfig {
+    pub policy: QueuePolicy,
+    // the file queue consumed with YOSHINO_WORKER_MODE=queue
+    pub dir: PathBuf,
+    pub dead_letter_path: PathBuf,
+    pub metrics_addr: String,
+}
+
+impl QueueConfig {
+    // YOSHINO_QUEUE_MAX_ATTEMPTS must match the maxReceiveCount of the SQS redrive policy
+    pub fn load(loader: &mut ConfigLoader) -> Self {
+        let max_attempts = loader.parse("YOSHINO_QUEUE_MAX_ATTEMPTS", 3);
+        // longer than the worker's timeout so that a message is not redelivered while being handled
+        let visibility_timeout = loader.parse("YOSHINO_QUEUE_VISIBILITY_TIMEOUT", 360);
+        let policy = QueuePolicy {
+            visibility_timeout: Duration::from_secs(visibility_timeout),
+            max_attempts,
+        };
+        let dir = loader.optional("YOSHINO_QUEUE_DIR")
+            .unwrap_or_else(|| "/tmp/yoshino-radio-queue".into());
+        let dead_letter_path = loader.optional("YOSHINO_DEAD_LETTER_PATH")
+            .unwrap_or_else(|| "/tmp/yoshino-radio-dead-letters.jsonl".into());
+        let metrics_addr = loader.optional("YOSHINO_METRICS_ADDR")
+            .unwrap_or_else(|| "0.0.0.0:9090".into());
+        Self {
+            policy,
+            dir: PathBuf::from(dir),
+            dead_letter_path: PathBuf::from(dead_letter_path),
+            metrics_addr,
+        }
+    }
+}
+
+// https://docs.aws.amazon.com/lambda/latest/dg/with-sqs.html
+#[derive(Deserialize)]
+pub struct SqsEvent {
+    #[serde(rename = "Records")]
+    records: Vec<SqsRecord>,
+}
+
+#[derive(Deserialize)]
+struct SqsRecord {
+    #[serde(rename = "messageId")]
+    message_id: String,
+    body: String,
+    attributes: SqsRecordAttributes,
+}
+
+#[derive(Deserialize)]
+struct SqsRecordAttributes {
+    #[serde(rename = "ApproximateReceiveCount")]
+    approximate_receive_count: String,
+}
+
+// https://docs.aws.amazon.com/lambda/latest/dg/services-sqs-errorhandling.html#services-sqs-batchfailurereporting
+#[derive(Serialize)]
+pub struct SqsBatchResponse {
+    #[serde(rename = "batchItemFailures")]
+    batch_item_failures: Vec<SqsBatchItemFailure>,
+}
+
+#[derive(Serialize)]
+struct SqsBatchItemFailure {
+    #[serde(rename = "itemIdentifier")]
+    item_identifier: String,
+}
+
+// Messages delivered by the SQS event source mapping.
+// Failed records are reported back for redelivery, SQS moves them to the dead-letter queue after the final attempt.
+pub async fn handle_sqs_event(config: &Arc<Config>, event: SqsEvent) -> SqsBatchResponse {
+    let policy = &config.queue.policy;
+    let mut batch_item_failures = vec![];
+    for record in event.records {
+        let message = QueueMessage {
+            id: record.message_id,
+            receipt: String::new(),
+            body: record.body,
+            attempts: record.attributes.approximate_receive_count.parse().unwrap_or(1),
+        };
+        if let Err(error) = process(config, &message).await {
+            info!("queue message {} failed attempt {} {:?}", message.id, message.attempts, error);
+            if policy.is_final_attempt(&message) {
+                metrics::increment("dead_letters", &[]);
+                notify_failure(config, &message).await;
+            }
+            batch_item_failures.push(SqsBatchItemFailure {
+                item_identifier: message.id,
+            });
+        }
+    }
+    SqsBatchResponse {
+        batch_item_failures,
+    }
+}
+
+// YOSHINO_WORKER_MODE=queue polls the file queue at YOSHINO_QUEUE_DIR,
+// dead letters are appended to YOSHINO_DEAD_LETTER_PATH
+// and metrics are served at YOSHINO_METRICS_ADDR/metrics
+pub async fn run_queue_consumer(config: Arc<Config>) -> Result<()> {
+    let metrics_addr = config.queue.metrics_addr.clone();
+    tokio::spawn(async move {
+        if let Err(error) = metrics::serve_prometheus(&metrics_addr).await {
+            info!("metrics server terminated {:?}", error);
+        }
+    });
+    let queue: Arc<dyn MessageQueue> = FileQueue::new(config.queue.dir.clone())?;
+    let dead_letter_sink: Arc<dyn DeadLetterSink> = FileDeadLetterSink::new(config.queue.dead_letter_path.clone());
+    let policy = config.queue.policy;
+    info!("consuming queue with {:?}", policy);
+    loop {
+        let messages = queue.receive(1, policy.visibility_timeout).await?;
+        if messages.is_empty() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            continue;
+        }
+        for message in messages {
+            consume(&config, &queue, &dead_letter_sink, &policy, message).await?;
+        }
+    }
+}
+
+async fn consume(config: &Arc<Config>, queue: &Arc<dyn MessageQueue>, dead_letter_sink: &Arc<dyn DeadLetterSink>, policy: &QueuePolicy, message: QueueMessage) -> Result<()> {
+    // an earlier consumer died while handling the final attempt
+    if message.attempts > policy.max_attempts {
+        metrics::increment("dead_letters", &[]);
+        dead_letter_sink.dead_letter(&message, "max attempts exceeded").await?;
+        queue.ack(&message.receipt).await?;
+        return Ok(())
+    }
+    match process(config, &message).await {
+        Ok(()) => queue.ack(&message.receipt).await?,
+        Err(error) => {
+            info!("queue message {} failed attempt {} {:?}", message.id, message.attempts, error);
+            if policy.is_final_attempt(&message) {
+                metrics::increment("dead_letters", &[]);
+                notify_failure(config, &message).await;
+                dead_letter_sink.dead_letter(&message, &format!("{:?}", error)).await?;
+                queue.ack(&message.receipt).await?;
+            }
+            // otherwise redelivered once the visibility timeout expires
+        }
+    }
+    Ok(())
+}
+
+async fn process(config: &Arc<Config>, message: &QueueMessage) -> Result<()> {
+    if message.attempts > 1 {
+        metrics::increment("retries", &[("source", "queue")]);
+    }
+    let invoke_message = InvokeMessage::decode(&message.body)?;
+    let handle = MessageHandle::new(config, invoke_message.team_id.as_deref()).await?;
+    handle.handle_message(invoke_message).await
+}
+
+// lets the user know that the message was given up on
+async fn notify_failure(config: &Arc<Config>, message: &QueueMessage) {
+    let result = async {
+        let invoke_message = InvokeMessage::decode(&message.body)?;
+        let handle = MessageHandle::new(config, invoke_message.team_id.as_deref()).await?;
+        handle.notify_failure(&invoke_message).await
+    };
+    if let Err(error) = result.await {
+        info!("failure notification for {} failed {:?}", message.id, error);
+    }
+}
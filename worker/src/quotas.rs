@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, Context};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::types::AttributeValue;
+use cores::config::ConfigLoader;
+use serde::{Deserialize, Serialize};
+use tiktoken_rs::CoreBPE;
+use tokio::sync::Mutex;
+
+// counts within fixed windows, a count starts over at the next window
+#[async_trait]
+pub trait CounterStore: Send + Sync {
+    async fn get(&self, key: &str, window: Duration) -> Result<u64>;
+    // Adds in one step with checking the limit, so that concurrent messages cannot both take the last one.
+    // Returns false without adding when the count would exceed the limit.
+    // A negative amount takes back an earlier add and is never limited.
+    async fn add(&self, key: &str, amount: i64, window: Duration, limit: Option<u64>) -> Result<bool>;
+}
+
+// YOSHINO_COUNTER_STORE selects the implementation
+// * `dynamodb` (default) the table YOSHINO_COUNTER_TABLE, YOSHINO_COUNTER_DYNAMODB_ENDPOINT points at DynamoDB Local
+// * `file` a JSON file at YOSHINO_COUNTER_PATH
+// * `memory` only survives within a warm execution environment
+async fn load_counter_store(loader: &mut ConfigLoader) -> Arc<dyn CounterStore> {
+    let kind = loader.optional("YOSHINO_COUNTER_STORE").unwrap_or_else(|| "dynamodb".into());
+    match kind.as_str() {
+        "dynamodb" => {
+            let table = loader.optional("YOSHINO_COUNTER_TABLE")
+                .unwrap_or_else(|| "yoshino-radio-counters".into());
+            let endpoint = loader.optional("YOSHINO_COUNTER_DYNAMODB_ENDPOINT");
+            DynamoDbCounterStore::new(&table, endpoint.as_deref()).await
+        },
+        "file" => FileCounterStore::new(PathBuf::from(loader.required("YOSHINO_COUNTER_PATH"))),
+        "memory" => MemoryCounterStore::new(),
+        _ => {
+            loader.invalid(format!("YOSHINO_COUNTER_STORE has an invalid value {:?}: expected dynamodb, file or memory", kind));
+            MemoryCounterStore::new()
+        },
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct Counter {
+    count: u64,
+    // unix seconds
+    expires_at: u64,
+}
+
+// the key of the window the current time falls in
+fn windowed_key(key: &str, window: Duration, now: u64) -> (String, u64) {
+    let window = window.as_secs().max(1);
+    let index = now / window;
+    (format!("{}@{}", key, index), (index + 1) * window)
+}
+
+fn add_counter(counters: &mut HashMap<String, Counter>, key: &str, amount: i64, window: Duration, limit: Option<u64>) -> bool {
+    let now = unix_now();
+    counters.retain(|_, v| v.expires_at > now);
+    let (key, expires_at) = windowed_key(key, window, now);
+    let counter = counters.entry(key)
+        .or_insert(Counter { count: 0, expires_at });
+    if amount > 0 && limit.is_some_and(|v| counter.count + amount as u64 > v) {
+        return false
+    }
+    counter.count = counter.count.saturating_add_signed(amount);
+    true
+}
+
+fn get_counter(counters: &HashMap<String, Counter>, key: &str, window: Duration) -> u64 {
+    let (key, _) = windowed_key(key, window, unix_now());
+    counters.get(&key)
+        .map(|v| v.count)
+        .unwrap_or(0)
+}
+
+pub struct MemoryCounterStore {
+    counters: Mutex<HashMap<String, Counter>>,
+}
+
+impl MemoryCounterStore {
+    pub fn new() -> Arc<Self> {
+        let this = Self {
+            counters: Mutex::new(HashMap::new()),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl CounterStore for MemoryCounterStore {
+    async fn get(&self, key: &str, window: Duration) -> Result<u64> {
+        let counters = self.counters.lock().await;
+        Ok(get_counter(&counters, key, window))
+    }
+
+    async fn add(&self, key: &str, amount: i64, window: Duration, limit: Option<u64>) -> Result<bool> {
+        let mut counters = self.counters.lock().await;
+        Ok(add_counter(&mut counters, key, amount, window, limit))
+    }
+}
+
+pub struct FileCounterStore {
+    path: PathBuf,
+    // serializes read-modify-write cycles within the process
+    lock: Mutex<()>,
+}
+
+impl FileCounterStore {
+    pub fn new(path: PathBuf) -> Arc<Self> {
+        let this = Self {
+            path,
+            lock: Mutex::new(()),
+        };
+        Arc::new(this)
+    }
+
+    fn read(&self) -> Result<HashMap<String, Counter>> {
+        if !self.path.exists() {
+            return Ok(HashMap::new())
+        }
+        let text = fs::read_to_string(&self.path)?;
+        let counters = serde_json::from_str(&text)
+            .with_context(|| format!("broken counter file {:?}", self.path))?;
+        Ok(counters)
+    }
+}
+
+#[async_trait]
+impl CounterStore for FileCounterStore {
+    async fn get(&self, key: &str, window: Duration) -> Result<u64> {
+        let _guard = self.lock.lock().await;
+        let counters = self.read()?;
+        Ok(get_counter(&counters, key, window))
+    }
+
+    async fn add(&self, key: &str, amount: i64, window: Duration, limit: Option<u64>) -> Result<bool> {
+        let _guard = self.lock.lock().await;
+        let mut counters = self.read()?;
+        let added = add_counter(&mut counters, key, amount, window, limit);
+        fs::write(&self.path, serde_json::to_string(&counters)?)?;
+        Ok(added)
+    }
+}
+
+// Expects a table with the string partition key `counter`, the windowed key, and the number attribute `count`.
+// Enable TTL on `expires_at` to drop the counts of past windows.
+pub struct DynamoDbCounterStore {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+}
+
+impl DynamoDbCounterStore {
+    pub async fn new(table: &str, endpoint: Option<&str>) -> Arc<Self> {
+        let client = cores::dynamodb::client(endpoint).await;
+        let this = Self {
+            client,
+            table: table.into(),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl CounterStore for DynamoDbCounterStore {
+    async fn get(&self, key: &str, window: Duration) -> Result<u64> {
+        let (key, _) = windowed_key(key, window, unix_now());
+        let output = self.client.get_item()
+            .table_name(&self.table)
+            .key("counter", AttributeValue::S(key))
+            .consistent_read(true)
+            .send()
+            .await?;
+        let count = match output.item.as_ref().and_then(|v| v.get("count")) {
+            Some(AttributeValue::N(count)) => count.parse()?,
+            _ => 0,
+        };
+        Ok(count)
+    }
+
+    async fn add(&self, key: &str, amount: i64, window: Duration, limit: Option<u64>) -> Result<bool> {
+        let (key, expires_at) = windowed_key(key, window, unix_now());
+        let mut request = self.client.update_item()
+            .table_name(&self.table)
+            .key("counter", AttributeValue::S(key))
+            .update_expression("ADD #count :amount SET expires_at = if_not_exists(expires_at, :expires_at)")
+            .expression_attribute_names("#count", "count")
+            .expression_attribute_values(":amount", AttributeValue::N(amount.to_string()))
+            .expression_attribute_values(":expires_at", AttributeValue::N(expires_at.to_string()));
+        if let (true, Some(limit)) = (amount > 0, limit) {
+            let Some(max) = limit.checked_sub(amount as u64) else { return Ok(false) };
+            request = request.condition_expression("attribute_not_exists(#count) OR #count <= :max")
+                .expression_attribute_values(":max", AttributeValue::N(max.to_string()));
+        }
+        match request.send().await {
+            Ok(_) => Ok(true),
+            Err(error) => {
+                let error = error.into_service_error();
+                if error.is_conditional_check_failed_exception() {
+                    return Ok(false)
+                }
+                Err(error.into())
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaScope {
+    User,
+    Channel,
+    Workspace,
+}
+
+impl fmt::Display for QuotaScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User => write!(f, "user"),
+            Self::Channel => write!(f, "channel"),
+            Self::Workspace => write!(f, "workspace"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaMetric {
+    MessagesPerMinute,
+    TokensPerDay,
+    ImagesPerDay,
+}
+
+impl QuotaMetric {
+    const ALL: [Self; 3] = [Self::MessagesPerMinute, Self::TokensPerDay, Self::ImagesPerDay];
+
+    fn window(&self) -> Duration {
+        match self {
+            Self::MessagesPerMinute => Duration::from_secs(60),
+            Self::TokensPerDay | Self::ImagesPerDay => Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::MessagesPerMinute => "messages_per_minute",
+            Self::TokensPerDay => "tokens_per_day",
+            Self::ImagesPerDay => "images_per_day",
+        }
+    }
+}
+
+impl fmt::Display for QuotaMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// unset limits are not enforced
+#[derive(Default, Clone, Copy, Debug)]
+pub struct QuotaLimits {
+    pub messages_per_minute: Option<u64>,
+    pub tokens_per_day: Option<u64>,
+    pub images_per_day: Option<u64>,
+}
+
+impl QuotaLimits {
+    // e.g. YOSHINO_QUOTA_USER_MESSAGES_PER_MINUTE for the `USER` scope,
+    // a value that is not a number fails the configuration instead of lifting the limit
+    fn load(loader: &mut ConfigLoader, scope: &str) -> Self {
+        let mut limit = |metric: QuotaMetric| {
+            let name = format!("YOSHINO_QUOTA_{}_{}", scope, metric.name().to_uppercase());
+            loader.parse_optional(&name)
+        };
+        Self {
+            messages_per_minute: limit(QuotaMetric::MessagesPerMinute),
+            tokens_per_day: limit(QuotaMetric::TokensPerDay),
+            images_per_day: limit(QuotaMetric::ImagesPerDay),
+        }
+    }
+
+    fn get(&self, metric: QuotaMetric) -> Option<u64> {
+        match metric {
+            QuotaMetric::MessagesPerMinute => self.messages_per_minute,
+            QuotaMetric::TokensPerDay => self.tokens_per_day,
+            QuotaMetric::ImagesPerDay => self.images_per_day,
+        }
+    }
+}
+
+// who a message counts against
+pub struct QuotaSubject<'a> {
+    pub team: Option<&'a str>,
+    pub channel: &'a str,
+    pub user: &'a str,
+}
+
+impl QuotaSubject<'_> {
+    fn scopes(&self) -> Vec<(QuotaScope, &str)> {
+        let mut scopes = vec![
+            (QuotaScope::User, self.user),
+            (QuotaScope::Channel, self.channel),
+        ];
+        if let Some(team) = self.team {
+            scopes.push((QuotaScope::Workspace, team));
+        }
+        scopes
+    }
+}
+
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub scope: QuotaScope,
+    pub metric: QuotaMetric,
+}
+
+pub struct QuotaUsage {
+    pub scope: QuotaScope,
+    pub id: String,
+    pub metric: QuotaMetric,
+    pub used: u64,
+    pub limit: Option<u64>,
+}
+
+pub struct Quotas {
+    store: Arc<dyn CounterStore>,
+    user: QuotaLimits,
+    channel: QuotaLimits,
+    workspace: QuotaLimits,
+}
+
+impl Quotas {
+    pub async fn load(loader: &mut ConfigLoader) -> Arc<Self> {
+        let this = Self {
+            store: load_counter_store(loader).await,
+            user: QuotaLimits::load(loader, "USER"),
+            channel: QuotaLimits::load(loader, "CHANNEL"),
+            workspace: QuotaLimits::load(loader, "WORKSPACE"),
+        };
+        Arc::new(this)
+    }
+
+    fn limits(&self, scope: QuotaScope) -> &QuotaLimits {
+        match scope {
+            QuotaScope::User => &self.user,
+            QuotaScope::Channel => &self.channel,
+            QuotaScope::Workspace => &self.workspace,
+        }
+    }
+
+    fn key(scope: QuotaScope, id: &str, metric: QuotaMetric) -> String {
+        format!("{}:{}:{}", scope, id, metric)
+    }
+
+    // Checks every limit before a message is answered and counts the message once it is admitted.
+    // The counts are added against their limits one by one and taken back when a later one is exceeded.
+    pub async fn admit(&self, subject: &QuotaSubject<'_>, images: u64) -> Result<Option<QuotaExceeded>> {
+        // tokens are counted after the answer, so the day's budget only runs out once exceeded
+        for (scope, id) in subject.scopes() {
+            let metric = QuotaMetric::TokensPerDay;
+            let Some(limit) = self.limits(scope).get(metric) else { continue };
+            let used = self.store.get(&Self::key(scope, id, metric), metric.window()).await?;
+            if used >= limit {
+                return Ok(Some(QuotaExceeded { scope, metric }))
+            }
+        }
+        let mut added = vec![];
+        for (scope, id) in subject.scopes() {
+            let requests = [
+                (QuotaMetric::MessagesPerMinute, 1),
+                (QuotaMetric::ImagesPerDay, images),
+            ];
+            for (metric, amount) in requests {
+                if amount == 0 {
+                    continue;
+                }
+                let key = Self::key(scope, id, metric);
+                let limit = self.limits(scope).get(metric);
+                if !self.store.add(&key, amount as i64, metric.window(), limit).await? {
+                    self.take_back(added).await?;
+                    return Ok(Some(QuotaExceeded { scope, metric }))
+                }
+                added.push((key, amount, metric));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn take_back(&self, added: Vec<(String, u64, QuotaMetric)>) -> Result<()> {
+        for (key, amount, metric) in added {
+            self.store.add(&key, -(amount as i64), metric.window(), None).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn record_tokens(&self, subject: &QuotaSubject<'_>, tokens: u64) -> Result<()> {
+        for (scope, id) in subject.scopes() {
+            self.add(scope, id, QuotaMetric::TokensPerDay, tokens).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn usage(&self, subject: &QuotaSubject<'_>) -> Result<Vec<QuotaUsage>> {
+        let mut usage = vec![];
+        for (scope, id) in subject.scopes() {
+            for metric in QuotaMetric::ALL {
+                let used = self.store.get(&Self::key(scope, id, metric), metric.window()).await?;
+                usage.push(QuotaUsage {
+                    scope,
+                    id: id.into(),
+                    metric,
+                    used,
+                    limit: self.limits(scope).get(metric),
+                });
+            }
+        }
+        Ok(usage)
+    }
+
+    async fn add(&self, scope: QuotaScope, id: &str, metric: QuotaMetric, amount: u64) -> Result<()> {
+        self.store.add(&Self::key(scope, id, metric), amount as i64, metric.window(), None).await?;
+        Ok(())
+    }
+}
+
+// Streams stopped early never receive the reported usage, so their tokens are counted with the model's encoding.
+// Models tiktoken does not know yet are counted with cl100k_base.
+pub struct Tokenizer {
+    bpe: CoreBPE,
+}
+
+impl Tokenizer {
+    pub fn new(model: &str) -> Result<Arc<Self>> {
+        let bpe = tiktoken_rs::get_bpe_from_model(model)
+            .or_else(|_| tiktoken_rs::cl100k_base())
+            .with_context(|| format!("failed to load the tokenizer for {}", model))?;
+        Ok(Arc::new(Self { bpe }))
+    }
+
+    pub fn count(&self, text: &str) -> u64 {
+        self.bpe.encode_with_special_tokens(text).len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quotas(user: QuotaLimits, channel: QuotaLimits) -> Quotas {
+        Quotas {
+            store: MemoryCounterStore::new(),
+            user,
+            channel,
+            workspace: QuotaLimits::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn takes_back_the_counts_of_a_refused_message() {
+        let user = QuotaLimits { messages_per_minute: Some(2), ..Default::default() };
+        let channel = QuotaLimits { images_per_day: Some(1), ..Default::default() };
+        let quotas = quotas(user, channel);
+        let subject = QuotaSubject { team: None, channel: "C1", user: "U1" };
+        let exceeded = quotas.admit(&subject, 2).await.unwrap().unwrap();
+        assert_eq!((exceeded.scope, exceeded.metric), (QuotaScope::Channel, QuotaMetric::ImagesPerDay));
+        assert!(quotas.admit(&subject, 0).await.unwrap().is_none());
+        assert!(quotas.admit(&subject, 1).await.unwrap().is_none());
+        let exceeded = quotas.admit(&subject, 0).await.unwrap().unwrap();
+        assert_eq!((exceeded.scope, exceeded.metric), (QuotaScope::User, QuotaMetric::MessagesPerMinute));
+    }
+
+    #[tokio::test]
+    async fn runs_out_of_tokens_once_exceeded() {
+        let user = QuotaLimits { tokens_per_day: Some(100), ..Default::default() };
+        let quotas = quotas(user, QuotaLimits::default());
+        let subject = QuotaSubject { team: None, channel: "C1", user: "U1" };
+        quotas.record_tokens(&subject, 99).await.unwrap();
+        assert!(quotas.admit(&subject, 0).await.unwrap().is_none());
+        quotas.record_tokens(&subject, 1).await.unwrap();
+        assert!(quotas.admit(&subject, 0).await.unwrap().is_some());
+    }
+
+    #[test]
+    fn counts_japanese_with_the_tokenizer() {
+        let tokenizer = Tokenizer::new("gpt-4-turbo").unwrap();
+        assert_eq!(tokenizer.count("hello world"), 2);
+        assert!(tokenizer.count("明日の天気は？") > 0);
+    }
+}
@@ -0,0 +1,424 @@
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Result, Context};
+use cores::config::ConfigLoader;
+use cores::secrets::Secret;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use tracing::info;
+
+use crate::embeddings::{Embedder, cosine_similarity, load_embedder};
+
+// chunks are roughly this many characters, split at paragraph boundaries
+const CHUNK_CHARS: usize = 800;
+const EMBEDDING_BATCH_SIZE: usize = 64;
+
+// the index is a flat JSON file rebuilt whenever the corpus or the embedding model changes
+#[derive(Serialize, Deserialize)]
+struct DocumentIndex {
+    model: String,
+    sources: Vec<SourceFingerprint>,
+    chunks: Vec<DocumentChunk>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct SourceFingerprint {
+    path: String,
+    len: u64,
+    modified: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DocumentChunk {
+    source: String,
+    heading: Option<String>,
+    text: String,
+    embedding: Vec<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Passage {
+    pub source: String,
+    pub heading: Option<String>,
+    pub text: String,
+    pub score: f32,
+}
+
+impl Passage {
+    pub fn title(&self) -> String {
+        match self.heading {
+            Some(ref heading) => format!("{} § {}", self.source, heading),
+            None => self.source.clone(),
+        }
+    }
+}
+
+// YOSHINO_RETRIEVAL_MIN_SCORE drops passages less similar to the prompt than this, so that an unrelated
+// question is not grounded in whatever happens to rank first
+const DEFAULT_MIN_SCORE: f32 = 0.3;
+
+pub struct Retriever {
+    embedder: Arc<dyn Embedder>,
+    corpus_dir: PathBuf,
+    index_path: PathBuf,
+    top_k: usize,
+    min_score: f32,
+    // loaded on the first search and kept for the life of the process, a failed load is retried on the next one
+    index: OnceCell<DocumentIndex>,
+}
+
+impl Retriever {
+    // enabled when YOSHINO_CORPUS_DIR points at a directory of Markdown/text files
+    pub fn load(loader: &mut ConfigLoader, openai_api_key: &Secret) -> Option<Arc<Self>> {
+        let corpus_dir = loader.optional("YOSHINO_CORPUS_DIR")?;
+        let index_path = loader.optional("YOSHINO_INDEX_PATH")
+            .unwrap_or_else(|| "/tmp/yoshino-radio-index.json".into());
+        let top_k = loader.parse("YOSHINO_RETRIEVAL_TOP_K", 4);
+        if top_k == 0 {
+            loader.invalid("YOSHINO_RETRIEVAL_TOP_K must be at least 1".into());
+        }
+        let min_score = loader.parse("YOSHINO_RETRIEVAL_MIN_SCORE", DEFAULT_MIN_SCORE);
+        if !(-1.0..=1.0).contains(&min_score) {
+            loader.invalid(format!("YOSHINO_RETRIEVAL_MIN_SCORE must be between -1 and 1, got {}", min_score));
+        }
+        let embedder = load_embedder(loader, openai_api_key)?;
+        Some(Self::new(PathBuf::from(corpus_dir), PathBuf::from(index_path), embedder, top_k, min_score))
+    }
+
+    pub fn new(corpus_dir: PathBuf, index_path: PathBuf, embedder: Arc<dyn Embedder>, top_k: usize, min_score: f32) -> Arc<Self> {
+        let this = Self {
+            embedder,
+            corpus_dir,
+            index_path,
+            top_k,
+            min_score,
+            index: OnceCell::new(),
+        };
+        Arc::new(this)
+    }
+
+    async fn index(&self) -> Result<&DocumentIndex> {
+        self.index.get_or_try_init(|| Self::load_or_build(&self.corpus_dir, &self.index_path, self.embedder.as_ref())).await
+    }
+
+    async fn load_or_build(corpus_dir: &Path, index_path: &Path, embedder: &dyn Embedder) -> Result<DocumentIndex> {
+        let sources = Self::fingerprints(corpus_dir)?;
+        let cached = fs::read_to_string(index_path).ok()
+            .and_then(|v| serde_json::from_str::<DocumentIndex>(&v).ok())
+            .filter(|v| v.model == embedder.model() && v.sources == sources);
+        if let Some(index) = cached {
+            return Ok(index)
+        }
+        info!("building document index for {:?}...", corpus_dir);
+        let index = Self::build(corpus_dir, sources, embedder).await?;
+        fs::write(index_path, serde_json::to_string(&index)?)
+            .with_context(|| format!("failed to write index {:?}", index_path))?;
+        info!("document index built: {} chunks", index.chunks.len());
+        Ok(index)
+    }
+
+    // at most top_k passages scoring at least min_score, best first
+    pub async fn search(&self, query: &str) -> Result<Vec<Passage>> {
+        if query.trim().is_empty() {
+            return Ok(vec![])
+        }
+        let index = self.index().await?;
+        if index.chunks.is_empty() {
+            return Ok(vec![])
+        }
+        let embeddings = self.embedder.embed(&[query.to_string()]).await?;
+        let query_embedding = embeddings.first().context("missing query embedding")?;
+        let mut passages: Vec<Passage> = index.chunks.iter()
+            .map(|chunk| Passage {
+                source: chunk.source.clone(),
+                heading: chunk.heading.clone(),
+                text: chunk.text.clone(),
+                score: cosine_similarity(query_embedding, &chunk.embedding),
+            })
+            .filter(|v| v.score >= self.min_score)
+            .collect();
+        passages.sort_by(|a, b| b.score.total_cmp(&a.score));
+        passages.truncate(self.top_k);
+        Ok(passages)
+    }
+
+    async fn build(corpus_dir: &Path, sources: Vec<SourceFingerprint>, embedder: &dyn Embedder) -> Result<DocumentIndex> {
+        let mut chunks = vec![];
+        for source in sources.iter() {
+            let text = fs::read_to_string(corpus_dir.join(&source.path))?;
+            chunks.extend(chunk_document(&source.path, &text));
+        }
+        let texts: Vec<String> = chunks.iter()
+            .map(|(_, heading, text)| match heading {
+                Some(heading) => format!("{}\n{}", heading, text),
+                None => text.clone(),
+            })
+            .collect();
+        let mut embeddings = vec![];
+        for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+            embeddings.extend(embedder.embed(batch).await?);
+        }
+        let chunks = chunks.into_iter()
+            .zip(embeddings)
+            .map(|((source, heading, text), embedding)| DocumentChunk {
+                source,
+                heading,
+                text,
+                embedding,
+            })
+            .collect();
+        let index = DocumentIndex {
+            model: embedder.model(),
+            sources,
+            chunks,
+        };
+        Ok(index)
+    }
+
+    fn fingerprints(corpus_dir: &Path) -> Result<Vec<SourceFingerprint>> {
+        let mut paths = vec![];
+        collect_documents(corpus_dir, &mut paths)
+            .with_context(|| format!("failed to read corpus {:?}", corpus_dir))?;
+        paths.sort();
+        let mut sources = vec![];
+        for path in paths {
+            let metadata = fs::metadata(&path)?;
+            let modified = metadata.modified()?
+                .duration_since(UNIX_EPOCH)?
+                .as_secs();
+            let relative = path.strip_prefix(corpus_dir)?
+                .to_string_lossy()
+                .to_string();
+            sources.push(SourceFingerprint {
+                path: relative,
+                len: metadata.len(),
+                modified,
+            });
+        }
+        Ok(sources)
+    }
+}
+
+fn collect_documents(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_documents(&path, paths)?;
+            continue;
+        }
+        let extension = path.extension()
+            .and_then(|v| v.to_str())
+            .unwrap_or_default();
+        if matches!(extension, "md" | "markdown" | "txt") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+// splits at Markdown headings, then packs paragraphs into chunks of about CHUNK_CHARS,
+// a paragraph longer than that is split into its sentences first
+fn chunk_document(source: &str, text: &str) -> Vec<(String, Option<String>, String)> {
+    let mut chunks = vec![];
+    let mut heading: Option<String> = None;
+    let mut current = String::new();
+    let mut flush = |heading: &Option<String>, current: &mut String| {
+        let text = current.trim();
+        if !text.is_empty() {
+            chunks.push((source.to_string(), heading.clone(), text.to_string()));
+        }
+        current.clear();
+    };
+    for paragraph in text.split("\n\n") {
+        let trimmed = paragraph.trim();
+        if let Some(title) = trimmed.strip_prefix('#') {
+            flush(&heading, &mut current);
+            let title = title.trim_start_matches('#').trim();
+            let (title, rest) = title.split_once('\n').unwrap_or((title, ""));
+            heading = Some(title.trim().to_string());
+            current.push_str(rest.trim());
+            continue;
+        }
+        // the sentences of a split paragraph keep running on in a chunk
+        for (index, piece) in split_paragraph(trimmed).into_iter().enumerate() {
+            if current.chars().count() + piece.chars().count() > CHUNK_CHARS {
+                flush(&heading, &mut current);
+            }
+            if !current.is_empty() && index == 0 {
+                current.push_str("\n\n");
+            }
+            current.push_str(piece);
+        }
+    }
+    flush(&heading, &mut current);
+    chunks
+}
+
+// a paragraph of at most CHUNK_CHARS as it is, a longer one at sentence ends,
+// and a sentence that is still too long every CHUNK_CHARS characters
+fn split_paragraph(paragraph: &str) -> Vec<&str> {
+    if paragraph.chars().count() <= CHUNK_CHARS {
+        return vec![paragraph]
+    }
+    let mut pieces = vec![];
+    let mut start = 0;
+    for (index, c) in paragraph.char_indices() {
+        if matches!(c, '。' | '！' | '？' | '.' | '!' | '?' | '\n') {
+            let end = index + c.len_utf8();
+            pieces.push(&paragraph[start..end]);
+            start = end;
+        }
+    }
+    if start < paragraph.len() {
+        pieces.push(&paragraph[start..]);
+    }
+    let mut split = vec![];
+    for piece in pieces {
+        let mut rest = piece;
+        while rest.chars().count() > CHUNK_CHARS {
+            let (end, _) = rest.char_indices().nth(CHUNK_CHARS).unwrap();
+            split.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+        if !rest.is_empty() {
+            split.push(rest);
+        }
+    }
+    split
+}
+
+// passages are numbered so that the answer can cite them as [1], [2], ...
+pub fn passages_prompt(passages: &[Passage]) -> String {
+    let mut prompt = String::from(
+        "以下はチームの資料からの抜粋です。質問に関係がある場合のみ参考にし、参考にした箇所は [1] のように番号で引用してください。\n");
+    for (index, passage) in passages.iter().enumerate() {
+        prompt += &format!("\n[{}] {}\n{}\n", index + 1, passage.title(), passage.text);
+    }
+    prompt
+}
+
+// lists the passages the answer actually cited
+pub fn citations_footer(content: &str, passages: &[Passage]) -> Option<String> {
+    let cited: Vec<String> = passages.iter()
+        .enumerate()
+        .filter(|(index, _)| content.contains(&format!("[{}]", index + 1)))
+        .map(|(index, passage)| format!("[{}] {}", index + 1, passage.title()))
+        .collect();
+    if cited.is_empty() {
+        return None
+    }
+    Some(format!("_Sources_\n{}", cited.join("\n")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::embeddings::HashingEmbedder;
+
+    // counts the texts embedded, to tell a built index from a loaded one
+    struct CountingEmbedder {
+        inner: Arc<HashingEmbedder>,
+        embedded: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Embedder for CountingEmbedder {
+        fn model(&self) -> String {
+            self.inner.model()
+        }
+
+        async fn embed(&self, inputs: &[String]) -> Result<Vec<Vec<f32>>> {
+            self.embedded.fetch_add(inputs.len(), Ordering::SeqCst);
+            self.inner.embed(inputs).await
+        }
+    }
+
+    fn corpus() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("vacation.md"), "# 休暇\n\n有給休暇の申請はワークフローから三日前までに出してください。").unwrap();
+        fs::write(dir.path().join("expenses.md"), "# Expenses\n\nSubmit expense receipts within thirty days of purchase.").unwrap();
+        fs::write(dir.path().join("notes.bin"), "not a document").unwrap();
+        dir
+    }
+
+    fn retriever(corpus: &tempfile::TempDir, embedder: Arc<dyn Embedder>, min_score: f32) -> Arc<Retriever> {
+        let index_path = corpus.path().join("index.json");
+        Retriever::new(corpus.path().to_path_buf(), index_path, embedder, 4, min_score)
+    }
+
+    #[test]
+    fn chunks_at_headings() {
+        let chunks = chunk_document("a.md", "intro\n\n# One\nfirst\n\nsecond\n\n## Two\n\nthird");
+        let chunks: Vec<(Option<&str>, &str)> = chunks.iter()
+            .map(|(_, heading, text)| (heading.as_deref(), text.as_str()))
+            .collect();
+        assert_eq!(chunks, vec![(None, "intro"), (Some("One"), "first\n\nsecond"), (Some("Two"), "third")]);
+    }
+
+    #[test]
+    fn splits_oversized_paragraphs() {
+        let sentence = "あ".repeat(299) + "。";
+        let paragraph = sentence.repeat(4);
+        let chunks = chunk_document("a.md", &paragraph);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|(_, _, text)| text.chars().count() <= CHUNK_CHARS));
+        assert_eq!(chunks.iter().map(|(_, _, text)| text.as_str()).collect::<String>(), paragraph);
+
+        let unbroken = "い".repeat(CHUNK_CHARS * 2 + 1);
+        let chunks = chunk_document("a.md", &unbroken);
+        let lengths: Vec<usize> = chunks.iter().map(|(_, _, text)| text.chars().count()).collect();
+        assert_eq!(lengths, vec![CHUNK_CHARS, CHUNK_CHARS, 1]);
+    }
+
+    #[tokio::test]
+    async fn ranks_the_relevant_passage_first() {
+        let corpus = corpus();
+        let retriever = retriever(&corpus, HashingEmbedder::new(256), -1.0);
+        let passages = retriever.search("有給休暇の申請はいつまで？").await.unwrap();
+        assert_eq!(passages.len(), 2);
+        assert_eq!(passages[0].title(), "vacation.md § 休暇");
+        assert!(passages[0].score > passages[1].score);
+    }
+
+    #[tokio::test]
+    async fn drops_passages_below_the_threshold() {
+        let corpus = corpus();
+        let retriever = retriever(&corpus, HashingEmbedder::new(256), 0.3);
+        let passages = retriever.search("expense receipts").await.unwrap();
+        assert_eq!(passages.iter().map(|v| v.source.as_str()).collect::<Vec<_>>(), vec!["expenses.md"]);
+        assert!(retriever.search("量子力学").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn builds_the_index_once() {
+        let corpus = corpus();
+        let embedder = Arc::new(CountingEmbedder { inner: HashingEmbedder::new(256), embedded: AtomicUsize::new(0) });
+        let first = retriever(&corpus, embedder.clone(), -1.0);
+        first.search("expenses").await.unwrap();
+        first.search("vacation").await.unwrap();
+        // two chunks, then one query embedding per search
+        assert_eq!(embedder.embedded.load(Ordering::SeqCst), 4);
+
+        // another process finds the index on disk
+        let second = retriever(&corpus, embedder.clone(), -1.0);
+        second.search("expenses").await.unwrap();
+        assert_eq!(embedder.embedded.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn cites_only_the_referenced_passages() {
+        let passage = |source: &str| Passage { source: source.into(), heading: None, text: "text".into(), score: 1.0 };
+        let passages = vec![passage("a.md"), passage("b.md")];
+        assert!(passages_prompt(&passages).contains("\n[2] b.md\ntext\n"));
+        assert_eq!(citations_footer("see [2]", &passages).as_deref(), Some("_Sources_\n[2] b.md"));
+        assert_eq!(citations_footer("nothing cited", &passages), None);
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Duration, Utc};
+use cores::config::{ConfigLoader, StoreKind};
+use cores::metrics;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::moderation::{Classifier, ClassifierKind, KeywordClassifier, KeywordRule, keyword_rules};
+use crate::openai_client::OpenAIClient;
+use crate::slack_client::{RepliesMessage, SlackClient};
+
+// a person may agree to be put in touch with the wellbeing team for this long after an escalation
+const CONSENT_WINDOW_HOURS: i64 = 24;
+
+// the person's latest messages in the thread that are assessed, the prompt included
+const ASSESSED_TURNS: usize = 5;
+
+// The texts of a locale, reviewed by the people responsible for wellbeing rather than generated.
+// `{user}` in the notification is replaced with a mention of the person asking for contact.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SafetyMessages {
+    // answered instead of the model
+    pub support: String,
+    // added to the support message when a wellbeing channel is configured
+    pub offer: String,
+    pub connected: String,
+    pub nothing_pending: String,
+    // posted to the wellbeing channel
+    pub notification: String,
+}
+
+fn builtin_messages() -> HashMap<String, SafetyMessages> {
+    let ja = SafetyMessages {
+        support: "そなたのお気持ち、しかと受け止めましたー。おひとりで抱え込まずに、どうか信頼できる方や、相談の窓口にお話しくださいー
+• いのちの電話 0570-783-556
+• よりそいホットライン 0120-279-338 (24時間)
+• こころの健康相談統一ダイヤル 0570-064-556
+• 今すぐ危ないときは 119 へ
+わたくしも、そなたのお話をお聞きいたしますゆえー".into(),
+        offer: "よろしければ、社内の相談窓口の方へおつなぎいたしますー。一日のうちに `!つないで` とお返事くだされば、お知らせいたしましょうー。お返事がなければ、どなたにもお伝えいたしませぬー".into(),
+        connected: "相談窓口の方へお知らせいたしましたー。追ってご連絡があるはずでしてー".into(),
+        nothing_pending: "ただいま、おつなぎするご相談は見当たりませぬー".into(),
+        notification: "{user} さんが、相談窓口からの連絡を希望されていますー。ご本人の同意のうえでのお知らせで、お話の中身はお伝えしておりませぬー".into(),
+    };
+    let en = SafetyMessages {
+        support: "Thank you for telling me. You don't have to carry this alone, please reach out to someone you trust or to a helpline.
+• If you are in immediate danger, call your local emergency number
+• Find a free, confidential helpline in your country at https://findahelpline.com
+I'm here to listen, too.".into(),
+        offer: "If you'd like, I can let our wellbeing team know so that someone reaches out to you. Reply `!connect` within a day to agree, nothing is shared otherwise.".into(),
+        connected: "I've let our wellbeing team know, someone will reach out to you.".into(),
+        nothing_pending: "There is nothing to pass on to the wellbeing team right now.".into(),
+        notification: "{user} has asked to be contacted by the wellbeing team. They agreed to this notification, the conversation itself is not shared.".into(),
+    };
+    HashMap::from([("ja".to_string(), ja), ("en".to_string(), en)])
+}
+
+// YOSHINO_SAFETY selects the classifier that looks for conversations at risk, only moderation escalates without one
+// * `openai` the moderation endpoint
+// * `keywords` regular expressions, the built-in self-harm ones and the ones in YOSHINO_SAFETY_RULES
+// YOSHINO_SAFETY_CATEGORIES are the categories escalated, comma separated
+// YOSHINO_SAFETY_MESSAGES is a JSON object of locales to SafetyMessages, which override the built-in ja and en ones
+// YOSHINO_SAFETY_LOCALE is used when the person's Slack locale has no messages (default ja)
+// YOSHINO_SAFETY_CHANNEL is the wellbeing channel notified when the person agrees to it
+pub struct SafetyConfig {
+    pub classifier: Option<ClassifierKind>,
+    pub rules: Vec<KeywordRule>,
+    pub categories: Vec<String>,
+    pub messages: HashMap<String, SafetyMessages>,
+    pub default_locale: String,
+    pub channel: Option<String>,
+    pub escalation_log: Arc<dyn EscalationLog>,
+}
+
+impl SafetyConfig {
+    pub async fn load(loader: &mut ConfigLoader) -> Self {
+        let classifier = loader.optional("YOSHINO_SAFETY")
+            .and_then(|v| match v.parse() {
+                Ok(classifier) => Some(classifier),
+                Err(error) => {
+                    loader.invalid(format!("YOSHINO_SAFETY has an invalid value {:?}: {}", v, error));
+                    None
+                },
+            });
+        let rules = match keyword_rules(loader.optional("YOSHINO_SAFETY_RULES").as_deref()) {
+            Ok(rules) => rules,
+            Err(error) => {
+                loader.invalid(format!("YOSHINO_SAFETY_RULES {:#}", error));
+                vec![]
+            },
+        };
+        let categories = loader.optional("YOSHINO_SAFETY_CATEGORIES")
+            .unwrap_or_else(|| "self-harm,self-harm/intent,self-harm/instructions".into())
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        let mut messages = builtin_messages();
+        if let Some(path) = loader.optional("YOSHINO_SAFETY_MESSAGES") {
+            match Self::messages(&path) {
+                Ok(custom) => messages.extend(custom),
+                Err(error) => loader.invalid(format!("YOSHINO_SAFETY_MESSAGES {:#}", error)),
+            }
+        }
+        let default_locale = loader.optional("YOSHINO_SAFETY_LOCALE")
+            .unwrap_or_else(|| "ja".into());
+        if !messages.contains_key(&default_locale) {
+            loader.invalid(format!("YOSHINO_SAFETY_LOCALE {} has no messages", default_locale));
+        }
+        let channel = loader.optional("YOSHINO_SAFETY_CHANNEL");
+        let escalation_log = load_escalation_log(loader).await;
+        Self {
+            classifier,
+            rules,
+            categories,
+            messages,
+            default_locale,
+            channel,
+            escalation_log,
+        }
+    }
+
+    fn messages(path: &str) -> Result<HashMap<String, SafetyMessages>> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path))?;
+        let messages = serde_json::from_str(&text)
+            .with_context(|| format!("broken messages {}", path))?;
+        Ok(messages)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EscalationEventKind {
+    Escalated,
+    // the person agreed to be put in touch and the wellbeing channel was notified
+    Connected,
+}
+
+// one line of the audit log, the conversation itself is never recorded
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EscalationEvent {
+    pub recorded_at: DateTime<Utc>,
+    pub escalation_id: String,
+    pub kind: EscalationEventKind,
+    pub trace_id: String,
+    pub team_id: Option<String>,
+    pub channel: String,
+    pub user: Option<String>,
+    // `safety` or `moderation`
+    pub source: String,
+    pub categories: Vec<String>,
+    pub locale: String,
+    pub notified_channel: Option<String>,
+}
+
+// append-only
+#[async_trait]
+pub trait EscalationLog: Send + Sync {
+    async fn record(&self, event: &EscalationEvent) -> Result<()>;
+    async fn events(&self, user: &str) -> Result<Vec<EscalationEvent>>;
+}
+
+// YOSHINO_ESCALATION_LOG selects the implementation
+// * `dynamodb` (default) the table YOSHINO_ESCALATION_TABLE, YOSHINO_ESCALATION_DYNAMODB_ENDPOINT points at DynamoDB Local
+// * `file` the JSON lines file at YOSHINO_ESCALATION_LOG_PATH, one event per line
+async fn load_escalation_log(loader: &mut ConfigLoader) -> Arc<dyn EscalationLog> {
+    match loader.parse("YOSHINO_ESCALATION_LOG", StoreKind::DynamoDb) {
+        StoreKind::DynamoDb => {
+            let table = loader.optional("YOSHINO_ESCALATION_TABLE")
+                .unwrap_or_else(|| "yoshino-radio-escalations".into());
+            let endpoint = loader.optional("YOSHINO_ESCALATION_DYNAMODB_ENDPOINT");
+            DynamoDbEscalationLog::new(&table, endpoint.as_deref()).await
+        },
+        StoreKind::File => FileEscalationLog::new(PathBuf::from(loader.required("YOSHINO_ESCALATION_LOG_PATH"))),
+    }
+}
+
+pub struct FileEscalationLog {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileEscalationLog {
+    pub fn new(path: PathBuf) -> Arc<Self> {
+        let this = Self {
+            path,
+            lock: Mutex::new(()),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl EscalationLog for FileEscalationLog {
+    async fn record(&self, event: &EscalationEvent) -> Result<()> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        let _guard = self.lock.lock().await;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    async fn events(&self, user: &str) -> Result<Vec<EscalationEvent>> {
+        let _guard = self.lock.lock().await;
+        if !self.path.exists() {
+            return Ok(vec![])
+        }
+        let text = fs::read_to_string(&self.path)?;
+        let events = text.lines()
+            .filter_map(|v| serde_json::from_str::<EscalationEvent>(v).ok())
+            .filter(|v| v.user.as_deref() == Some(user))
+            .collect();
+        Ok(events)
+    }
+}
+
+// Expects a table with the string partition key `user` and the string sort key `id`, the event's time followed by
+// its escalation ID and kind, and keeps the event as JSON in `event`. Events without a user are kept under their channel.
+pub struct DynamoDbEscalationLog {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+}
+
+impl DynamoDbEscalationLog {
+    pub async fn new(table: &str, endpoint: Option<&str>) -> Arc<Self> {
+        let client = cores::dynamodb::client(endpoint).await;
+        let this = Self {
+            client,
+            table: table.into(),
+        };
+        Arc::new(this)
+    }
+}
+
+#[async_trait]
+impl EscalationLog for DynamoDbEscalationLog {
+    async fn record(&self, event: &EscalationEvent) -> Result<()> {
+        let user = match event.user {
+            Some(ref user) => user.clone(),
+            None => format!("channel:{}", event.channel),
+        };
+        let kind = serde_json::to_value(event.kind)?;
+        let id = format!("{}#{}#{}", event.recorded_at.to_rfc3339(), event.escalation_id, kind.as_str().unwrap_or_default());
+        self.client.put_item()
+            .table_name(&self.table)
+            .item("user", AttributeValue::S(user))
+            .item("id", AttributeValue::S(id))
+            .item("event", AttributeValue::S(serde_json::to_string(event)?))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn events(&self, user: &str) -> Result<Vec<EscalationEvent>> {
+        let mut pages = self.client.query()
+            .table_name(&self.table)
+            .key_condition_expression("#user = :user")
+            .expression_attribute_names("#user", "user")
+            .expression_attribute_values(":user", AttributeValue::S(user.into()))
+            .into_paginator()
+            .items()
+            .send();
+        let mut events = vec![];
+        while let Some(item) = pages.next().await {
+            let item = item?;
+            let Some(AttributeValue::S(event)) = item.get("event") else { continue };
+            events.push(serde_json::from_str(event)?);
+        }
+        Ok(events)
+    }
+}
+
+// an escalation answered with the support message, recorded once the message is posted
+pub struct Escalation {
+    pub text: String,
+    event: EscalationEvent,
+}
+
+// the person's earlier messages in the thread followed by the prompt, one per line,
+// messages after the prompt are left out as an edited prompt is answered in place
+pub fn recent_user_turns(messages: &[RepliesMessage], ts: &str, prompt: &str) -> String {
+    let earlier: Vec<&str> = messages.iter()
+        .filter(|v| v.r#type == "message" && v.bot_id.is_none() && v.ts.as_str() < ts)
+        .map(|v| v.text.as_str())
+        .collect();
+    let skipped = earlier.len().saturating_sub(ASSESSED_TURNS - 1);
+    earlier[skipped..].iter()
+        .copied()
+        .chain([prompt])
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+// where an escalated prompt came from
+pub struct EscalationSubject<'a> {
+    pub trace_id: &'a str,
+    pub team_id: Option<&'a str>,
+    pub channel: &'a str,
+    pub user: Option<&'a str>,
+}
+
+// Answers conversations at risk with vetted support messages in the person's language,
+// and puts the person in touch with the wellbeing team only once they agree to it.
+pub struct Safety {
+    classifier: Option<Arc<dyn Classifier>>,
+    categories: Vec<String>,
+    messages: HashMap<String, SafetyMessages>,
+    default_locale: String,
+    channel: Option<String>,
+    slack_client: Arc<SlackClient>,
+    escalation_log: Arc<dyn EscalationLog>,
+}
+
+impl Safety {
+    pub fn new(config: &SafetyConfig, openai_client: &Arc<OpenAIClient>, slack_client: &Arc<SlackClient>) -> Arc<Self> {
+        let classifier: Option<Arc<dyn Classifier>> = match config.classifier {
+            Some(ClassifierKind::OpenAI) => Some(openai_client.clone()),
+            Some(ClassifierKind::Keywords) => Some(KeywordClassifier::new(config.rules.clone())),
+            None => None,
+        };
+        let this = Self {
+            classifier,
+            categories: config.categories.clone(),
+            messages: config.messages.clone(),
+            default_locale: config.default_locale.clone(),
+            channel: config.channel.clone(),
+            slack_client: Arc::clone(slack_client),
+            escalation_log: Arc::clone(&config.escalation_log),
+        };
+        Arc::new(this)
+    }
+
+    // the escalated categories found in the prompt, a failing classifier is logged and lets the prompt through
+    pub async fn assess(&self, text: &str, image_url: Option<&str>) -> Option<Vec<String>> {
+        let classifier = self.classifier.as_ref()?;
+        let categories = match classifier.classify(text, image_url).await {
+            Ok(categories) => categories,
+            Err(error) => {
+                info!(error = ?error, "safety assessment failed");
+                metrics::increment("errors", &[("type", "safety")]);
+                return None
+            },
+        };
+        let categories: Vec<String> = categories.into_iter()
+            .filter(|v| self.categories.contains(v))
+            .collect();
+        (!categories.is_empty()).then_some(categories)
+    }
+
+    // the text to answer with, see record for the audit log
+    pub async fn escalate(&self, subject: &EscalationSubject<'_>, source: &str, categories: Vec<String>) -> Escalation {
+        let locale = self.locale(subject.user).await;
+        let event = EscalationEvent {
+            recorded_at: Utc::now(),
+            escalation_id: Uuid::new_v4().to_string(),
+            kind: EscalationEventKind::Escalated,
+            trace_id: subject.trace_id.into(),
+            team_id: subject.team_id.map(|v| v.into()),
+            channel: subject.channel.into(),
+            user: subject.user.map(|v| v.into()),
+            source: source.into(),
+            categories,
+            locale: locale.clone(),
+            notified_channel: None,
+        };
+        info!(escalation = event.escalation_id, source, categories = ?event.categories, "escalated");
+        metrics::increment("escalations", &[("event", "escalated"), ("source", source)]);
+        let messages = self.messages(&locale);
+        // without a user there is nobody to give consent
+        let text = if self.channel.is_some() && subject.user.is_some() {
+            format!("{}\n\n{}", messages.support, messages.offer)
+        } else {
+            messages.support.clone()
+        };
+        Escalation {
+            text,
+            event,
+        }
+    }
+
+    // The support message goes out first, a failing audit log must not keep it from the person.
+    // An escalation missing from the log cannot be connected later, which is counted as an error.
+    pub async fn record(&self, escalation: &Escalation) {
+        self.record_event(&escalation.event).await
+    }
+
+    async fn record_event(&self, event: &EscalationEvent) {
+        if let Err(error) = self.escalation_log.record(event).await {
+            info!(error = ?error, escalation = event.escalation_id, "failed to record the escalation");
+            metrics::increment("errors", &[("type", "escalation_log")]);
+        }
+    }
+
+    // the support message alone, for answers that turned to self-harm
+    pub async fn support_text(&self, user: Option<&str>) -> String {
+        let locale = self.locale(user).await;
+        self.messages(&locale).support.clone()
+    }
+
+    // notifies the wellbeing channel of the person's latest escalation, once they agreed to it
+    pub async fn connect(&self, subject: &EscalationSubject<'_>) -> Result<String> {
+        let user = subject.user.context("connect needs a user")?;
+        let locale = self.locale(Some(user)).await;
+        let messages = self.messages(&locale);
+        let Some(ref channel) = self.channel else { return Ok(messages.nothing_pending.clone()) };
+        let events = self.escalation_log.events(user).await?;
+        let pending = events.iter()
+            .rev()
+            .take_while(|v| v.kind != EscalationEventKind::Connected)
+            .find(|v| v.kind == EscalationEventKind::Escalated);
+        let Some(pending) = pending else { return Ok(messages.nothing_pending.clone()) };
+        if Utc::now() - pending.recorded_at > Duration::hours(CONSENT_WINDOW_HOURS) {
+            return Ok(messages.nothing_pending.clone())
+        }
+        // the wellbeing team reads the notification in the deployment's language
+        let notification = self.messages(&self.default_locale).notification
+            .replace("{user}", &format!("<@{}>", user));
+        self.slack_client.post(channel, None, notification).await?;
+        let event = EscalationEvent {
+            recorded_at: Utc::now(),
+            kind: EscalationEventKind::Connected,
+            trace_id: subject.trace_id.into(),
+            notified_channel: Some(channel.clone()),
+            ..pending.clone()
+        };
+        info!(escalation = event.escalation_id, "connected to the wellbeing channel");
+        metrics::increment("escalations", &[("event", "connected"), ("source", &event.source)]);
+        self.record_event(&event).await;
+        Ok(messages.connected.clone())
+    }
+
+    // the Slack locale of the user, e.g. ja-JP, falls back to its language and then to the default locale
+    async fn locale(&self, user: Option<&str>) -> String {
+        let locale = match user {
+            Some(user) => match self.slack_client.user_info(user).await {
+                Ok(info) => info.locale,
+                Err(error) => {
+                    info!(error = ?error, "failed to look up the locale");
+                    None
+                },
+            },
+            None => None,
+        };
+        let Some(locale) = locale else { return self.default_locale.clone() };
+        if self.messages.contains_key(&locale) {
+            return locale
+        }
+        let language = locale.split('-').next().unwrap_or_default();
+        if self.messages.contains_key(language) {
+            return language.into()
+        }
+        self.default_locale.clone()
+    }
+
+    fn messages(&self, locale: &str) -> &SafetyMessages {
+        self.messages.get(locale)
+            .or_else(|| self.messages.get(&self.default_locale))
+            .expect("the default locale has messages")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(ts: &str, text: &str, bot_id: Option<&str>) -> RepliesMessage {
+        RepliesMessage {
+            r#type: "message".into(),
+            ts: ts.into(),
+            user: Some("U0123ABCD".into()),
+            text: text.into(),
+            thread_ts: "1712000000.000000".into(),
+            bot_id: bot_id.map(|v| v.into()),
+        }
+    }
+
+    #[test]
+    fn assesses_the_latest_turns_of_the_person() {
+        let messages: Vec<RepliesMessage> = (0..8)
+            .map(|i| message(&format!("171200000{}.000000", i), &format!("turn {}", i), None))
+            .chain([message("1712000008.500000", "answer", Some("B0123ABCD"))])
+            .chain([message("1712000009.000000", "prompt", None)])
+            .chain([message("1712000010.000000", "later", None)])
+            .collect();
+        let turns = recent_user_turns(&messages, "1712000009.000000", "prompt");
+        assert_eq!(turns, "turn 4\nturn 5\nturn 6\nturn 7\nprompt");
+    }
+
+    #[test]
+    fn assesses_the_prompt_alone_in_a_new_thread() {
+        assert_eq!(recent_user_turns(&[], "1712000009.000000", "prompt"), "prompt");
+    }
+}
@@ -1,9 +1,11 @@
 
-use std::{sync::Arc, env, os::unix::thread};
-use anyhow::Result;
+use std::{sync::Arc, os::unix::thread};
+use anyhow::{Result, Context, bail};
+use crate::config::Config;
+use cores::slack::{self, UserInfo, UserInfoResponseBody};
 use reqwest::{self, Client};
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use tracing::{debug, info, instrument};
 
 #[derive(Serialize)]
 struct PostRequestBody {
@@ -54,6 +56,12 @@ struct RepliesResponseBody {
     messages: Vec<RepliesMessage>,
 }
 
+#[derive(Serialize)]
+struct RespondRequestBody {
+    response_type: String,
+    text: String,
+}
+
 #[derive(Debug)]
 pub struct RepliesResult {
     pub messages: Vec<RepliesMessage>,
@@ -63,42 +71,180 @@ pub struct RepliesResult {
 pub struct RepliesMessage {
     pub r#type: String,
     pub ts: String,
+    pub user: Option<String>,
     pub text: String,
     pub thread_ts: String,
     pub bot_id: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct GetUploadURLExternalResponseBody {
+    upload_url: String,
+    file_id: String,
+}
+
+#[derive(Serialize)]
+struct CompleteUploadExternalRequestBody {
+    files: Vec<CompleteUploadExternalFile>,
+    channel_id: String,
+    thread_ts: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CompleteUploadExternalFile {
+    id: String,
+    title: String,
+}
+
+// Web API methods answer with `ok: false` and an error code instead of a failure status
+#[derive(Deserialize)]
+struct SearchMessagesResponseBody {
+    messages: SearchMessagesMatches,
+}
+
+#[derive(Deserialize)]
+struct SearchMessagesMatches {
+    matches: Vec<SearchMessage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchMessage {
+    pub channel: SearchMessageChannel,
+    pub user: Option<String>,
+    pub username: Option<String>,
+    pub text: String,
+    pub ts: String,
+    pub permalink: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchMessageChannel {
+    pub id: String,
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HistoryResponseBody {
+    messages: Vec<HistoryMessage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HistoryMessage {
+    pub user: Option<String>,
+    pub bot_id: Option<String>,
+    pub text: String,
+    pub ts: String,
+    pub thread_ts: Option<String>,
+    pub reply_count: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ConversationInfoResponseBody {
+    channel: ConversationInfo,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConversationInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub is_private: Option<bool>,
+    pub is_im: Option<bool>,
+    pub topic: Option<ConversationInfoValue>,
+    pub purpose: Option<ConversationInfoValue>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConversationInfoValue {
+    pub value: String,
+}
+
+#[derive(Deserialize)]
+struct UserConversationsResponseBody {
+    channels: Vec<UserConversation>,
+    response_metadata: Option<ResponseMetadata>,
+}
+
+#[derive(Deserialize)]
+struct UserConversation {
+    id: String,
+}
+
+#[derive(Serialize)]
+struct OpenConversationRequestBody {
+    users: String,
+}
+
+#[derive(Deserialize)]
+struct OpenConversationResponseBody {
+    channel: UserConversation,
+}
+
+#[derive(Deserialize)]
+struct ResponseMetadata {
+    next_cursor: Option<String>,
+}
+
 pub struct SlackClient {
     client: Client,
+    bot_token: String,
+    user_token: Option<String>,
 }
 
 // https://api.slack.com/messaging/sending
 impl SlackClient {
-    pub fn new() -> Result<Arc<Self>> {
+    pub fn new(bot_token: String, user_token: Option<String>) -> Result<Arc<Self>> {
         let client = reqwest::Client::new();
         let this = Self {
             client,
+            bot_token,
+            user_token,
         };
         let this = Arc::new(this);
         Ok(this)
     }
 
+    // acts in the workspace with the tokens its installation granted,
+    // SLACK_BOT_TOKEN and SLACK_USER_TOKEN serve a workspace that was not installed through OAuth
+    pub async fn for_team(config: &Config, team_id: Option<&str>) -> Result<Arc<Self>> {
+        let installation = match team_id {
+            Some(team_id) => config.installation_store.find(team_id).await?,
+            None => None,
+        };
+        match installation {
+            Some(installation) => Self::new(installation.bot_token, installation.user_token),
+            None => {
+                let bot_token = config.slack_bot_token.as_ref()
+                    .with_context(|| format!("no installation for team {:?}", team_id))?;
+                let user_token = match config.slack_user_token {
+                    Some(ref user_token) => Some(user_token.get().await),
+                    None => None,
+                };
+                Self::new(bot_token.get().await, user_token)
+            },
+        }
+    }
+
+    // search is only available to user tokens
+    pub fn has_user_token(&self) -> bool {
+        self.user_token.is_some()
+    }
+
     // https://api.slack.com/methods/chat.postMessage
+    #[instrument(name = "slack_post", skip_all, fields(channel = channel, thread_ts = ?thread_ts))]
     pub async fn post(&self, channel: &str, thread_ts: Option<&str>, text: String) -> Result<PostResult> {
-        let client_token = env::var("SLACK_BOT_TOKEN")?;
+        let client_token = &self.bot_token;
         let request_body = PostRequestBody {
             channel: channel.into(),
             text: text.into(),
             thread_ts: thread_ts.map(|v| v.into()),
         };
-        let response = self.client.post("https://slack.com/api/chat.postMessage")
+        let request = self.client.post("https://slack.com/api/chat.postMessage")
             .header("Content-type", "application/json; charset=utf-8")
             .header("Authorization", ["Bearer", &client_token].join(" "))
-            .json(&request_body)
-            .send()
-            .await?;
+            .json(&request_body);
+        let response = slack::send("chat.postMessage", request).await?;
         let text = response.text().await?;
-        info!("slack chat.postMessage response {:?}", text);
+        debug!(response = text, "slack chat.postMessage");
         let response: PostResponseBody = serde_json::from_str(&text)?;
         let result = PostResult {
             channel: response.channel,
@@ -109,36 +255,36 @@ impl SlackClient {
     }
 
     // https://api.slack.com/methods/chat.update
+    #[instrument(name = "slack_update", skip_all, fields(channel = channel, ts = ts))]
     pub async fn update(&self, channel: &str, ts: &str, text: String) -> Result<()> {
-        let client_token = env::var("SLACK_BOT_TOKEN")?;
+        let client_token = &self.bot_token;
         let request_body = UpdateRequestBody {
             channel: channel.into(),
             ts: ts.into(),
             text,
         };
-        let response = self.client.post("https://slack.com/api/chat.update")
+        let request = self.client.post("https://slack.com/api/chat.update")
             .header("Content-type", "application/json; charset=utf-8")
             .header("Authorization", ["Bearer", &client_token].join(" "))
-            .json(&request_body)
-            .send()
-            .await?;
+            .json(&request_body);
+        let response = slack::send("chat.update", request).await?;
         let text = response.text().await?;
-        info!("slack chat.update response {:?}", text);
+        debug!(response = text, "slack chat.update");
         let _response: UpdateResponseBody = serde_json::from_str(&text)?;
         Ok(())
     }
 
     // https://api.slack.com/methods/conversations.replies
+    #[instrument(name = "fetch_replies", skip_all, fields(channel = channel, ts = ts))]
     pub async fn replies(&self, channel: &str, ts: &str) -> Result<RepliesResult> {
-        let client_token = env::var("SLACK_BOT_TOKEN")?;
-        let response = self.client.get("https://slack.com/api/conversations.replies")
+        let client_token = &self.bot_token;
+        let request = self.client.get("https://slack.com/api/conversations.replies")
             .header("Content-type", "application/x-www-form-urlencoded; charset=utf-8")
             .header("Authorization", ["Bearer", &client_token].join(" "))
-            .query(&[("channel", channel), ("ts", &ts)])
-            .send()
-            .await?;
+            .query(&[("channel", channel), ("ts", &ts)]);
+        let response = slack::send("conversations.replies", request).await?;
         let text = response.text().await?;
-        info!("slack conversations.replies response {:?}", text);
+        debug!(response = text, "slack conversations.replies");
         let response: RepliesResponseBody = serde_json::from_str(&text)?;
         let result = RepliesResult {
             messages: response.messages,
@@ -146,14 +292,193 @@ impl SlackClient {
         Ok(result)
     }
 
-    pub async fn download_image(&self, url_private_download: &str) -> Result<Vec<u8>> {
-        let client_token = env::var("SLACK_BOT_TOKEN")?;
-        let response = self.client.get(url_private_download)
-            .header("Authorization", ["Bearer", &client_token].join(" "))
-            .send()
-            .await?;
-        let image_bytes = response.bytes().await?;
-        let data = Vec::from(image_bytes);
+    #[instrument(name = "download_file", skip_all)]
+    pub async fn download_file(&self, url_private_download: &str) -> Result<Vec<u8>> {
+        let client_token = &self.bot_token;
+        let request = self.client.get(url_private_download)
+            .header("Authorization", ["Bearer", &client_token].join(" "));
+        let response = slack::send("files.download", request).await?;
+        let file_bytes = response.bytes().await?;
+        let data = Vec::from(file_bytes);
+        info!(size = data.len(), "slack file downloaded");
         Ok(data)
     }
+
+    // https://api.slack.com/messaging/files#uploading_files
+    pub async fn upload_file(&self, channel: &str, thread_ts: Option<&str>, filename: &str, data: Vec<u8>) -> Result<()> {
+        let client_token = &self.bot_token;
+        // https://api.slack.com/methods/files.getUploadURLExternal
+        let length = data.len().to_string();
+        let request = self.client.get("https://slack.com/api/files.getUploadURLExternal")
+            .header("Content-type", "application/x-www-form-urlencoded; charset=utf-8")
+            .header("Authorization", ["Bearer", &client_token].join(" "))
+            .query(&[("filename", filename), ("length", length.as_str())]);
+        let response = slack::send("files.getUploadURLExternal", request).await?;
+        let text = response.text().await?;
+        debug!(response = text, "slack files.getUploadURLExternal");
+        slack::check_status(&text)?;
+        let upload: GetUploadURLExternalResponseBody = serde_json::from_str(&text)?;
+        let request = self.client.post(&upload.upload_url)
+            .body(data);
+        let response = slack::send("files.upload", request).await?;
+        if !response.status().is_success() {
+            bail!("file upload failure. status {}", response.status());
+        }
+        // https://api.slack.com/methods/files.completeUploadExternal
+        let request_body = CompleteUploadExternalRequestBody {
+            files: vec![
+                CompleteUploadExternalFile {
+                    id: upload.file_id,
+                    title: filename.into(),
+                }
+            ],
+            channel_id: channel.into(),
+            thread_ts: thread_ts.map(|v| v.into()),
+        };
+        let request = self.client.post("https://slack.com/api/files.completeUploadExternal")
+            .header("Content-type", "application/json; charset=utf-8")
+            .header("Authorization", ["Bearer", &client_token].join(" "))
+            .json(&request_body);
+        let response = slack::send("files.completeUploadExternal", request).await?;
+        let text = response.text().await?;
+        debug!(response = text, "slack files.completeUploadExternal");
+        slack::check_status(&text)?;
+        Ok(())
+    }
+
+    // https://api.slack.com/methods/search.messages
+    // search is only available to user tokens (search:read)
+    pub async fn search_messages(&self, query: &str, count: u64) -> Result<Vec<SearchMessage>> {
+        let client_token = self.user_token.as_deref().context("no user token")?;
+        let count = count.to_string();
+        let request = self.client.get("https://slack.com/api/search.messages")
+            .header("Content-type", "application/x-www-form-urlencoded; charset=utf-8")
+            .header("Authorization", ["Bearer", &client_token].join(" "))
+            .query(&[("query", query), ("count", count.as_str()), ("sort", "timestamp")]);
+        let response = slack::send("search.messages", request).await?;
+        let text = response.text().await?;
+        debug!(response = text, "slack search.messages");
+        slack::check_status(&text)?;
+        let response: SearchMessagesResponseBody = serde_json::from_str(&text)?;
+        Ok(response.messages.matches)
+    }
+
+    // https://api.slack.com/methods/conversations.history
+    pub async fn history(&self, channel: &str, oldest: Option<&str>, limit: u64) -> Result<Vec<HistoryMessage>> {
+        let client_token = &self.bot_token;
+        let limit = limit.to_string();
+        let mut query = vec![("channel", channel), ("limit", limit.as_str())];
+        if let Some(oldest) = oldest {
+            query.push(("oldest", oldest));
+        }
+        let request = self.client.get("https://slack.com/api/conversations.history")
+            .header("Content-type", "application/x-www-form-urlencoded; charset=utf-8")
+            .header("Authorization", ["Bearer", &client_token].join(" "))
+            .query(&query);
+        let response = slack::send("conversations.history", request).await?;
+        let text = response.text().await?;
+        debug!(response = text, "slack conversations.history");
+        slack::check_status(&text)?;
+        let response: HistoryResponseBody = serde_json::from_str(&text)?;
+        Ok(response.messages)
+    }
+
+    // https://api.slack.com/methods/users.info
+    pub async fn user_info(&self, user: &str) -> Result<UserInfo> {
+        let client_token = &self.bot_token;
+        let request = self.client.get("https://slack.com/api/users.info")
+            .header("Content-type", "application/x-www-form-urlencoded; charset=utf-8")
+            .header("Authorization", ["Bearer", &client_token].join(" "))
+            .query(&[("user", user), ("include_locale", "true")]);
+        let response = slack::send("users.info", request).await?;
+        let text = response.text().await?;
+        debug!(response = text, "slack users.info");
+        slack::check_status(&text)?;
+        let response: UserInfoResponseBody = serde_json::from_str(&text)?;
+        Ok(response.user)
+    }
+
+    // https://api.slack.com/methods/conversations.info
+    pub async fn conversation_info(&self, channel: &str) -> Result<ConversationInfo> {
+        let client_token = &self.bot_token;
+        let request = self.client.get("https://slack.com/api/conversations.info")
+            .header("Content-type", "application/x-www-form-urlencoded; charset=utf-8")
+            .header("Authorization", ["Bearer", &client_token].join(" "))
+            .query(&[("channel", channel)]);
+        let response = slack::send("conversations.info", request).await?;
+        let text = response.text().await?;
+        debug!(response = text, "slack conversations.info");
+        slack::check_status(&text)?;
+        let response: ConversationInfoResponseBody = serde_json::from_str(&text)?;
+        Ok(response.channel)
+    }
+
+    // https://api.slack.com/methods/users.conversations
+    // lists every conversation the given user is a member of
+    pub async fn user_conversations(&self, user: &str) -> Result<Vec<String>> {
+        let client_token = &self.bot_token;
+        let mut channels = vec![];
+        let mut cursor = String::new();
+        loop {
+            let request = self.client.get("https://slack.com/api/users.conversations")
+                .header("Content-type", "application/x-www-form-urlencoded; charset=utf-8")
+                .header("Authorization", ["Bearer", &client_token].join(" "))
+                .query(&[
+                    ("user", user),
+                    ("types", "public_channel,private_channel,mpim,im"),
+                    ("exclude_archived", "true"),
+                    ("limit", "200"),
+                    ("cursor", cursor.as_str()),
+                ]);
+            let response = slack::send("users.conversations", request).await?;
+            let text = response.text().await?;
+            debug!(response = text, "slack users.conversations");
+            slack::check_status(&text)?;
+            let response: UserConversationsResponseBody = serde_json::from_str(&text)?;
+            channels.extend(response.channels.into_iter().map(|v| v.id));
+            let next_cursor = response.response_metadata
+                .and_then(|v| v.next_cursor)
+                .unwrap_or_default();
+            if next_cursor.is_empty() {
+                break;
+            }
+            cursor = next_cursor;
+        }
+        Ok(channels)
+    }
+
+    // https://api.slack.com/methods/conversations.open
+    // returns the channel of the direct message with the given user
+    pub async fn open_direct_message(&self, user: &str) -> Result<String> {
+        let client_token = &self.bot_token;
+        let request_body = OpenConversationRequestBody {
+            users: user.into(),
+        };
+        let request = self.client.post("https://slack.com/api/conversations.open")
+            .header("Content-type", "application/json; charset=utf-8")
+            .header("Authorization", ["Bearer", &client_token].join(" "))
+            .json(&request_body);
+        let response = slack::send("conversations.open", request).await?;
+        let text = response.text().await?;
+        debug!(response = text, "slack conversations.open");
+        slack::check_status(&text)?;
+        let response: OpenConversationResponseBody = serde_json::from_str(&text)?;
+        Ok(response.channel.id)
+    }
+
+    // replies to a slash command, visible only to whoever ran it
+    // https://api.slack.com/interactivity/handling#message_responses
+    pub async fn respond(&self, response_url: &str, text: String) -> Result<()> {
+        let request_body = RespondRequestBody {
+            response_type: "ephemeral".into(),
+            text,
+        };
+        let request = self.client.post(response_url)
+            .header("Content-type", "application/json; charset=utf-8")
+            .json(&request_body);
+        let response = slack::send("response_url", request).await?;
+        let text = response.text().await?;
+        debug!(response = text, "slack response_url");
+        Ok(())
+    }
 }
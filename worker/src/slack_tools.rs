@@ -0,0 +1,418 @@
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use anyhow::{Result, Context, bail};
+use async_trait::async_trait;
+use chrono::{Utc, Duration as ChronoDuration};
+use serde_json::{json, Value};
+use tokio::sync::OnceCell;
+
+use crate::slack_client::{SearchMessage, SlackClient};
+use crate::tools::{Tool, ToolRegistry};
+
+// the Slack API methods that decide what the requester may see, behind a trait so that the scoping is tested offline
+#[async_trait]
+pub trait SlackLookup: Send + Sync {
+    async fn user_conversations(&self, user: &str) -> Result<Vec<String>>;
+    async fn search_messages(&self, query: &str, count: u64) -> Result<Vec<SearchMessage>>;
+}
+
+#[async_trait]
+impl SlackLookup for SlackClient {
+    async fn user_conversations(&self, user: &str) -> Result<Vec<String>> {
+        SlackClient::user_conversations(self, user).await
+    }
+
+    async fn search_messages(&self, query: &str, count: u64) -> Result<Vec<SearchMessage>> {
+        SlackClient::search_messages(self, query, count).await
+    }
+}
+
+// workspace content is only surfaced from conversations the requester is a member of
+pub struct RequesterScope {
+    slack_lookup: Arc<dyn SlackLookup>,
+    user: String,
+    channel: String,
+    channels: OnceCell<HashSet<String>>,
+}
+
+impl RequesterScope {
+    pub fn new(slack_lookup: Arc<dyn SlackLookup>, user: &str, channel: &str) -> Arc<Self> {
+        let this = Self {
+            slack_lookup,
+            user: user.into(),
+            channel: channel.into(),
+            channels: OnceCell::new(),
+        };
+        Arc::new(this)
+    }
+
+    pub async fn can_see(&self, channel: &str) -> Result<bool> {
+        if channel == self.channel {
+            return Ok(true)
+        }
+        let channels = self.channels
+            .get_or_try_init(|| async {
+                let channels = self.slack_lookup.user_conversations(&self.user).await?;
+                Ok::<_, anyhow::Error>(channels.into_iter().collect::<HashSet<String>>())
+            })
+            .await?;
+        Ok(channels.contains(channel))
+    }
+
+    async fn ensure_visible(&self, channel: &str) -> Result<()> {
+        if !self.can_see(channel).await? {
+            bail!("channel {} is not accessible to the requester", channel);
+        }
+        Ok(())
+    }
+}
+
+// registers the Slack-aware tools for a conversation with the given requester
+pub fn register_slack_tools(registry: &mut ToolRegistry, slack_client: &Arc<SlackClient>, scope: &Arc<RequesterScope>) {
+    // search.messages requires a user token
+    if slack_client.has_user_token() {
+        registry.register(Arc::new(SearchMessagesTool {
+            slack_lookup: Arc::clone(slack_client) as Arc<dyn SlackLookup>,
+            scope: Arc::clone(scope),
+        }));
+    }
+    registry.register(Arc::new(FetchChannelHistoryTool {
+        slack_client: Arc::clone(slack_client),
+        scope: Arc::clone(scope),
+    }));
+    registry.register(Arc::new(FetchThreadTool {
+        slack_client: Arc::clone(slack_client),
+        scope: Arc::clone(scope),
+    }));
+    registry.register(Arc::new(LookupUserTool {
+        slack_client: Arc::clone(slack_client),
+    }));
+    registry.register(Arc::new(LookupChannelTool {
+        slack_client: Arc::clone(slack_client),
+        scope: Arc::clone(scope),
+    }));
+}
+
+fn string_argument<'a>(arguments: &'a Value, name: &str) -> Result<&'a str> {
+    arguments.get(name)
+        .and_then(|v| v.as_str())
+        .with_context(|| format!("missing argument {}", name))
+}
+
+fn format_message_line(user: Option<&str>, ts: &str, text: &str) -> String {
+    let user = user.map(|v| format!("<@{}>", v)).unwrap_or_else(|| "unknown".into());
+    format!("[ts {}] {}: {}", ts, user, text)
+}
+
+struct SearchMessagesTool {
+    slack_lookup: Arc<dyn SlackLookup>,
+    scope: Arc<RequesterScope>,
+}
+
+#[async_trait]
+impl Tool for SearchMessagesTool {
+    fn name(&self) -> &str {
+        "search_messages"
+    }
+
+    fn description(&self) -> &str {
+        "Searches Slack messages in the workspace. Supports Slack search modifiers such as in:#channel, from:@user, after:YYYY-MM-DD."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": { "type": "string", "description": "Slack search query" },
+            },
+            "required": ["query"],
+        })
+    }
+
+    fn progress(&self) -> String {
+        "🔧 searching…".into()
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<String> {
+        let query = string_argument(&arguments, "query")?;
+        let matches = self.slack_lookup.search_messages(query, 20).await?;
+        let mut lines = vec![];
+        for message in matches {
+            if !self.scope.can_see(&message.channel.id).await? {
+                continue;
+            }
+            let channel = message.channel.name.as_deref().unwrap_or(&message.channel.id);
+            let line = format_message_line(message.user.as_deref(), &message.ts, &message.text);
+            let permalink = message.permalink.unwrap_or_default();
+            lines.push(format!("#{} {} {}", channel, line, permalink));
+        }
+        if lines.is_empty() {
+            return Ok("no messages found".into())
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+struct FetchChannelHistoryTool {
+    slack_client: Arc<SlackClient>,
+    scope: Arc<RequesterScope>,
+}
+
+#[async_trait]
+impl Tool for FetchChannelHistoryTool {
+    fn name(&self) -> &str {
+        "fetch_channel_history"
+    }
+
+    fn description(&self) -> &str {
+        "Fetches recent messages of a Slack channel by channel ID."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "channel": { "type": "string", "description": "channel ID such as C0123456789" },
+                "days": { "type": "integer", "description": "how many days back to fetch, defaults to 7" },
+            },
+            "required": ["channel"],
+        })
+    }
+
+    fn progress(&self) -> String {
+        "🔧 reading the channel…".into()
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<String> {
+        let channel = string_argument(&arguments, "channel")?;
+        let days = arguments.get("days")
+            .and_then(|v| v.as_i64())
+            .unwrap_or(7)
+            .clamp(1, 90);
+        self.scope.ensure_visible(channel).await?;
+        let oldest = Utc::now() - ChronoDuration::days(days);
+        let oldest = oldest.timestamp().to_string();
+        let messages = self.slack_client.history(channel, Some(&oldest), 100).await?;
+        let lines: Vec<String> = messages.iter()
+            .rev()
+            .map(|v| {
+                let line = format_message_line(v.user.as_deref(), &v.ts, &v.text);
+                match v.reply_count {
+                    Some(count) if count > 0 => format!("{} ({} replies, thread_ts {})", line, count, v.ts),
+                    _ => line,
+                }
+            })
+            .collect();
+        if lines.is_empty() {
+            return Ok("no messages found".into())
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+struct FetchThreadTool {
+    slack_client: Arc<SlackClient>,
+    scope: Arc<RequesterScope>,
+}
+
+#[async_trait]
+impl Tool for FetchThreadTool {
+    fn name(&self) -> &str {
+        "fetch_thread"
+    }
+
+    fn description(&self) -> &str {
+        "Fetches all replies of a Slack thread."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "channel": { "type": "string", "description": "channel ID" },
+                "thread_ts": { "type": "string", "description": "ts of the parent message" },
+            },
+            "required": ["channel", "thread_ts"],
+        })
+    }
+
+    fn progress(&self) -> String {
+        "🔧 reading the thread…".into()
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<String> {
+        let channel = string_argument(&arguments, "channel")?;
+        let thread_ts = string_argument(&arguments, "thread_ts")?;
+        self.scope.ensure_visible(channel).await?;
+        let replies = self.slack_client.replies(channel, thread_ts).await?;
+        let lines: Vec<String> = replies.messages.iter()
+            .map(|v| format_message_line(v.user.as_deref(), &v.ts, &v.text))
+            .collect();
+        Ok(lines.join("\n"))
+    }
+}
+
+struct LookupUserTool {
+    slack_client: Arc<SlackClient>,
+}
+
+#[async_trait]
+impl Tool for LookupUserTool {
+    fn name(&self) -> &str {
+        "lookup_user"
+    }
+
+    fn description(&self) -> &str {
+        "Looks up a Slack user's name, title and time zone by user ID."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "user": { "type": "string", "description": "user ID such as U0123456789" },
+            },
+            "required": ["user"],
+        })
+    }
+
+    fn progress(&self) -> String {
+        "🔧 looking up the user…".into()
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<String> {
+        let user = string_argument(&arguments, "user")?;
+        let info = self.slack_client.user_info(user).await?;
+        let profile = info.profile.as_ref();
+        let value = json!({
+            "id": info.id,
+            "name": info.name,
+            "real_name": info.real_name,
+            "display_name": profile.and_then(|v| v.display_name.clone()),
+            "title": profile.and_then(|v| v.title.clone()),
+            "tz": info.tz,
+            "is_bot": info.is_bot,
+        });
+        Ok(value.to_string())
+    }
+}
+
+struct LookupChannelTool {
+    slack_client: Arc<SlackClient>,
+    scope: Arc<RequesterScope>,
+}
+
+#[async_trait]
+impl Tool for LookupChannelTool {
+    fn name(&self) -> &str {
+        "lookup_channel"
+    }
+
+    fn description(&self) -> &str {
+        "Looks up a Slack channel's name, topic and purpose by channel ID."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "channel": { "type": "string", "description": "channel ID" },
+            },
+            "required": ["channel"],
+        })
+    }
+
+    fn progress(&self) -> String {
+        "🔧 looking up the channel…".into()
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<String> {
+        let channel = string_argument(&arguments, "channel")?;
+        self.scope.ensure_visible(channel).await?;
+        let info = self.slack_client.conversation_info(channel).await?;
+        let value = json!({
+            "id": info.id,
+            "name": info.name,
+            "is_private": info.is_private,
+            "is_im": info.is_im,
+            "topic": info.topic.map(|v| v.value),
+            "purpose": info.purpose.map(|v| v.value),
+        });
+        Ok(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::slack_client::SearchMessageChannel;
+
+    use super::*;
+
+    // the requester is a member of C0MEMBER only, besides the DM they are asking in
+    #[derive(Default)]
+    struct FakeSlack {
+        conversation_calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl SlackLookup for FakeSlack {
+        async fn user_conversations(&self, _user: &str) -> Result<Vec<String>> {
+            self.conversation_calls.fetch_add(1, Ordering::SeqCst);
+            Ok(vec!["C0MEMBER".into()])
+        }
+
+        async fn search_messages(&self, _query: &str, _count: u64) -> Result<Vec<SearchMessage>> {
+            let hit = |channel: &str, text: &str| SearchMessage {
+                channel: SearchMessageChannel { id: channel.into(), name: None },
+                user: Some("U0OTHER".into()),
+                username: None,
+                text: text.into(),
+                ts: "1712000000.000000".into(),
+                permalink: None,
+            };
+            Ok(vec![hit("C0MEMBER", "shared plan"), hit("C0SECRET", "salary review"), hit("D0REQUESTER", "my note")])
+        }
+    }
+
+    fn scope(slack: &Arc<FakeSlack>) -> Arc<RequesterScope> {
+        RequesterScope::new(Arc::clone(slack) as Arc<dyn SlackLookup>, "U0REQUESTER", "D0REQUESTER")
+    }
+
+    #[tokio::test]
+    async fn drops_search_hits_from_channels_the_requester_is_not_in() {
+        let slack = Arc::new(FakeSlack::default());
+        let tool = SearchMessagesTool {
+            slack_lookup: Arc::clone(&slack) as Arc<dyn SlackLookup>,
+            scope: scope(&slack),
+        };
+        let result = tool.execute(json!({"query": "plan"})).await.unwrap();
+        assert!(result.contains("shared plan"));
+        assert!(result.contains("my note"));
+        assert!(!result.contains("salary review"));
+        // the memberships are asked for once per conversation
+        assert_eq!(slack.conversation_calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_channels_the_requester_is_not_in() {
+        let slack = Arc::new(FakeSlack::default());
+        let scope = scope(&slack);
+        // refused before Slack is called, the token is never used
+        let slack_client = SlackClient::new("xoxb-unused".into(), None).unwrap();
+        let tools: Vec<(Arc<dyn Tool>, Value)> = vec![
+            (Arc::new(FetchChannelHistoryTool { slack_client: Arc::clone(&slack_client), scope: Arc::clone(&scope) }), json!({"channel": "C0SECRET"})),
+            (Arc::new(FetchThreadTool { slack_client: Arc::clone(&slack_client), scope: Arc::clone(&scope) }), json!({"channel": "C0SECRET", "thread_ts": "1712000000.000000"})),
+            (Arc::new(LookupChannelTool { slack_client: Arc::clone(&slack_client), scope: Arc::clone(&scope) }), json!({"channel": "C0SECRET"})),
+        ];
+        for (tool, arguments) in tools {
+            let error = tool.execute(arguments).await.unwrap_err();
+            assert!(error.to_string().contains("not accessible"), "{} {}", tool.name(), error);
+        }
+        assert!(scope.can_see("C0MEMBER").await.unwrap());
+        assert!(scope.can_see("D0REQUESTER").await.unwrap());
+    }
+}
@@ -0,0 +1,154 @@
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::openai_client::OpenAIClient;
+
+// speech-to-text and text-to-speech are hidden behind traits
+// so that canned implementations can stand in for the HTTP clients offline
+#[async_trait]
+pub trait Transcriber: Send + Sync {
+    async fn transcribe(&self, data: Vec<u8>, mimetype: &str) -> Result<String>;
+}
+
+#[async_trait]
+pub trait Synthesizer: Send + Sync {
+    async fn synthesize(&self, text: &str) -> Result<SynthesizedAudio>;
+}
+
+pub struct SynthesizedAudio {
+    pub data: Vec<u8>,
+    pub filename: String,
+}
+
+// the speech endpoint rejects longer input
+// https://platform.openai.com/docs/api-reference/audio/createSpeech
+const SPEECH_MAX_CHARS: usize = 4096;
+
+// None for a clip without speech
+pub async fn transcribe_audio(transcriber: &dyn Transcriber, data: Vec<u8>, mimetype: &str) -> Result<Option<String>> {
+    let transcript = transcriber.transcribe(data, mimetype).await?;
+    let transcript = transcript.trim();
+    Ok((!transcript.is_empty()).then(|| transcript.to_string()))
+}
+
+// the transcript of a voice message stands in for the text of its message
+pub fn with_transcript(text: &str, transcript: Option<&str>) -> String {
+    match transcript {
+        Some(transcript) if text.trim().is_empty() => transcript.into(),
+        Some(transcript) => format!("{}\n{}", text, transcript),
+        None => text.into(),
+    }
+}
+
+// reads the beginning of a reply too long for the speech endpoint
+pub async fn speak(synthesizer: &dyn Synthesizer, text: &str) -> Result<SynthesizedAudio> {
+    let text: String = text.chars().take(SPEECH_MAX_CHARS).collect();
+    synthesizer.synthesize(&text).await
+}
+
+// Slack audio clips
+// https://api.slack.com/types/file
+pub fn is_audio_mimetype(mimetype: &str) -> bool {
+    matches!(mimetype, "audio/webm" | "audio/mp4" | "audio/mpeg" | "audio/x-m4a")
+}
+
+// the transcriptions endpoint infers the format from the file extension
+fn audio_filename(mimetype: &str) -> &'static str {
+    match mimetype {
+        "audio/webm" => "audio.webm",
+        "audio/mp4" => "audio.mp4",
+        "audio/x-m4a" => "audio.m4a",
+        _ => "audio.mp3",
+    }
+}
+
+#[async_trait]
+impl Transcriber for OpenAIClient {
+    async fn transcribe(&self, data: Vec<u8>, mimetype: &str) -> Result<String> {
+        self.transcriptions(data, audio_filename(mimetype), mimetype).await
+    }
+}
+
+#[async_trait]
+impl Synthesizer for OpenAIClient {
+    async fn synthesize(&self, text: &str) -> Result<SynthesizedAudio> {
+        let data = self.speech(text).await?;
+        let audio = SynthesizedAudio {
+            data,
+            filename: "yoshino-radio.mp3".into(),
+        };
+        Ok(audio)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    struct CannedTranscriber {
+        transcript: String,
+    }
+
+    #[async_trait]
+    impl Transcriber for CannedTranscriber {
+        async fn transcribe(&self, _data: Vec<u8>, _mimetype: &str) -> Result<String> {
+            Ok(self.transcript.clone())
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingSynthesizer {
+        inputs: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Synthesizer for RecordingSynthesizer {
+        async fn synthesize(&self, text: &str) -> Result<SynthesizedAudio> {
+            self.inputs.lock().unwrap().push(text.into());
+            Ok(SynthesizedAudio { data: vec![0xff, 0xfb], filename: "reply.mp3".into() })
+        }
+    }
+
+    #[test]
+    fn audio_mimetypes() {
+        assert!(is_audio_mimetype("audio/webm"));
+        assert!(is_audio_mimetype("audio/mp4"));
+        assert!(!is_audio_mimetype("image/png"));
+        assert_eq!(audio_filename("audio/webm"), "audio.webm");
+        assert_eq!(audio_filename("audio/mpeg"), "audio.mp3");
+    }
+
+    #[tokio::test]
+    async fn transcribes_speech() {
+        let transcriber = CannedTranscriber { transcript: " 明日の天気は？\n".into() };
+        let transcript = transcribe_audio(&transcriber, vec![1, 2, 3], "audio/webm").await.unwrap();
+        assert_eq!(transcript.as_deref(), Some("明日の天気は？"));
+    }
+
+    #[tokio::test]
+    async fn silence_has_no_transcript() {
+        let transcriber = CannedTranscriber { transcript: "  ".into() };
+        let transcript = transcribe_audio(&transcriber, vec![], "audio/mp4").await.unwrap();
+        assert_eq!(transcript, None);
+    }
+
+    #[test]
+    fn transcript_stands_in_for_the_text() {
+        assert_eq!(with_transcript("", Some("hello")), "hello");
+        assert_eq!(with_transcript("listen", Some("hello")), "listen\nhello");
+        assert_eq!(with_transcript("listen", None), "listen");
+    }
+
+    #[tokio::test]
+    async fn speaks_the_beginning_of_long_replies() {
+        let synthesizer = RecordingSynthesizer::default();
+        let text = "よ".repeat(SPEECH_MAX_CHARS + 10);
+        let audio = speak(&synthesizer, &text).await.unwrap();
+        assert_eq!(audio.filename, "reply.mp3");
+        let inputs = synthesizer.inputs.lock().unwrap();
+        assert_eq!(inputs[0].chars().count(), SPEECH_MAX_CHARS);
+    }
+}
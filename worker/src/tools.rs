@@ -0,0 +1,213 @@
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use cores::metrics;
+use chrono::Utc;
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::openai_client::{
+    CompletionsRequestTool, CompletionsRequestToolFunction,
+    CompletionsToolCall, CompletionsToolCallFunction, CompletionsMessageChunkToolCall};
+
+// https://platform.openai.com/docs/guides/function-calling
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    // JSON schema of the arguments object
+    fn parameters(&self) -> Value;
+    // shown on Slack while the tool runs
+    fn progress(&self) -> String {
+        format!("🔧 {}…", self.name())
+    }
+    async fn execute(&self, arguments: Value) -> Result<String>;
+}
+
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self {
+            tools: vec![],
+        }
+    }
+
+    // tools available to every conversation
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register(Arc::new(CurrentTimeTool {}));
+        registry
+    }
+
+    pub fn register(&mut self, tool: Arc<dyn Tool>) {
+        self.tools.retain(|v| v.name() != tool.name());
+        self.tools.push(tool);
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Arc<dyn Tool>> {
+        self.tools.iter()
+            .find(|v| v.name() == name)
+    }
+
+    pub fn definitions(&self) -> Vec<CompletionsRequestTool> {
+        self.tools.iter()
+            .map(|tool| CompletionsRequestTool {
+                r#type: "function".into(),
+                function: CompletionsRequestToolFunction {
+                    name: tool.name().into(),
+                    description: tool.description().into(),
+                    parameters: tool.parameters(),
+                },
+            })
+            .collect()
+    }
+
+    pub fn progress(&self, call: &CompletionsToolCall) -> String {
+        self.find(&call.function.name)
+            .map(|tool| tool.progress())
+            .unwrap_or_else(|| "🔧 …".into())
+    }
+
+    // failures are reported back to the model as the tool result so that it can recover
+    pub async fn execute(&self, call: &CompletionsToolCall) -> String {
+        let Some(tool) = self.find(&call.function.name) else {
+            return format!("error: unknown tool {}", call.function.name);
+        };
+        let arguments: Value = match serde_json::from_str(&call.function.arguments) {
+            Ok(v) => v,
+            Err(error) => return format!("error: invalid arguments {}", error),
+        };
+        info!("executing tool {}", call.function.name);
+        match tool.execute(arguments).await {
+            Ok(v) if v.is_empty() => "(no result)".into(),
+            Ok(v) => v,
+            Err(error) => {
+                info!("tool {} failed {:?}", call.function.name, error);
+                metrics::increment("errors", &[("type", "tool")]);
+                format!("error: {}", error)
+            },
+        }
+    }
+}
+
+// parallel tool calls of a single response, deltas indexed beyond it are dropped
+const MAX_TOOL_CALLS: usize = 16;
+
+// assembles streamed tool call deltas into complete calls
+pub struct ToolCallAssembler {
+    calls: Vec<CompletionsToolCall>,
+}
+
+impl ToolCallAssembler {
+    pub fn new() -> Self {
+        Self {
+            calls: vec![],
+        }
+    }
+
+    pub fn push(&mut self, delta: CompletionsMessageChunkToolCall) {
+        if delta.index >= MAX_TOOL_CALLS {
+            info!("dropping tool call delta with index {}", delta.index);
+            return
+        }
+        while self.calls.len() <= delta.index {
+            self.calls.push(CompletionsToolCall {
+                id: String::new(),
+                r#type: "function".into(),
+                function: CompletionsToolCallFunction {
+                    name: String::new(),
+                    arguments: String::new(),
+                },
+            });
+        }
+        let call = &mut self.calls[delta.index];
+        if let Some(id) = delta.id {
+            call.id = id;
+        }
+        if let Some(function) = delta.function {
+            if let Some(name) = function.name {
+                call.function.name += &name;
+            }
+            if let Some(arguments) = function.arguments {
+                call.function.arguments += &arguments;
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<CompletionsToolCall> {
+        self.calls.into_iter()
+            .filter(|v| !v.id.is_empty() && !v.function.name.is_empty())
+            .collect()
+    }
+}
+
+struct CurrentTimeTool {}
+
+#[async_trait]
+impl Tool for CurrentTimeTool {
+    fn name(&self) -> &str {
+        "current_time"
+    }
+
+    fn description(&self) -> &str {
+        "Returns the current date and time in UTC."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {},
+        })
+    }
+
+    fn progress(&self) -> String {
+        "🔧 checking the time…".into()
+    }
+
+    async fn execute(&self, _arguments: Value) -> Result<String> {
+        Ok(Utc::now().to_rfc3339())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::openai_client::CompletionsMessageChunkToolCallFunction;
+
+    use super::*;
+
+    fn delta(index: usize, id: Option<&str>, name: Option<&str>, arguments: Option<&str>) -> CompletionsMessageChunkToolCall {
+        CompletionsMessageChunkToolCall {
+            index,
+            id: id.map(String::from),
+            function: Some(CompletionsMessageChunkToolCallFunction {
+                name: name.map(String::from),
+                arguments: arguments.map(String::from),
+            }),
+        }
+    }
+
+    #[test]
+    fn assembles_fragments() {
+        let mut assembler = ToolCallAssembler::new();
+        assembler.push(delta(0, Some("call_1"), Some("current_time"), Some("")));
+        assembler.push(delta(1, Some("call_2"), Some("search_"), Some("{\"query\":")));
+        assembler.push(delta(1, None, Some("messages"), Some("\"jlpt\"}")));
+        let calls = assembler.finish();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1].function.name, "search_messages");
+        assert_eq!(calls[1].function.arguments, "{\"query\":\"jlpt\"}");
+    }
+
+    #[test]
+    fn drops_indexes_beyond_the_cap() {
+        let mut assembler = ToolCallAssembler::new();
+        assembler.push(delta(usize::MAX, Some("call_1"), Some("current_time"), None));
+        assembler.push(delta(MAX_TOOL_CALLS, Some("call_2"), Some("current_time"), None));
+        assert!(assembler.finish().is_empty());
+    }
+}